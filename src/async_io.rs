@@ -0,0 +1,389 @@
+//! Decoding from and encoding to async readers and writers, without blocking the executor.
+//!
+//! Wrap `futures::io` types in `FuturesIo`, or `tokio::io` types in `TokioIo`.
+//!
+//! Decoding is driven by the non-blocking `IncrementalDecompress`. Encoding suspends libjpeg
+//! whenever its output buffer is full, and awaits the writer before compressing more rows.
+//! See `AsyncCompressStarted` for modes in which libjpeg can't suspend.
+use bytemuck::Pod;
+use crate::colorspace::ColorSpaceExt;
+use crate::component::CompInfo;
+use crate::compress::{Compress, CompressStarted};
+use crate::decompress::{pixel_items_per_pixel, DecompressBuilder};
+use crate::incremental::{IncrementalDecompress, Progress};
+use crate::marker::Marker;
+use std::future::poll_fn;
+use std::io;
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Adapter for readers and writers implementing `futures::io::AsyncBufRead` or `futures::io::AsyncWrite`
+#[cfg(feature = "futures")]
+pub struct FuturesIo<T>(pub T);
+
+/// Adapter for readers and writers implementing `tokio::io::AsyncBufRead` or `tokio::io::AsyncWrite`
+#[cfg(feature = "tokio")]
+pub struct TokioIo<T>(pub T);
+
+/// Async reader that can be used with `AsyncDecompress`. See `FuturesIo` and `TokioIo`.
+pub trait AsyncSource {
+    /// Reads some data into the decoder, or marks end of input
+    #[doc(hidden)]
+    fn poll_push_to(&mut self, cx: &mut Context<'_>, dec: &mut IncrementalDecompress) -> Poll<io::Result<()>>;
+}
+
+/// Async writer that can be used with `AsyncCompressStarted`. See `FuturesIo` and `TokioIo`.
+pub trait AsyncSink {
+    #[doc(hidden)]
+    fn poll_write_some(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>>;
+    #[doc(hidden)]
+    fn poll_flush_all(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>>;
+}
+
+/// `AsyncSource` and `AsyncSink` for an adapter, since `futures` and `tokio` traits have the same methods
+macro_rules! impl_async_io {
+    ($feature:literal, $adapter:ident, $buf_read:path, $write:path) => {
+        #[cfg(feature = $feature)]
+        impl<R: $buf_read + Unpin> AsyncSource for $adapter<R> {
+            fn poll_push_to(&mut self, cx: &mut Context<'_>, dec: &mut IncrementalDecompress) -> Poll<io::Result<()>> {
+                let buf = match Pin::new(&mut self.0).poll_fill_buf(cx) {
+                    Poll::Ready(Ok(buf)) => buf,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                };
+                let len = buf.len();
+                if len == 0 {
+                    dec.end_of_input();
+                } else {
+                    dec.push(buf);
+                }
+                Pin::new(&mut self.0).consume(len);
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        #[cfg(feature = $feature)]
+        impl<W: $write + Unpin> AsyncSink for $adapter<W> {
+            fn poll_write_some(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+                Pin::new(&mut self.0).poll_write(cx, buf)
+            }
+
+            fn poll_flush_all(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.0).poll_flush(cx)
+            }
+        }
+    };
+}
+
+impl_async_io!("futures", FuturesIo, futures_io::AsyncBufRead, futures_io::AsyncWrite);
+impl_async_io!("tokio", TokioIo, tokio::io::AsyncBufRead, tokio::io::AsyncWrite);
+
+async fn write_all<W: AsyncSink>(writer: &mut W, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        let written = poll_fn(|cx| writer.poll_write_some(cx, data)).await?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        data = &data[written..];
+    }
+    Ok(())
+}
+
+/// Decodes a JPEG from an async reader
+///
+/// Create with `DecompressBuilder::from_async_reader()`.
+///
+/// Bytes after the end of the image may have been consumed from the reader.
+pub struct AsyncDecompress<R> {
+    dec: IncrementalDecompress,
+    reader: R,
+}
+
+impl<'markers> DecompressBuilder<'markers> {
+    /// Decode from an async reader, e.g. `FuturesIo(reader)` or `TokioIo(reader)`.
+    ///
+    /// The header is read by `AsyncDecompress::read_header()`.
+    pub fn from_async_reader<R: AsyncSource>(self, reader: R) -> AsyncDecompress<R> {
+        AsyncDecompress {
+            dec: self.incremental(),
+            reader,
+        }
+    }
+}
+
+impl<R: AsyncSource> AsyncDecompress<R> {
+    async fn read_more(&mut self) -> io::Result<()> {
+        let Self { dec, reader } = self;
+        poll_fn(|cx| reader.poll_push_to(cx, dec)).await
+    }
+
+    /// Reads the header, after which the size, markers, and decoding options are available via `decompress()`.
+    pub async fn read_header(&mut self) -> io::Result<()> {
+        while self.dec.read_header()? == Progress::NeedMoreData {
+            self.read_more().await?;
+        }
+        Ok(())
+    }
+
+    /// Prepare for reading scanlines. Set options via `decompress()` first.
+    ///
+    /// For progressive JPEGs this reads the whole file.
+    pub async fn start_decompress(&mut self) -> io::Result<()> {
+        self.read_header().await?;
+        while self.dec.start_decompress()? == Progress::NeedMoreData {
+            self.read_more().await?;
+        }
+        Ok(())
+    }
+
+    /// Decodes the rows into `dest`, until it's full or the image ends. Returns number of rows read.
+    pub async fn read_scanlines_into<T: Pod>(&mut self, dest: &mut [T]) -> io::Result<usize> {
+        self.start_decompress().await?;
+        let line_width = self.dec.width() * pixel_items_per_pixel::<T>(self.dec.color_space().num_components())?;
+        let mut rows_read = 0;
+        while rows_read * line_width < dest.len() && !self.dec.is_scanlines_complete() {
+            match self.dec.read_scanlines_into(&mut dest[rows_read * line_width..])? {
+                Progress::Ready(rows) => rows_read += rows,
+                Progress::NeedMoreData => self.read_more().await?,
+            }
+        }
+        Ok(rows_read)
+    }
+
+    /// Decodes all remaining rows of the image
+    pub async fn read_scanlines<T: Pod>(&mut self) -> io::Result<Vec<T>> {
+        self.start_decompress().await?;
+        let mut image_dst: Vec<T> = Vec::new();
+        while !self.dec.is_scanlines_complete() {
+            match self.dec.read_scanlines::<T>()? {
+                Progress::Ready(rows) => image_dst.extend_from_slice(&rows),
+                Progress::NeedMoreData => self.read_more().await?,
+            }
+        }
+        Ok(image_dst)
+    }
+
+    /// Reads the rest of the file, and returns the reader
+    pub async fn finish(mut self) -> io::Result<R> {
+        while self.dec.finish()? == Progress::NeedMoreData {
+            self.read_more().await?;
+        }
+        Ok(self.reader)
+    }
+
+    /// Access to image properties and decoding options
+    #[inline]
+    pub fn decompress(&mut self) -> &mut IncrementalDecompress {
+        &mut self.dec
+    }
+}
+
+/// Writes a JPEG to an async writer
+///
+/// Create with `Compress::start_compress_async()`.
+///
+/// In single-pass mode (baseline files without MozJPEG's optimizations, e.g. after `set_fastest_defaults()`)
+/// libjpeg suspends when its output buffer is full, and the data is written out while the rows are compressed.
+///
+/// Progressive files, optimized Huffman tables, trellis quantization and scan optimization (the defaults)
+/// need multiple passes, and libjpeg writes them only in `finish()`, which can't suspend. In these modes
+/// expect the whole file to be buffered in memory before anything is written. Markers are buffered too.
+pub struct AsyncCompressStarted<W> {
+    started: CompressStarted<Vec<u8>>,
+    writer: W,
+}
+
+impl Compress {
+    /// Like `start_compress`, but for async writers, e.g. `FuturesIo(writer)` or `TokioIo(writer)`.
+    pub fn start_compress_async<W: AsyncSink>(self, writer: W) -> io::Result<AsyncCompressStarted<W>> {
+        Ok(AsyncCompressStarted {
+            started: self.start_compress(Vec::new())?,
+            writer,
+        })
+    }
+}
+
+impl<W: AsyncSink> AsyncCompressStarted<W> {
+    /// See `CompressStarted::write_marker`
+    pub fn write_marker(&mut self, marker: Marker, data: &[u8]) {
+        self.started.write_marker(marker, data);
+    }
+
+    /// See `CompressStarted::write_icc_profile`
+    pub fn write_icc_profile(&mut self, data: &[u8]) {
+        self.started.write_icc_profile(data);
+    }
+
+    /// Read-only view of component information
+    #[must_use]
+    pub fn components(&self) -> &[CompInfo] {
+        self.started.components()
+    }
+
+    /// Sends out data that libjpeg has produced so far
+    async fn write_pending(&mut self) -> io::Result<()> {
+        let pending = mem::take(self.started.writer_mut());
+        if !pending.is_empty() {
+            write_all(&mut self.writer, &pending).await?;
+            // reuse the allocation
+            *self.started.writer_mut() = pending;
+            self.started.writer_mut().clear();
+        }
+        Ok(())
+    }
+
+    /// Compresses rows, and writes the data libjpeg outputs (nothing in multi-pass modes, see above).
+    /// See `CompressStarted::write_scanlines`.
+    pub async fn write_scanlines(&mut self, mut image_src: &[u8]) -> io::Result<()> {
+        let byte_width = self.started.byte_width();
+        if byte_width == 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        while image_src.len() >= byte_width {
+            let rows = self.started.write_scanlines_suspending(image_src)?;
+            image_src = &image_src[rows * byte_width..];
+            self.write_pending().await?;
+        }
+        Ok(())
+    }
+
+    /// Finishes compression, writes the rest of the file, and returns the writer after flushing it
+    pub async fn finish(mut self) -> io::Result<W> {
+        let data = self.started.finish()?;
+        write_all(&mut self.writer, &data).await?;
+        poll_fn(|cx| self.writer.poll_flush_all(cx)).await?;
+        Ok(self.writer)
+    }
+}
+
+/// Accepts a few bytes at a time, and is pending every other time
+#[cfg(all(test, feature = "futures"))]
+#[derive(Default)]
+struct TrickleWriter {
+    data: Vec<u8>,
+    largest_write: usize,
+    pending: bool,
+}
+
+#[cfg(all(test, feature = "futures"))]
+impl futures_io::AsyncWrite for TrickleWriter {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.largest_write = self.largest_write.max(buf.len());
+        self.pending = !self.pending;
+        if self.pending {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        let len = buf.len().min(7);
+        self.data.extend_from_slice(&buf[..len]);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Returns a few bytes at a time, and is pending every other time
+#[cfg(all(test, feature = "futures"))]
+struct TrickleReader<'a> {
+    data: &'a [u8],
+    pending: bool,
+}
+
+#[cfg(all(test, feature = "futures"))]
+impl futures_io::AsyncRead for TrickleReader<'_> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        use futures_io::AsyncBufRead;
+        let data = match self.as_mut().poll_fill_buf(cx) {
+            Poll::Ready(Ok(data)) => data,
+            other => return other.map_ok(|_| 0),
+        };
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        self.consume(len);
+        Poll::Ready(Ok(len))
+    }
+}
+
+#[cfg(all(test, feature = "futures"))]
+impl futures_io::AsyncBufRead for TrickleReader<'_> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        this.pending = !this.pending;
+        if this.pending {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        Poll::Ready(Ok(&this.data[..this.data.len().min(5)]))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.data = &self.data[amt..];
+    }
+}
+
+#[cfg(feature = "futures")]
+#[test]
+fn roundtrip_with_partial_io() {
+    use crate::ColorSpace;
+    let (width, height) = (61, 37);
+    let pixels: Vec<u8> = (0..width * height * 3).map(|i| (i % 251) as u8).collect();
+
+    futures_executor::block_on(async {
+        let mut comp = Compress::new(ColorSpace::JCS_RGB);
+        comp.set_size(width, height);
+        let mut comp = comp.start_compress_async(FuturesIo(TrickleWriter::default())).unwrap();
+        comp.write_marker(Marker::COM, b"hello");
+        comp.write_scanlines(&pixels).await.unwrap();
+        let jpeg = comp.finish().await.unwrap().0.data;
+        let expected: Vec<[u8; 3]> = crate::Decompress::new_mem(&jpeg).unwrap().rgb().unwrap().read_scanlines().unwrap();
+        assert_eq!(width * height, expected.len());
+
+        let mut dec = crate::Decompress::builder()
+            .with_markers(&[Marker::COM])
+            .from_async_reader(FuturesIo(TrickleReader { data: &jpeg, pending: false }));
+        dec.read_header().await.unwrap();
+        assert_eq!((width, height), (dec.decompress().width(), dec.decompress().height()));
+        assert_eq!(b"hello", dec.decompress().markers().next().unwrap().data);
+        dec.decompress().to_colorspace(ColorSpace::JCS_RGB).unwrap();
+        let mut decoded = vec![[0u8; 3]; width * 20];
+        assert_eq!(20, dec.read_scanlines_into(&mut decoded).await.unwrap());
+        decoded.extend(dec.read_scanlines::<[u8; 3]>().await.unwrap());
+        assert_eq!(expected, decoded);
+        assert!(dec.finish().await.unwrap().0.data.is_empty());
+    });
+}
+
+#[cfg(feature = "futures")]
+#[test]
+fn baseline_is_written_while_compressing() {
+    use crate::ColorSpace;
+    // gets the smallest output buffer (4KB), but compresses to much more
+    let (width, height) = (2048, 16);
+    let pixels: Vec<u8> = (0..width * height * 3u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+    let settings = || {
+        let mut comp = Compress::new(ColorSpace::JCS_RGB);
+        comp.set_fastest_defaults();
+        comp.set_size(width as usize, height as usize);
+        comp.set_restart_interval(5);
+        comp
+    };
+    let mut blocking = settings().start_compress(Vec::new()).unwrap();
+    blocking.write_scanlines(&pixels).unwrap();
+    let expected = blocking.finish().unwrap();
+
+    futures_executor::block_on(async {
+        let mut comp = settings().start_compress_async(FuturesIo(TrickleWriter::default())).unwrap();
+        comp.write_scanlines(&pixels).await.unwrap();
+        assert!(comp.writer.0.data.len() > expected.len() / 2);
+        assert!(comp.writer.0.largest_write <= 4096);
+        let writer = comp.finish().await.unwrap().0;
+        assert_eq!(expected, writer.data);
+    });
+}
@@ -0,0 +1,291 @@
+//! Compressing and decompressing many images on a pool of threads. See `Batch`.
+use bytemuck::Pod;
+use crate::colorspace::{ColorSpace, ColorSpaceExt};
+use crate::compress::Compress;
+use crate::decompress::{pixel_items_per_pixel, Decompress};
+use crate::qtable::QTable;
+use std::any::Any;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Compression options applied to every image in a batch
+#[derive(Debug, Clone)]
+pub struct EncodeSettings {
+    /// 1-100, see `Compress::set_quality`
+    pub quality: f32,
+    /// Progressive JPEG with MozJPEG's scan optimization. Baseline if `false`.
+    pub progressive: bool,
+    /// Use libjpeg's faster defaults (no trellis quantization and no scan optimization), see `Compress::set_fastest_defaults`
+    pub fastest: bool,
+    /// Color space of the JPEG file, if different from the default for the input
+    pub jpeg_color_space: Option<ColorSpace>,
+    /// Pixel sizes of chroma channels, e.g. `(2, 2)` for 4:2:0 subsampling, see `Compress::set_chroma_sampling_pixel_sizes`
+    pub chroma_sampling: Option<(u8, u8)>,
+    /// MozJPEG's smoothing 0-100, see `Compress::set_smoothing_factor`
+    pub smoothing_factor: u8,
+    /// MozJPEG's trellis quantization, see `Compress::set_trellis_quantization`. Ignored if `fastest`.
+    pub trellis_quantization: bool,
+    /// Quantization table to use instead of the one scaled by `quality`, see `Compress::set_luma_qtable`
+    pub luma_qtable: Option<QTable>,
+    /// See `Compress::set_chroma_qtable`
+    pub chroma_qtable: Option<QTable>,
+}
+
+impl Default for EncodeSettings {
+    fn default() -> Self {
+        Self {
+            quality: 75.,
+            progressive: true,
+            fastest: false,
+            jpeg_color_space: None,
+            chroma_sampling: None,
+            smoothing_factor: 0,
+            trellis_quantization: true,
+            luma_qtable: None,
+            chroma_qtable: None,
+        }
+    }
+}
+
+impl EncodeSettings {
+    /// Configures `Compress` with these settings. Image size needs to be set separately.
+    pub fn apply(&self, comp: &mut Compress) {
+        if self.fastest {
+            comp.set_fastest_defaults();
+        }
+        if let Some(color_space) = self.jpeg_color_space {
+            comp.set_color_space(color_space);
+        }
+        if let Some(pixel_size) = self.chroma_sampling {
+            if comp.components().len() == 3 {
+                comp.set_chroma_sampling_pixel_sizes(pixel_size, pixel_size);
+            }
+        }
+        if !self.fastest {
+            comp.set_trellis_quantization(self.trellis_quantization);
+        }
+        comp.set_quality(self.quality);
+        if let Some(qtable) = &self.luma_qtable {
+            comp.set_luma_qtable(qtable);
+        }
+        if let Some(qtable) = &self.chroma_qtable {
+            comp.set_chroma_qtable(qtable);
+        }
+        comp.set_smoothing_factor(self.smoothing_factor);
+        if self.progressive {
+            comp.set_progressive_mode();
+        } else {
+            comp.set_optimize_scans(false);
+        }
+    }
+}
+
+/// Uncompressed image to compress in a batch
+#[derive(Debug, Copy, Clone)]
+pub struct EncodeInput<'a> {
+    /// Rows of width × number of components bytes, top to bottom
+    pub pixels: &'a [u8],
+    pub width: usize,
+    pub height: usize,
+    /// Color space of the `pixels`
+    pub color_space: ColorSpace,
+}
+
+/// Decompressed image
+#[derive(Debug, Clone)]
+pub struct DecodedImage<T> {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<T>,
+}
+
+/// Outcome of processing one image of a batch
+#[derive(Debug)]
+pub struct ItemResult<T> {
+    /// libjpeg's fatal errors are returned as errors too
+    pub result: io::Result<T>,
+    /// How long it took to process this image
+    pub duration: Duration,
+}
+
+/// Runs `Compress` or `Decompress` for many images on a pool of threads
+///
+/// Every thread reuses its `Compress` for consecutive images. Files are decoded with a new `Decompress` each,
+/// so that a file can't use tables left by another one. Failure of one image, including
+/// libjpeg's fatal errors (which are panics in this library), doesn't affect other images.
+///
+/// ```rust
+/// # use mozjpeg::*;
+/// # use mozjpeg::batch::*;
+/// let pixels = vec![128; 8 * 8 * 3];
+/// let inputs = [EncodeInput { pixels: &pixels, width: 8, height: 8, color_space: ColorSpace::JCS_RGB }; 3];
+/// let results = Batch::new().encode(&inputs, &EncodeSettings::default());
+/// assert!(results.iter().all(|r| r.result.is_ok()));
+/// ```
+pub struct Batch {
+    threads: usize,
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Batch {
+    /// Uses as many threads as there are CPUs available
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+
+    /// Max number of images processed at the same time
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    /// Compresses every input with the same settings. Results are in the same order as inputs.
+    pub fn encode(&self, inputs: &[EncodeInput<'_>], settings: &EncodeSettings) -> Vec<ItemResult<Vec<u8>>> {
+        self.run(inputs, |reuse: &mut Option<Compress>, input| {
+            let mut comp = match reuse.take() {
+                Some(mut comp) => {
+                    comp.reset(input.color_space);
+                    comp
+                },
+                None => Compress::new(input.color_space),
+            };
+            if input.width == 0 || input.height == 0 || input.pixels.len() != input.width * input.height * input.color_space.num_components() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("expected {}x{} pixels, got {}B", input.width, input.height, input.pixels.len())));
+            }
+            settings.apply(&mut comp);
+            comp.set_size(input.width, input.height);
+            let mut started = comp.start_compress(Vec::new())?;
+            started.write_scanlines(input.pixels)?;
+            let (comp, jpeg) = started.finish_for_reuse()?;
+            *reuse = Some(comp);
+            Ok(jpeg)
+        })
+    }
+
+    /// Decompresses every JPEG file to pixels in the given color space. Results are in the same order as inputs.
+    ///
+    /// Pixels can be `u8` or pixel types, like in `DecompressStarted::read_scanlines`.
+    pub fn decode<T: Pod + Send>(&self, inputs: &[&[u8]], color_space: ColorSpace) -> Vec<ItemResult<DecodedImage<T>>> {
+        // `ReusableDecompress` would keep tables of previous files, and files without them
+        // would decode with tables of whichever file the thread happened to decode before
+        self.run(inputs, |_: &mut Option<()>, input| {
+            pixel_items_per_pixel::<T>(color_space.num_components())?;
+            let mut started = Decompress::new_mem(input)?.to_colorspace(color_space)?;
+            let (width, height) = (started.width(), started.height());
+            let pixels = started.read_scanlines()?;
+            started.finish()?;
+            Ok(DecodedImage { width, height, pixels })
+        })
+    }
+
+    /// Workers take inputs in order, and keep a reusable object between them
+    fn run<I: Sync, O: Send, S>(&self, inputs: &[I], process: impl Fn(&mut Option<S>, &I) -> io::Result<O> + Sync) -> Vec<ItemResult<O>> {
+        let next_input = AtomicUsize::new(0);
+        let worker = || {
+            let mut reuse = None;
+            let mut done = Vec::new();
+            loop {
+                let index = next_input.fetch_add(1, Ordering::Relaxed);
+                let Some(input) = inputs.get(index) else { break };
+                let start = Instant::now();
+                // libjpeg object that panicked is dropped, and won't be reused
+                let result = panic::catch_unwind(AssertUnwindSafe(|| process(&mut reuse, input)))
+                    .unwrap_or_else(|payload| Err(panic_to_error(payload)));
+                done.push((index, ItemResult { result, duration: start.elapsed() }));
+            }
+            done
+        };
+
+        let mut results: Vec<_> = thread::scope(|s| {
+            let handles: Vec<_> = (0..self.threads.min(inputs.len())).map(|_| s.spawn(worker)).collect();
+            handles.into_iter().flat_map(|h| h.join().unwrap_or_else(|e| panic::resume_unwind(e))).collect()
+        });
+        results.sort_unstable_by_key(|&(index, _)| index);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+}
+
+#[cold]
+pub(crate) fn panic_to_error(payload: Box<dyn Any + Send>) -> io::Error {
+    let msg = match payload.downcast::<String>() {
+        Ok(msg) => *msg,
+        Err(payload) => payload.downcast_ref::<&str>().map_or("libjpeg error", |s| s).to_string(),
+    };
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[test]
+fn batch_roundtrip() {
+    let images: Vec<(usize, usize, Vec<u8>)> = (1..20).map(|i| {
+        let (width, height) = (i * 7, 100 / i);
+        (width, height, (0..width * height * 3).map(|p| (p * i) as u8).collect())
+    }).collect();
+    let mut inputs: Vec<_> = images.iter().map(|(width, height, pixels)| EncodeInput {
+        pixels, width: *width, height: *height, color_space: ColorSpace::JCS_RGB,
+    }).collect();
+    inputs.insert(5, EncodeInput { pixels: &[1, 2, 3], width: 7, height: 7, color_space: ColorSpace::JCS_RGB });
+    inputs.push(EncodeInput { pixels: &images[2].2[..images[2].0 * images[2].1], width: images[2].0, height: images[2].1, color_space: ColorSpace::JCS_GRAYSCALE });
+
+    let mut batch = Batch::new();
+    batch.set_threads(3);
+    let settings = EncodeSettings { quality: 90., progressive: false, ..EncodeSettings::default() };
+    let encoded = batch.encode(&inputs, &settings);
+    assert_eq!(inputs.len(), encoded.len());
+    assert!(encoded[5].result.is_err());
+
+    let mut files: Vec<Vec<u8>> = encoded.into_iter().map(|r| r.result.unwrap_or_default()).collect();
+    files[7].truncate(20);
+    let decoded = batch.decode::<[u8; 3]>(&files.iter().map(|f| &f[..]).collect::<Vec<_>>(), ColorSpace::JCS_RGB);
+    for (i, (input, result)) in inputs.iter().zip(decoded).enumerate() {
+        match i {
+            5 | 7 => assert!(result.result.is_err()),
+            _ => {
+                let image = result.result.unwrap();
+                assert_eq!((input.width, input.height), (image.width, image.height));
+                assert_eq!(image.width * image.height, image.pixels.len());
+            },
+        }
+    }
+
+    let mut comp = Compress::new(ColorSpace::JCS_RGB);
+    settings.apply(&mut comp);
+    comp.set_size(images[0].0, images[0].1);
+    let mut started = comp.start_compress(Vec::new()).unwrap();
+    started.write_scanlines(&images[0].2).unwrap();
+    assert_eq!(started.finish().unwrap(), files[0]);
+}
+
+#[test]
+fn decode_does_not_use_tables_of_other_files() {
+    let pixels: Vec<u8> = (0..32 * 32 * 3).map(|i| (i * 7) as u8).collect();
+    let files: Vec<Vec<u8>> = [50., 90.].iter().map(|&quality| {
+        let settings = EncodeSettings { quality, ..EncodeSettings::default() };
+        let input = EncodeInput { pixels: &pixels, width: 32, height: 32, color_space: ColorSpace::JCS_RGB };
+        Batch::new().encode(&[input], &settings).pop().unwrap().result.unwrap()
+    }).collect();
+    let without_dqt = crate::parse::without_segments(&files[0], 0xDB);
+    assert!(without_dqt.len() < files[0].len());
+
+    for threads in [1, 3] {
+        let mut batch = Batch::new();
+        batch.set_threads(threads);
+        for pos in 0..=4 {
+            let mut inputs: Vec<&[u8]> = vec![&files[0], &files[1], &files[0], &files[1]];
+            inputs.insert(pos, &without_dqt);
+            let decoded = batch.decode::<[u8; 3]>(&inputs, ColorSpace::JCS_RGB);
+            for (i, item) in decoded.iter().enumerate() {
+                assert_eq!(i != pos, item.result.is_ok(), "{threads} {pos} {i}");
+            }
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Argument parsing and error reporting shared by the command-line tools
+#![allow(dead_code)] // each binary uses only some of these
+
+use std::any::Any;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::panic::{self, UnwindSafe};
+use std::process::ExitCode;
+
+pub type CliResult<T = ()> = Result<T, Box<dyn Error>>;
+
+/// Runs the tool, printing errors to stderr. libjpeg's fatal errors are panics without a message of their own.
+pub fn run_reporting_errors(name: &str, run: impl FnOnce() -> CliResult + UnwindSafe) -> ExitCode {
+    let err = match panic::catch_unwind(run) {
+        Ok(Ok(())) => return ExitCode::SUCCESS,
+        Ok(Err(e)) => e.to_string(),
+        Err(payload) => panic_message(&*payload),
+    };
+    eprintln!("{name}: {err}");
+    ExitCode::FAILURE
+}
+
+/// Message of a libjpeg error (or any other panic) caught with `catch_unwind`
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload.downcast_ref::<String>().cloned()
+        .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+        .unwrap_or_else(|| "libjpeg error".into())
+}
+
+/// Prints the error and usage for invalid arguments
+pub fn usage_error(name: &str, usage: &str, err: &dyn Error) -> ExitCode {
+    eprintln!("{name}: {err}\n\n{usage}");
+    ExitCode::from(2)
+}
+
+/// `-switch` or `--switch` without the dashes. `None` for file names, and `-` meaning stdin/stdout.
+pub fn switch_name(arg: &str) -> Option<&str> {
+    arg.strip_prefix("--").or_else(|| arg.strip_prefix('-')).filter(|s| !s.is_empty())
+}
+
+pub fn parse_pair<T: std::str::FromStr>(s: &str, separator: char) -> CliResult<(T, T)> where T::Err: Error + 'static {
+    let (a, b) = s.split_once(separator).ok_or_else(|| format!("expected {separator}-separated pair, got '{s}'"))?;
+    Ok((a.parse()?, b.parse()?))
+}
+
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// `WxH+X+Y` or `WxH` (at 0,0), like djpeg's and jpegtran's `-crop`
+pub fn parse_region(s: &str) -> CliResult<Region> {
+    let (size, offsets) = match s.split_once('+') {
+        Some((size, offsets)) if offsets.contains('+') => (size, offsets),
+        None => (s, "0+0"),
+        Some(_) => return Err(format!("crop region must be WxH+X+Y or WxH, got '{s}'").into()),
+    };
+    let (width, height) = parse_pair(size, 'x')?;
+    let (x, y) = parse_pair(offsets, '+')?;
+    Ok(Region { x, y, width, height })
+}
+
+/// File, or stdin if there's no path or it's `-`
+pub fn open_input(path: Option<&str>) -> CliResult<Box<dyn Read>> {
+    Ok(match path {
+        Some(path) if path != "-" => Box::new(File::open(path).map_err(|e| format!("can't open {path}: {e}"))?),
+        _ => Box::new(io::stdin().lock()),
+    })
+}
+
+/// File, or stdout if there's no path or it's `-`
+pub fn create_output(path: Option<&str>) -> CliResult<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) if path != "-" => Box::new(File::create(path).map_err(|e| format!("can't create {path}: {e}"))?),
+        _ => Box::new(io::stdout().lock()),
+    })
+}
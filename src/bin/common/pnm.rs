@@ -0,0 +1,177 @@
+//! Netpbm (PGM, PPM, PAM) files, as used by cjpeg and djpeg
+#![allow(dead_code)] // each binary uses only some of these
+
+use mozjpeg::{ColorSpace, ColorSpaceExt};
+use std::io::{self, BufRead, Read, Write};
+
+/// 8-bit interleaved pixels
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    /// `JCS_GRAYSCALE`, `JCS_RGB` or `JCS_CMYK`
+    pub color_space: ColorSpace,
+    pub pixels: Vec<u8>,
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Reads binary or ASCII PGM/PPM, or PAM. Alpha is discarded, and samples with `maxval` other than 255 are rescaled to 8 bits.
+pub fn read(r: &mut impl BufRead) -> io::Result<Image> {
+    let mut magic = [0; 2];
+    r.read_exact(&mut magic)?;
+    let (width, height, depth, maxval, color_space, ascii) = match &magic {
+        b"P2" | b"P3" | b"P5" | b"P6" => {
+            let width = read_number(r)?;
+            let height = read_number(r)?;
+            let maxval = read_number(r)?;
+            let gray = matches!(magic[1], b'2' | b'5');
+            let color_space = if gray { ColorSpace::JCS_GRAYSCALE } else { ColorSpace::JCS_RGB };
+            (width, height, color_space.num_components(), maxval, color_space, magic[1] < b'5')
+        },
+        b"P7" => {
+            let (width, height, depth, maxval, tuple_type) = read_pam_header(r)?;
+            let color_space = match (depth, tuple_type.as_str()) {
+                (1 | 2, _) => ColorSpace::JCS_GRAYSCALE,
+                (4, "CMYK") => ColorSpace::JCS_CMYK,
+                (3 | 4, _) => ColorSpace::JCS_RGB,
+                _ => return Err(invalid(format!("unsupported PAM depth {depth} {tuple_type}"))),
+            };
+            (width, height, depth, maxval, color_space, false)
+        },
+        _ => return Err(invalid("not a PGM/PPM/PAM file")),
+    };
+    if width == 0 || height == 0 || maxval == 0 || maxval > 0xFFFF {
+        return Err(invalid(format!("invalid image size {width}x{height} or maxval {maxval}")));
+    }
+
+    let samples = width.checked_mul(height).and_then(|n| n.checked_mul(depth)).ok_or_else(|| invalid("image too large"))?;
+    let samples = if ascii {
+        (0..samples).map(|_| read_number(r)).collect::<io::Result<Vec<_>>>()?
+    } else {
+        let bytes_per_sample = if maxval > 255 { 2 } else { 1 };
+        let mut data = Vec::new();
+        r.take((samples * bytes_per_sample) as u64).read_to_end(&mut data)?;
+        if data.len() != samples * bytes_per_sample {
+            return Err(invalid("truncated pixel data"));
+        }
+        if bytes_per_sample == 2 {
+            data.chunks_exact(2).map(|s| usize::from(u16::from_be_bytes([s[0], s[1]]))).collect()
+        } else {
+            data.into_iter().map(usize::from).collect()
+        }
+    };
+
+    let components = color_space.num_components();
+    let pixels = samples.chunks_exact(depth)
+        .flat_map(|px| &px[..components])
+        .map(|&v| if maxval == 255 { v as u8 } else { ((v.min(maxval) * 255 + maxval / 2) / maxval) as u8 })
+        .collect();
+    Ok(Image { width, height, color_space, pixels })
+}
+
+/// Raw interleaved 8-bit samples of known size
+pub fn read_raw(r: &mut impl Read, width: usize, height: usize, color_space: ColorSpace) -> io::Result<Image> {
+    let len = width * height * color_space.num_components();
+    let mut pixels = Vec::with_capacity(len);
+    r.take(len as u64 + 1).read_to_end(&mut pixels)?;
+    if pixels.len() != len {
+        return Err(invalid(format!("expected {len} bytes of {width}x{height} pixels, got {}", pixels.len())));
+    }
+    Ok(Image { width, height, color_space, pixels })
+}
+
+/// Writes binary PGM for grayscale, PPM for RGB, and PAM for CMYK or if `pam` is set
+pub fn write(w: &mut impl Write, image: &Image, pam: bool) -> io::Result<()> {
+    match image.color_space {
+        ColorSpace::JCS_GRAYSCALE if !pam => write!(w, "P5\n{} {}\n255\n", image.width, image.height)?,
+        ColorSpace::JCS_RGB if !pam => write!(w, "P6\n{} {}\n255\n", image.width, image.height)?,
+        cs => {
+            let tuple_type = match cs {
+                ColorSpace::JCS_GRAYSCALE => "GRAYSCALE",
+                ColorSpace::JCS_RGB => "RGB",
+                ColorSpace::JCS_CMYK => "CMYK",
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("can't write {cs:?} to PNM"))),
+            };
+            write!(w, "P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL 255\nTUPLTYPE {tuple_type}\nENDHDR\n",
+                image.width, image.height, cs.num_components())?;
+        },
+    }
+    w.write_all(&image.pixels)
+}
+
+/// width, height, depth, maxval, tuple type
+fn read_pam_header(r: &mut impl BufRead) -> io::Result<(usize, usize, usize, usize, String)> {
+    let (mut width, mut height, mut depth, mut maxval) = (0, 0, 0, 0);
+    let mut tuple_type = String::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if r.read_line(&mut line)? == 0 {
+            return Err(invalid("missing PAM ENDHDR"));
+        }
+        let mut words = line.split_whitespace();
+        let Some(key) = words.next() else { continue };
+        let value = || words.clone().next().and_then(|v| v.parse().ok()).ok_or_else(|| invalid(format!("invalid PAM {key}")));
+        match key {
+            "ENDHDR" => return Ok((width, height, depth, maxval, tuple_type)),
+            "WIDTH" => width = value()?,
+            "HEIGHT" => height = value()?,
+            "DEPTH" => depth = value()?,
+            "MAXVAL" => maxval = value()?,
+            "TUPLTYPE" => tuple_type = words.collect::<Vec<_>>().join(" "),
+            _ => {}, // comments
+        }
+    }
+}
+
+/// Decimal number in a PNM header or ASCII data, skipping whitespace and comments
+fn read_number(r: &mut impl BufRead) -> io::Result<usize> {
+    let mut byte = [0];
+    let mut value: Option<usize> = None;
+    loop {
+        if r.read(&mut byte)? == 0 {
+            break;
+        }
+        match byte[0] {
+            b'0'..=b'9' => {
+                let digit = usize::from(byte[0] - b'0');
+                value = Some(value.unwrap_or(0).checked_mul(10).and_then(|v| v.checked_add(digit)).ok_or_else(|| invalid("number too large"))?);
+            },
+            b'#' if value.is_none() => {
+                r.read_line(&mut String::new())?;
+            },
+            b' ' | b'\t' | b'\r' | b'\n' if value.is_none() => {},
+            // one whitespace byte ends the number; for binary data it also ends the header
+            b' ' | b'\t' | b'\r' | b'\n' => break,
+            _ => return Err(invalid("invalid character in PNM header")),
+        }
+    }
+    value.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+}
+
+#[test]
+fn reads_pnm() {
+    let img = read(&mut &b"P3 # comment\n2 1\n# another\n15\n0 15 0\n15 15 15\n"[..]).unwrap();
+    assert_eq!((2, 1, ColorSpace::JCS_RGB), (img.width, img.height, img.color_space));
+    assert_eq!(img.pixels, [0, 255, 0, 255, 255, 255]);
+
+    let img = read(&mut &b"P5\n2 1\n65535\n\xff\xff\x80\x00"[..]).unwrap();
+    assert_eq!(img.pixels, [255, 128]);
+
+    let pam = b"P7\nWIDTH 1\nHEIGHT 2\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n\x01\x02\x03\x04\x05\x06\x07\x08";
+    let img = read(&mut &pam[..]).unwrap();
+    assert_eq!((1, 2, ColorSpace::JCS_RGB), (img.width, img.height, img.color_space));
+    assert_eq!(img.pixels, [1, 2, 3, 5, 6, 7]);
+
+    for pam in [false, true] {
+        let mut out = Vec::new();
+        write(&mut out, &img, pam).unwrap();
+        let again = read(&mut &out[..]).unwrap();
+        assert_eq!((img.width, img.height, img.color_space, &img.pixels), (again.width, again.height, again.color_space, &again.pixels));
+    }
+
+    assert!(read(&mut &b"P6\n2 2\n255\n\0\0\0"[..]).is_err());
+    assert!(read(&mut &b"BM"[..]).is_err());
+}
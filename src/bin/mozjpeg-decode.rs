@@ -0,0 +1,152 @@
+//! djpeg-style decoder: decompresses JPEG to PPM/PGM/PAM
+
+#[path = "common/cli.rs"]
+mod cli;
+#[path = "common/pnm.rs"]
+mod pnm;
+
+use cli::{parse_pair, parse_region, CliResult, Region};
+use mozjpeg::{ColorSpace, DctMethod, Decompress, Marker, ALL_MARKERS};
+use std::io::{BufReader, BufWriter, Write};
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+usage: mozjpeg-decode [switches] [inputfile]
+Reads JPEG from inputfile or stdin, and writes PPM/PGM (or PAM) to -outfile or stdout.
+
+Switches:
+  -scale M/N        Scale output image by fraction M/N, in steps of 1/8 from 1/8 to 2
+  -dct int|fast|float  DCT method (default int)
+  -nosmooth         Use faster, less accurate chroma upsampling
+  -rgb              Output RGB (default for color images)
+  -grayscale        Output grayscale
+  -cmyk             Output CMYK (only for CMYK/YCCK files, written as PAM)
+  -crop WxH[+X+Y]   Decode only the given region of the (scaled) image
+  -pam              Write PAM instead of PPM/PGM
+  -dump-markers PREFIX  Write every APPn/COM marker to PREFIX-N-NAME.bin
+  -icc FILE         Write the embedded ICC profile to FILE
+  -outfile FILE     Write to FILE instead of stdout
+  -version          Print version and exit
+";
+
+#[derive(Default)]
+struct Options {
+    scale: Option<u8>,
+    dct_method: Option<DctMethod>,
+    nosmooth: bool,
+    color_space: Option<ColorSpace>,
+    crop: Option<Region>,
+    pam: bool,
+    dump_markers: Option<String>,
+    icc: Option<String>,
+    input: Option<String>,
+    output: Option<String>,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match parse_args(&args) {
+        Ok(Some(opts)) => cli::run_reporting_errors("mozjpeg-decode", || run(&opts)),
+        Ok(None) => ExitCode::SUCCESS,
+        Err(e) => cli::usage_error("mozjpeg-decode", USAGE, &*e),
+    }
+}
+
+fn parse_args(args: &[String]) -> CliResult<Option<Options>> {
+    let mut opts = Options::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let Some(switch) = cli::switch_name(arg) else {
+            if opts.input.replace(arg.clone()).is_some() {
+                return Err("only one input file can be given".into());
+            }
+            continue;
+        };
+        let mut value = || args.next().ok_or_else(|| format!("-{switch} requires a value"));
+        match switch {
+            "scale" => {
+                let (m, n): (u32, u32) = parse_pair(value()?, '/')?;
+                // libjpeg scales by multiples of 1/8
+                let eighths = m.checked_mul(8).filter(|&m8| n != 0 && m8 % n == 0).map(|m8| m8 / n);
+                opts.scale = Some(eighths.filter(|e| (1..=16).contains(e))
+                    .ok_or("scale must be between 1/8 and 2, in steps of 1/8")? as u8);
+            },
+            "dct" => opts.dct_method = Some(match value()?.as_str() {
+                "int" => DctMethod::IntegerSlow,
+                "fast" => DctMethod::IntegerFast,
+                "float" => DctMethod::Float,
+                other => return Err(format!("unknown DCT method {other}").into()),
+            }),
+            "nosmooth" => opts.nosmooth = true,
+            "rgb" => opts.color_space = Some(ColorSpace::JCS_RGB),
+            "grayscale" | "greyscale" | "gray" => opts.color_space = Some(ColorSpace::JCS_GRAYSCALE),
+            "cmyk" => opts.color_space = Some(ColorSpace::JCS_CMYK),
+            "crop" => opts.crop = Some(parse_region(value()?)?),
+            "pam" => opts.pam = true,
+            "dump-markers" => opts.dump_markers = Some(value()?.clone()),
+            "icc" => opts.icc = Some(value()?.clone()),
+            "outfile" | "o" => opts.output = Some(value()?.clone()),
+            "version" => {
+                println!("mozjpeg-decode {}", env!("CARGO_PKG_VERSION"));
+                return Ok(None);
+            },
+            "help" | "h" => {
+                print!("{USAGE}");
+                return Ok(None);
+            },
+            _ => return Err(format!("unknown switch -{switch}").into()),
+        }
+    }
+    Ok(Some(opts))
+}
+
+fn marker_name(marker: Marker) -> String {
+    match marker {
+        Marker::APP(n) => format!("APP{n}"),
+        Marker::COM => "COM".into(),
+    }
+}
+
+fn run(opts: &Options) -> CliResult {
+    let input = BufReader::new(cli::open_input(opts.input.as_deref())?);
+    let markers = if opts.dump_markers.is_some() { ALL_MARKERS } else { &[Marker::APP(2)] };
+    let mut dec = Decompress::with_markers(markers).from_reader(input)?;
+
+    if let Some(prefix) = &opts.dump_markers {
+        for (i, m) in dec.markers().enumerate() {
+            let path = format!("{prefix}-{i}-{}.bin", marker_name(m.marker));
+            std::fs::write(&path, m.data).map_err(|e| format!("can't write {path}: {e}"))?;
+        }
+    }
+    if let Some(path) = &opts.icc {
+        let icc = dec.icc_profile().ok_or("the file has no ICC profile")?;
+        std::fs::write(path, icc).map_err(|e| format!("can't write {path}: {e}"))?;
+    }
+
+    if let Some(scale) = opts.scale {
+        dec.scale(scale);
+    }
+    if let Some(method) = opts.dct_method {
+        dec.dct_method(method);
+    }
+    if opts.nosmooth {
+        dec.do_fancy_upsampling(false);
+    }
+    let color_space = opts.color_space.unwrap_or(match dec.color_space() {
+        ColorSpace::JCS_GRAYSCALE => ColorSpace::JCS_GRAYSCALE,
+        _ => ColorSpace::JCS_RGB,
+    });
+
+    let mut started = dec.to_colorspace(color_space)?;
+    let (width, height, pixels) = if let Some(r) = &opts.crop {
+        (r.width, r.height, started.decode_region(r.x, r.y, r.width, r.height)?)
+    } else {
+        (started.width(), started.height(), started.read_scanlines()?)
+    };
+    started.finish()?;
+
+    let mut output = BufWriter::new(cli::create_output(opts.output.as_deref())?);
+    pnm::write(&mut output, &pnm::Image { width, height, color_space, pixels }, opts.pam)?;
+    output.flush()?;
+    Ok(())
+}
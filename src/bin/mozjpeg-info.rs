@@ -0,0 +1,191 @@
+//! Prints how JPEG files have been encoded: sampling, tables, scans and markers
+
+#[path = "common/cli.rs"]
+mod cli;
+
+use cli::CliResult;
+use mozjpeg::huffman::HuffmanClass;
+use mozjpeg::info::Layout;
+use mozjpeg::qtable::QTable;
+use mozjpeg::{JpegInfo, Marker};
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+usage: mozjpeg-info [switches] [inputfile...]
+Prints structure of JPEG files (or stdin) as text or JSON.
+
+Switches:
+  -json             Print JSON instead of text (an array if there's more than one file)
+  -tables           Include values of quantization and Huffman tables in the text output
+  -version          Print version and exit
+";
+
+#[derive(Default)]
+struct Options {
+    json: bool,
+    tables: bool,
+    inputs: Vec<String>,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match parse_args(&args) {
+        Ok(Some(opts)) => cli::run_reporting_errors("mozjpeg-info", || run(&opts)),
+        Ok(None) => ExitCode::SUCCESS,
+        Err(e) => cli::usage_error("mozjpeg-info", USAGE, &*e),
+    }
+}
+
+fn parse_args(args: &[String]) -> CliResult<Option<Options>> {
+    let mut opts = Options::default();
+    for arg in args {
+        let Some(switch) = cli::switch_name(arg) else {
+            opts.inputs.push(arg.clone());
+            continue;
+        };
+        match switch {
+            "json" => opts.json = true,
+            "tables" => opts.tables = true,
+            "version" => {
+                println!("mozjpeg-info {}", env!("CARGO_PKG_VERSION"));
+                return Ok(None);
+            },
+            "help" | "h" => {
+                print!("{USAGE}");
+                return Ok(None);
+            },
+            _ => return Err(format!("unknown switch -{switch}").into()),
+        }
+    }
+    if opts.inputs.is_empty() {
+        opts.inputs.push("-".into());
+    }
+    Ok(Some(opts))
+}
+
+fn run(opts: &Options) -> CliResult {
+    let mut reports = Vec::with_capacity(opts.inputs.len());
+    for input in &opts.inputs {
+        let mut data = Vec::new();
+        cli::open_input(Some(input))?.read_to_end(&mut data)?;
+        let info = JpegInfo::from_mem(&data).map_err(|e| format!("{input}: {e}"))?;
+        reports.push(if opts.json { json_report(&info) } else { text_report(input, &info, opts.tables) });
+    }
+    let mut out = std::io::stdout().lock();
+    if opts.json && reports.len() > 1 {
+        writeln!(out, "[{}]", reports.join(",\n"))?;
+    } else {
+        for report in reports {
+            writeln!(out, "{report}")?;
+        }
+    }
+    Ok(())
+}
+
+fn marker_name(marker: Marker) -> String {
+    match marker {
+        Marker::APP(n) => format!("APP{n}"),
+        Marker::COM => "COM".into(),
+    }
+}
+
+fn table_rows(values: &[u32]) -> String {
+    values.chunks(8).map(|row| {
+        let row: Vec<_> = row.iter().map(|v| format!("{v:4}")).collect();
+        format!("      {}\n", row.join(""))
+    }).collect()
+}
+
+fn text_report(name: &str, info: &JpegInfo, tables: bool) -> String {
+    let mut s = String::new();
+    let _ = writeln!(s, "{name}: {}x{} {:?}", info.width, info.height, info.color_space);
+    if let Some(l) = &info.layout {
+        let _ = writeln!(s, "  mode: {:?}{}, {} scans, restart interval {}", l.mode,
+            if l.arithmetic { " arithmetic" } else { "" }, l.scans.len(), l.restart_interval);
+    }
+    if let Some(q) = info.estimate_quality() {
+        let chroma = q.chroma_quality.map(|c| format!("/{c}")).unwrap_or_default();
+        let _ = writeln!(s, "  quality: {}{chroma} (confidence {:.2}), closest preset {} x{:.2}",
+            q.luma_quality, q.confidence, q.preset, q.preset_scale);
+    }
+    for c in &info.components {
+        let _ = writeln!(s, "  component {}: sampling {}x{}, qtable {}", c.id, c.sampling.0, c.sampling.1, c.qtable_slot);
+    }
+    for (slot, q) in info.qtables.iter().enumerate() {
+        let Some(q) = q else { continue };
+        let _ = writeln!(s, "  qtable {slot}");
+        if tables {
+            s += &table_rows(q.as_slice());
+        }
+    }
+    let Some(l) = &info.layout else { return s };
+    for t in &l.huffman_tables {
+        let _ = writeln!(s, "  huffman {:?} {} at {}: {} codes", t.class, t.slot, t.offset, t.table.values().len());
+        if tables {
+            let _ = writeln!(s, "      counts {:?}", t.table.code_counts());
+            let _ = writeln!(s, "      values {:?}", t.table.values());
+        }
+    }
+    for (i, scan) in l.scans.iter().enumerate() {
+        let ids: Vec<_> = scan.components.iter().map(|c| c.id.to_string()).collect();
+        let _ = writeln!(s, "  scan {i} at {} ({} bytes): components {}, Ss={} Se={} Ah={} Al={}", scan.offset, scan.length,
+            ids.join(","), scan.spectral_selection.0, scan.spectral_selection.1,
+            scan.successive_approximation.0, scan.successive_approximation.1);
+    }
+    for seg in &l.segments {
+        let _ = writeln!(s, "  marker {} at {} ({} bytes)", seg.name(), seg.offset, seg.length);
+    }
+    if !l.complete {
+        s += "  missing EOI (truncated file)\n";
+    }
+    if l.trailing_bytes > 0 {
+        let _ = writeln!(s, "  {} bytes after EOI", l.trailing_bytes);
+    }
+    s
+}
+
+fn json_list<T>(items: impl IntoIterator<Item = T>, f: impl Fn(T) -> String) -> String {
+    let items: Vec<_> = items.into_iter().map(f).collect();
+    format!("[{}]", items.join(","))
+}
+
+/// JSON has no NaN or infinity
+fn json_f32(v: f32) -> String {
+    if v.is_finite() { v.to_string() } else { "null".into() }
+}
+
+fn json_qtable(q: &QTable) -> String {
+    json_list(q.as_slice(), |v| v.to_string())
+}
+
+fn json_layout(l: &Layout) -> String {
+    format!(
+        r#"{{"mode":"{:?}","arithmetic":{},"restart_interval":{},"complete":{},"trailing_bytes":{},"huffman_tables":{},"scans":{},"segments":{}}}"#,
+        l.mode, l.arithmetic, l.restart_interval, l.complete, l.trailing_bytes,
+        json_list(&l.huffman_tables, |t| format!(r#"{{"class":"{}","slot":{},"offset":{},"counts":{},"values":{}}}"#,
+            if t.class == HuffmanClass::Dc { "DC" } else { "AC" }, t.slot, t.offset,
+            json_list(t.table.code_counts(), |v| v.to_string()), json_list(t.table.values(), |v| v.to_string()))),
+        json_list(&l.scans, |scan| format!(r#"{{"offset":{},"length":{},"components":{},"Ss":{},"Se":{},"Ah":{},"Al":{}}}"#,
+            scan.offset, scan.length,
+            json_list(&scan.components, |c| format!(r#"{{"id":{},"dc_table":{},"ac_table":{}}}"#, c.id, c.dc_table, c.ac_table)),
+            scan.spectral_selection.0, scan.spectral_selection.1,
+            scan.successive_approximation.0, scan.successive_approximation.1)),
+        json_list(&l.segments, |seg| format!(r#"{{"marker":"{}","offset":{},"length":{}}}"#, seg.name(), seg.offset, seg.length)),
+    )
+}
+
+fn json_report(info: &JpegInfo) -> String {
+    format!(
+        r#"{{"width":{},"height":{},"color_space":"{:?}","quality":{},"components":{},"qtables":{},"markers":{},"layout":{}}}"#,
+        info.width, info.height, info.color_space,
+        info.estimate_quality().map_or_else(|| "null".into(), |q| format!(
+            r#"{{"luma":{},"chroma":{},"confidence":{},"preset":"{}","preset_scale":{}}}"#, q.luma_quality,
+            q.chroma_quality.map_or_else(|| "null".into(), |c| c.to_string()), json_f32(q.confidence), q.preset, json_f32(q.preset_scale))),
+        json_list(&info.components, |c| format!(r#"{{"id":{},"sampling":[{},{}],"qtable":{}}}"#, c.id, c.sampling.0, c.sampling.1, c.qtable_slot)),
+        json_list(&info.qtables, |q| q.as_ref().map_or_else(|| "null".into(), json_qtable)),
+        json_list(&info.markers, |&(m, size)| format!(r#"{{"marker":"{}","size":{size}}}"#, marker_name(m))),
+        info.layout.as_ref().map_or_else(|| "null".into(), json_layout),
+    )
+}
@@ -0,0 +1,233 @@
+//! jpegtran-style lossless optimizer: rewrites JPEG files in place, optionally rotated, flipped or cropped
+
+#[path = "common/cli.rs"]
+mod cli;
+
+use cli::{parse_region, CliResult, Region};
+use filetime::FileTime;
+use mozjpeg::transform::{CopyMarkers, Operation, Transform};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::panic::{self, AssertUnwindSafe};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+
+const USAGE: &str = "\
+usage: mozjpeg-optimize [switches] file-or-directory...
+Losslessly optimizes JPEG files in place. Directories are searched recursively for .jpg/.jpeg files.
+Files are replaced only if the result is smaller, unless the image is transformed. Timestamps are preserved.
+
+Switches:
+  -progressive      Progressive JPEG with optimized scans (default)
+  -baseline         Baseline (sequential) JPEG with optimized Huffman tables
+  -copy none|comments|icc|all|all-except-icc  Metadata to keep (default all)
+  -rotate 90|180|270  Rotate clockwise
+  -flip horizontal|vertical  Mirror image
+  -transpose        Flip across the top-left to bottom-right diagonal
+  -transverse       Flip across the top-right to bottom-left diagonal
+  -perfect          Fail if the transform can't include partial edge blocks
+  -trim             Drop partial edge blocks that can't be transformed
+  -grayscale        Drop color, keeping luma
+  -crop WxH[+X+Y]   Crop to the region (of the transformed image)
+  -always           Replace files even if the result is larger
+  -dry-run          Only report how much would be saved
+  -threads N        Number of files processed in parallel (default: number of CPUs)
+  -outfile FILE     Write to FILE instead of replacing the only input file
+  -version          Print version and exit
+";
+
+struct Options {
+    transform: Transform,
+    always: bool,
+    dry_run: bool,
+    threads: usize,
+    inputs: Vec<PathBuf>,
+    output: Option<PathBuf>,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match parse_args(&args) {
+        Ok(Some(opts)) => cli::run_reporting_errors("mozjpeg-optimize", || run(&opts)),
+        Ok(None) => ExitCode::SUCCESS,
+        Err(e) => cli::usage_error("mozjpeg-optimize", USAGE, &*e),
+    }
+}
+
+fn parse_args(args: &[String]) -> CliResult<Option<Options>> {
+    let mut opts = Options {
+        transform: Transform::new(),
+        always: false,
+        dry_run: false,
+        threads: thread::available_parallelism().map_or(1, |n| n.get()),
+        inputs: Vec::new(),
+        output: None,
+    };
+    let mut operation = Operation::None;
+    let mut set_operation = |op| {
+        if std::mem::replace(&mut operation, op) != Operation::None {
+            return Err("only one of -rotate, -flip, -transpose and -transverse can be used");
+        }
+        Ok(())
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let Some(switch) = cli::switch_name(arg) else {
+            opts.inputs.push(arg.into());
+            continue;
+        };
+        let mut value = || args.next().ok_or_else(|| format!("-{switch} requires a value"));
+        match switch {
+            "progressive" => opts.transform.set_progressive(true),
+            "baseline" => opts.transform.set_progressive(false),
+            "copy" => opts.transform.set_copy_markers(match value()?.as_str() {
+                "none" => CopyMarkers::None,
+                "comments" => CopyMarkers::Comments,
+                "icc" => CopyMarkers::Icc,
+                "all" => CopyMarkers::All,
+                "all-except-icc" => CopyMarkers::AllExceptIcc,
+                other => return Err(format!("unknown -copy option {other}").into()),
+            }),
+            "rotate" => set_operation(match value()?.as_str() {
+                "90" => Operation::Rotate90,
+                "180" => Operation::Rotate180,
+                "270" => Operation::Rotate270,
+                other => return Err(format!("can't rotate by {other}").into()),
+            })?,
+            "flip" => set_operation(match value()?.as_str() {
+                "horizontal" | "h" => Operation::FlipHorizontal,
+                "vertical" | "v" => Operation::FlipVertical,
+                other => return Err(format!("can't flip {other}").into()),
+            })?,
+            "transpose" => set_operation(Operation::Transpose)?,
+            "transverse" => set_operation(Operation::Transverse)?,
+            "perfect" => opts.transform.set_perfect(true),
+            "trim" => opts.transform.set_trim(true),
+            "grayscale" | "greyscale" | "gray" => opts.transform.set_grayscale(true),
+            "crop" => {
+                let Region { x, y, width, height } = parse_region(value()?)?;
+                opts.transform.set_crop(x, y, width, height);
+            },
+            "always" => opts.always = true,
+            "dry-run" | "n" => opts.dry_run = true,
+            "threads" => opts.threads = value()?.parse::<usize>()?.max(1),
+            "outfile" | "o" => opts.output = Some(value()?.into()),
+            "version" => {
+                println!("mozjpeg-optimize {}", env!("CARGO_PKG_VERSION"));
+                return Ok(None);
+            },
+            "help" | "h" => {
+                print!("{USAGE}");
+                return Ok(None);
+            },
+            _ => return Err(format!("unknown switch -{switch}").into()),
+        }
+    }
+    opts.transform.set_operation(operation);
+    if opts.inputs.is_empty() {
+        return Err("no input files".into());
+    }
+    if opts.output.is_some() && (opts.inputs.len() != 1 || opts.inputs[0].is_dir()) {
+        return Err("-outfile can be used only with a single input file".into());
+    }
+    Ok(Some(opts))
+}
+
+fn run(opts: &Options) -> CliResult {
+    let mut files = Vec::new();
+    for input in &opts.inputs {
+        if input.is_dir() {
+            find_jpegs(input, &mut files)?;
+        } else {
+            files.push(input.clone());
+        }
+    }
+
+    let next_file = AtomicUsize::new(0);
+    let bytes_before = AtomicU64::new(0);
+    let bytes_after = AtomicU64::new(0);
+    let failed = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for _ in 0..opts.threads.min(files.len()) {
+            s.spawn(|| while let Some(path) = files.get(next_file.fetch_add(1, Ordering::Relaxed)) {
+                match optimize_file(opts, path) {
+                    Ok((before, after)) => {
+                        bytes_before.fetch_add(before, Ordering::Relaxed);
+                        bytes_after.fetch_add(after, Ordering::Relaxed);
+                    },
+                    Err(e) => {
+                        eprintln!("{}: {e}", path.display());
+                        failed.fetch_add(1, Ordering::Relaxed);
+                    },
+                }
+            });
+        }
+    });
+
+    let (before, after) = (bytes_before.into_inner(), bytes_after.into_inner());
+    if files.len() > 1 {
+        println!("{} files: {before}B -> {after}B, saved {}B", files.len(), before.saturating_sub(after));
+    }
+    let failed = failed.into_inner();
+    if failed > 0 {
+        return Err(format!("{failed} of {} files failed", files.len()).into());
+    }
+    Ok(())
+}
+
+fn is_jpeg_name(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str())
+        .is_some_and(|e| ["jpg", "jpeg", "jpe", "jfif"].iter().any(|j| e.eq_ignore_ascii_case(j)))
+}
+
+/// Symlinks aren't followed
+fn find_jpegs(dir: &Path, files: &mut Vec<PathBuf>) -> CliResult {
+    let mut entries = fs::read_dir(dir).map_err(|e| format!("can't read {}: {e}", dir.display()))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            find_jpegs(&entry.path(), files)?;
+        } else if file_type.is_file() && is_jpeg_name(&entry.path()) {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Returns sizes before and after
+fn optimize_file(opts: &Options, path: &Path) -> CliResult<(u64, u64)> {
+    let original = fs::read(path)?;
+    let metadata = fs::metadata(path)?;
+    let optimized = panic::catch_unwind(AssertUnwindSafe(|| opts.transform.transform_mem(&original)))
+        .map_err(|payload| cli::panic_message(&*payload))??;
+
+    let replace = opts.always || opts.transform.changes_image() || optimized.len() < original.len();
+    let result = if replace { &optimized } else { &original };
+    let (before, after) = (original.len() as u64, result.len() as u64);
+    let status = if replace { "" } else { " (not smaller, kept)" };
+    println!("{}: {before}B -> {}B{status}", path.display(), optimized.len());
+    if opts.dry_run {
+        return Ok((before, after));
+    }
+
+    let target = opts.output.as_deref().unwrap_or(path);
+    if !replace && target == path {
+        return Ok((before, after));
+    }
+    // Written next to the original, so it can be atomically renamed over it
+    let mut tmp_name = target.file_name().ok_or("invalid file name")?.to_os_string();
+    tmp_name.push(".mozjpeg-tmp");
+    let tmp_path = target.with_file_name(tmp_name);
+    let written = fs::write(&tmp_path, result)
+        .and_then(|()| fs::set_permissions(&tmp_path, metadata.permissions()))
+        .and_then(|()| filetime::set_file_times(&tmp_path, FileTime::from_last_access_time(&metadata), FileTime::from_last_modification_time(&metadata)))
+        .and_then(|()| fs::rename(&tmp_path, target));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(format!("can't write {}: {e}", target.display()).into());
+    }
+    Ok((before, after))
+}
@@ -0,0 +1,223 @@
+//! cjpeg-style encoder: compresses PPM/PGM/PAM or raw RGB to JPEG
+
+#[path = "common/cli.rs"]
+mod cli;
+#[path = "common/pnm.rs"]
+mod pnm;
+
+use cli::{parse_pair, CliResult};
+use mozjpeg::qtable::{QTable, ALL_TABLES};
+use mozjpeg::{ColorSpace, Compress, PixelDensity, PixelDensityUnit};
+use std::io::{BufReader, BufWriter, Write};
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+usage: mozjpeg [switches] [inputfile]
+Reads PPM/PGM/PAM (or raw RGB with -raw) from inputfile or stdin, and writes JPEG to -outfile or stdout.
+
+Switches:
+  -quality N        Compression quality 0-100 (default 75)
+  -quant-table N[,M]  Quantization tables for luma and chroma from the list below, scaled by quality
+  -qtables FILE     Quantization tables from a text file (up to 4), scaled by quality
+  -qslots N[,...]   Quantization table slot for each component (default 0,1,1, or 0 for all if -qtables has one table)
+  -sample HxV       Chroma subsampling, e.g. 2x2 for 4:2:0 or 1x1 for none (default 2x2)
+  -grayscale        Create a monochrome JPEG file
+  -progressive      Progressive JPEG (default)
+  -baseline         Baseline (sequential) JPEG
+  -fastcrush        Don't optimize scans of progressive files
+  -notrellis        Disable trellis quantization
+  -smooth N         Smooth dithered input (N=1..100 is strength)
+  -restart N        Insert restart markers every N MCU rows
+  -density X[xY][dpi|dpcm]  Pixel density stored in JFIF header
+  -icc FILE         Embed ICC profile from FILE
+  -raw WxH          Input is raw RGB pixels of the given size
+  -outfile FILE     Write to FILE instead of stdout
+  -version          Print version and exit
+";
+
+#[derive(Default)]
+struct Options {
+    quality: Option<f32>,
+    quant_tables: Option<(usize, usize)>,
+    qtables_file: Option<String>,
+    qslots: Vec<usize>,
+    sample: Option<(u8, u8)>,
+    grayscale: bool,
+    baseline: bool,
+    fastcrush: bool,
+    notrellis: bool,
+    smooth: u8,
+    restart_rows: u16,
+    density: Option<PixelDensity>,
+    icc: Option<String>,
+    raw_size: Option<(usize, usize)>,
+    input: Option<String>,
+    output: Option<String>,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match parse_args(&args) {
+        Ok(Some(opts)) => cli::run_reporting_errors("mozjpeg", move || run(opts)),
+        Ok(None) => ExitCode::SUCCESS,
+        Err(e) => cli::usage_error("mozjpeg", USAGE, &*e),
+    }
+}
+
+fn parse_args(args: &[String]) -> CliResult<Option<Options>> {
+    let mut opts = Options::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let Some(switch) = cli::switch_name(arg) else {
+            if opts.input.replace(arg.clone()).is_some() {
+                return Err("only one input file can be given".into());
+            }
+            continue;
+        };
+        let mut value = || args.next().ok_or_else(|| format!("-{switch} requires a value"));
+        match switch {
+            "quality" | "q" => {
+                let q: f32 = value()?.parse()?;
+                if !(0. ..=100.).contains(&q) {
+                    return Err("quality must be 0-100".into());
+                }
+                opts.quality = Some(q);
+            },
+            "quant-table" => {
+                let v = value()?;
+                let (luma, chroma) = v.split_once(',').unwrap_or((v, v));
+                let (luma, chroma) = (luma.parse()?, chroma.parse()?);
+                if luma >= ALL_TABLES.len() || chroma >= ALL_TABLES.len() {
+                    return Err(format!("quant-table must be 0-{}", ALL_TABLES.len() - 1).into());
+                }
+                opts.quant_tables = Some((luma, chroma));
+            },
+            "qtables" => opts.qtables_file = Some(value()?.clone()),
+            "qslots" => {
+                opts.qslots = value()?.split(',').map(str::parse).collect::<Result<_, _>>()?;
+                if opts.qslots.iter().any(|&s| s > 3) {
+                    return Err("qslots must be 0-3".into());
+                }
+            },
+            "sample" => {
+                let (h, v) = parse_pair(value()?, 'x')?;
+                if !(1..=4).contains(&h) || !(1..=4).contains(&v) {
+                    return Err("sampling factors must be 1-4".into());
+                }
+                opts.sample = Some((h, v));
+            },
+            "grayscale" | "greyscale" | "gray" => opts.grayscale = true,
+            "progressive" => opts.baseline = false,
+            "baseline" => opts.baseline = true,
+            "fastcrush" => opts.fastcrush = true,
+            "notrellis" => opts.notrellis = true,
+            "smooth" => {
+                opts.smooth = value()?.parse()?;
+                if opts.smooth > 100 {
+                    return Err("smooth must be 0-100".into());
+                }
+            },
+            "restart" => opts.restart_rows = value()?.parse()?,
+            "density" => opts.density = Some(parse_density(value()?)?),
+            "icc" => opts.icc = Some(value()?.clone()),
+            "raw" => opts.raw_size = Some(parse_pair(value()?, 'x')?),
+            "outfile" | "o" => opts.output = Some(value()?.clone()),
+            "version" => {
+                println!("mozjpeg {}", env!("CARGO_PKG_VERSION"));
+                return Ok(None);
+            },
+            "help" | "h" => {
+                print!("{USAGE}");
+                println!("\nQuantization tables:");
+                for (i, (name, _)) in ALL_TABLES.iter().enumerate() {
+                    println!("  {i:2} {name}");
+                }
+                return Ok(None);
+            },
+            _ => return Err(format!("unknown switch -{switch}").into()),
+        }
+    }
+    Ok(Some(opts))
+}
+
+fn parse_density(s: &str) -> CliResult<PixelDensity> {
+    let (numbers, unit) = if let Some(n) = s.strip_suffix("dpi") {
+        (n, PixelDensityUnit::Inches)
+    } else if let Some(n) = s.strip_suffix("dpcm") {
+        (n, PixelDensityUnit::Centimeters)
+    } else {
+        (s, PixelDensityUnit::PixelAspectRatio)
+    };
+    let (x, y) = if numbers.contains('x') { parse_pair(numbers, 'x')? } else { let n = numbers.parse()?; (n, n) };
+    Ok(PixelDensity { unit, x, y })
+}
+
+fn run(opts: Options) -> CliResult {
+    let mut input = cli::open_input(opts.input.as_deref())?;
+    let image = if let Some((width, height)) = opts.raw_size {
+        pnm::read_raw(&mut input, width, height, ColorSpace::JCS_RGB)?
+    } else {
+        pnm::read(&mut BufReader::new(input))?
+    };
+    let icc = opts.icc.as_deref().map(|path| std::fs::read(path).map_err(|e| format!("can't read {path}: {e}"))).transpose()?;
+
+    let mut comp = Compress::new(image.color_space);
+    if opts.grayscale {
+        comp.set_color_space(ColorSpace::JCS_GRAYSCALE);
+    }
+    let quality = opts.quality.unwrap_or(75.);
+    comp.set_quality(quality);
+    if let Some((luma, chroma)) = opts.quant_tables {
+        comp.set_luma_qtable(&ALL_TABLES[luma].1.scaled(quality, quality));
+        comp.set_chroma_qtable(&ALL_TABLES[chroma].1.scaled(quality, quality));
+    }
+    if let Some(path) = &opts.qtables_file {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("can't read {path}: {e}"))?;
+        let tables = QTable::parse_text(&text).map_err(|e| format!("{path}: {e}"))?;
+        if tables.is_empty() {
+            return Err(format!("{path} has no tables").into());
+        }
+        for (slot, table) in tables.iter().enumerate() {
+            comp.set_qtable(slot, &table.scaled(quality, quality));
+        }
+        if tables.len() == 1 && opts.qslots.is_empty() {
+            for c in 0..comp.components().len() {
+                comp.set_component_qtable(c, 0);
+            }
+        }
+    }
+    if let Some(&last) = opts.qslots.last() {
+        for c in 0..comp.components().len() {
+            comp.set_component_qtable(c, opts.qslots.get(c).copied().unwrap_or(last));
+        }
+    }
+    if let Some((h, v)) = opts.sample {
+        if comp.components().len() == 3 {
+            comp.set_chroma_sampling_pixel_sizes((h, v), (h, v));
+        }
+    }
+    if opts.notrellis {
+        comp.set_trellis_quantization(false);
+    }
+    comp.set_smoothing_factor(opts.smooth);
+    comp.set_restart_in_rows(opts.restart_rows);
+    if let Some(density) = opts.density {
+        comp.set_pixel_density(density);
+    }
+    if opts.baseline || opts.fastcrush {
+        comp.set_optimize_scans(false);
+    }
+    if !opts.baseline {
+        comp.set_progressive_mode();
+    }
+    comp.set_size(image.width, image.height);
+
+    let output = cli::create_output(opts.output.as_deref())?;
+    let mut started = comp.start_compress(BufWriter::new(output))?;
+    if let Some(icc) = &icc {
+        started.write_icc_profile(icc);
+    }
+    started.write_scanlines(&image.pixels)?;
+    started.finish()?.flush()?;
+    Ok(())
+}
@@ -14,6 +14,7 @@ use crate::ffi::JPEG_LIB_VERSION;
 use crate::ffi::J_BOOLEAN_PARAM;
 use crate::ffi::J_INT_PARAM;
 use crate::marker::Marker;
+use crate::huffman::{HuffmanClass, HuffmanTable};
 use crate::qtable::QTable;
 use crate::writedst::DestinationMgr;
 use arrayvec::ArrayVec;
@@ -35,12 +36,18 @@ pub const MAX_COMPONENTS: usize = 4;
 ///
 /// Wrapper for `jpeg_compress_struct`
 pub struct Compress {
-    cinfo: jpeg_compress_struct,
+    /// Boxed, because libjpeg keeps pointers to it (e.g. the progressive entropy encoder's `cinfo`),
+    /// and `Compress` is moved into `CompressStarted` after `jpeg_start_compress`
+    pub(crate) cinfo: Box<jpeg_compress_struct>,
 
-    /// It's `Box<ErrorMgr>`, but `cinfo` references `own_err`,
-    /// so I need talismans to ward off nasal demons haunting self-referential structs
+    /// It's `Box<ErrorMgr>`, but `cinfo` keeps a pointer to it, so it can't be a uniquely-owned `Box`.
+    /// Both are on the heap, so moving `Compress` doesn't invalidate libjpeg's pointers.
     own_err: *mut ErrorMgr,
-    _it_is_self_referential: PhantomPinned,
+    _pinned: PhantomPinned,
+    /// `false` after `suppress_tables(true)`
+    write_all_tables: bool,
+    /// JFXX marker from `set_thumbnail()`, written by `start_compress()`
+    pub(crate) thumbnail: Option<Vec<u8>>,
 }
 
 #[derive(Copy, Clone)]
@@ -51,8 +58,25 @@ pub enum ScanMode {
     Auto = 2,
 }
 
+/// Supplies pixels to compress row by row, so that the whole image doesn't have to be in memory at once.
+///
+/// See `Compress::compress_from`. Closures `FnMut(y, row)` implement it too.
+pub trait RowSource {
+    /// Fill `row` with pixels of row `y`. `row` is width × number of components bytes long.
+    ///
+    /// Rows are requested once each, in order from the top.
+    fn read_row(&mut self, y: usize, row: &mut [u8]) -> io::Result<()>;
+}
+
+impl<F: FnMut(usize, &mut [u8]) -> io::Result<()>> RowSource for F {
+    #[inline]
+    fn read_row(&mut self, y: usize, row: &mut [u8]) -> io::Result<()> {
+        self(y, row)
+    }
+}
+
 pub struct CompressStarted<W> {
-    compress: Compress,
+    pub(crate) compress: Compress,
     /// Safety: sensitive to drop order. Needs to be dropped after `Compress`
     dest_mgr: DestinationMgr<W>,
 }
@@ -81,14 +105,16 @@ impl Compress {
     pub fn new_err(err: Box<ErrorMgr>, color_space: ColorSpace) -> Self {
         unsafe {
             let mut newself = Self {
-                cinfo: mem::zeroed(),
+                cinfo: Box::new(mem::zeroed()),
                 own_err: Box::into_raw(err),
-                _it_is_self_referential: PhantomPinned,
+                _pinned: PhantomPinned,
+                write_all_tables: true,
+                thumbnail: None,
             };
             newself.cinfo.common.err = addr_of_mut!(*newself.own_err);
 
-            let s = mem::size_of_val(&newself.cinfo);
-            ffi::jpeg_CreateCompress(&mut newself.cinfo, JPEG_LIB_VERSION, s);
+            let s = mem::size_of_val(&*newself.cinfo);
+            ffi::jpeg_CreateCompress(&mut *newself.cinfo, JPEG_LIB_VERSION, s);
 
             newself.cinfo.in_color_space = color_space;
             newself.cinfo.input_components = color_space.num_components() as c_int;
@@ -98,6 +124,18 @@ impl Compress {
         }
     }
 
+    /// Resets all settings to defaults for input in this color space, like `Compress::new()`.
+    ///
+    /// Use it with `CompressStarted::finish_for_reuse()` to compress many images with one libjpeg object.
+    pub fn reset(&mut self, color_space: ColorSpace) {
+        unsafe {
+            ffi::jpeg_c_set_int_param(&mut self.cinfo, J_INT_PARAM::JINT_COMPRESS_PROFILE, ffi::JINT_COMPRESS_PROFILE_VALUE::JCP_MAX_COMPRESSION as c_int);
+            self.cinfo.in_color_space = color_space;
+            self.cinfo.input_components = color_space.num_components() as c_int;
+            ffi::jpeg_set_defaults(&mut self.cinfo);
+        }
+    }
+
     #[doc(hidden)]
     #[deprecated(note = "Give a Vec to start_compress instead")]
     pub fn set_mem_dest(&self) {
@@ -116,16 +154,51 @@ impl Compress {
         let expected_file_size = (self.cinfo.image_width as usize * self.cinfo.image_height as usize / 8 + 4095) & !4095;
         let write_buffer_capacity = expected_file_size.clamp(1 << 12, 1 << 16);
 
+        if self.thumbnail.is_some() && self.cinfo.write_JFIF_header == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "thumbnail requires the JFIF header"));
+        }
+
         let mut started = CompressStarted {
             compress: self,
             dest_mgr: DestinationMgr::new(writer, write_buffer_capacity),
         };
         unsafe {
             started.compress.cinfo.dest = started.dest_mgr.iface_c_ptr();
-            ffi::jpeg_start_compress(&mut started.compress.cinfo, boolean::from(true));
+            if started.compress.thumbnail.is_some() {
+                // JFXX was added in JFIF 1.02
+                started.compress.cinfo.JFIF_minor_version = started.compress.cinfo.JFIF_minor_version.max(2);
+            }
+            ffi::jpeg_start_compress(&mut started.compress.cinfo, boolean::from(started.compress.write_all_tables));
+        }
+        if let Some(jfxx) = started.compress.thumbnail.take() {
+            // must follow the JFIF header
+            started.write_marker(Marker::APP(0), &jfxx);
         }
         Ok(started)
     }
+
+    /// Like `start_compress`, but writes DCT coefficients from a decompressor instead of pixels (`jpeg_write_coefficients`).
+    ///
+    /// Safety: `coef_arrays` must be valid for the `jpeg_copy_critical_parameters` copied into this object, and outlive it.
+    pub(crate) unsafe fn start_compress_coefficients<W: io::Write>(self, writer: W, coef_arrays: *mut *mut ffi::jvirt_barray_control) -> CompressStarted<W> {
+        let mut started = CompressStarted {
+            compress: self,
+            dest_mgr: DestinationMgr::new(writer, 1 << 16),
+        };
+        started.compress.cinfo.dest = started.dest_mgr.iface_c_ptr();
+        ffi::jpeg_write_coefficients(&mut started.compress.cinfo, coef_arrays);
+        started
+    }
+
+    /// Compresses the whole image, pulling its rows from the `source` as they're needed.
+    ///
+    /// In progressive and `optimize_coding` modes libjpeg still keeps the whole image in memory
+    /// (as DCT coefficients) until the end, but not its pixels. If the source fails, compression is aborted.
+    pub fn compress_from<W: io::Write>(self, source: &mut dyn RowSource, writer: W) -> io::Result<W> {
+        let mut started = self.start_compress(writer)?;
+        started.write_rows_from(source)?;
+        started.finish()
+    }
 }
 
 impl<W> CompressStarted<W> {
@@ -149,6 +222,9 @@ impl<W> CompressStarted<W> {
 
     /// Add ICC profile to compressed file
     ///
+    /// The profile is split into `APP2` markers numbered from 1, as the ICC spec requires.
+    /// Versions up to 0.10.13 numbered them from 0, which `Decompress::icc_profile()` still accepts.
+    ///
     /// ## Panics
     ///
     /// It may panic, like all functions of this library.
@@ -169,7 +245,8 @@ impl<W> CompressStarted<W> {
         chunks.enumerate().for_each(move |(current_marker, chunk)| {
             buf.clear();
             buf.extend_from_slice(b"ICC_PROFILE\0");
-            buf.extend([current_marker as u8, num_chunks as u8]);
+            // sequence numbers start at 1
+            buf.extend([current_marker as u8 + 1, num_chunks as u8]);
             buf.extend_from_slice(chunk);
 
             self.write_marker(Marker::APP(2), &buf);
@@ -182,6 +259,40 @@ impl<W> CompressStarted<W> {
         self.compress.components()
     }
 
+    /// The writer, which has received all data libjpeg has flushed so far
+    #[cfg(any(feature = "futures", feature = "tokio"))]
+    pub(crate) fn writer_mut(&mut self) -> &mut W where W: io::Write {
+        self.dest_mgr.writer_mut()
+    }
+
+    /// Compresses up to `MAX_MCU_HEIGHT` rows, but lets libjpeg stop early when its output buffer is full.
+    /// Returns the number of rows consumed, after moving the output so far to the writer.
+    ///
+    /// libjpeg can stop early only in single-pass mode. In other modes it doesn't output anything until `finish()`.
+    #[cfg(any(feature = "futures", feature = "tokio"))]
+    pub(crate) fn write_scanlines_suspending(&mut self, image_src: &[u8]) -> io::Result<usize> where W: io::Write {
+        self.check_scanlines_input()?;
+        if !self.can_write_more_lines() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let row_pointers = image_src.chunks_exact(self.byte_width()).take(MAX_MCU_HEIGHT)
+            .map(|row| row.as_ptr())
+            .collect::<ArrayVec<_, MAX_MCU_HEIGHT>>();
+        self.dest_mgr.set_suspend(true);
+        let rows_written = unsafe {
+            ffi::jpeg_write_scanlines(&mut self.compress.cinfo, row_pointers.as_ptr(), row_pointers.len() as u32)
+        };
+        self.dest_mgr.set_suspend(false);
+        self.dest_mgr.write_buffered()?;
+        Ok(rows_written as usize)
+    }
+
+    /// Size of one row of input pixels
+    pub(crate) fn byte_width(&self) -> usize {
+        self.compress.cinfo.image_width as usize * self.compress.cinfo.input_components.max(0) as usize
+    }
+
     fn can_write_more_lines(&self) -> bool {
         self.compress.cinfo.next_scanline < self.compress.cinfo.image_height
     }
@@ -217,35 +328,89 @@ impl<W> CompressStarted<W> {
     ///
     /// It may panic, like all functions of this library.
     pub fn write_scanlines(&mut self, image_src: &[u8]) -> io::Result<()> {
-        if self.compress.cinfo.raw_data_in != 0 ||
-            self.compress.cinfo.input_components <= 0 ||
-            self.compress.cinfo.image_width == 0 {
-            return Err(io::ErrorKind::InvalidInput.into());
-        }
+        self.check_scanlines_input()?;
 
-        let byte_width = self.compress.cinfo.image_width as usize * self.compress.cinfo.input_components as usize;
+        let byte_width = self.byte_width();
         for rows in image_src.chunks(MAX_MCU_HEIGHT * byte_width) {
             let mut row_pointers = ArrayVec::<_, MAX_MCU_HEIGHT>::new();
             for row in rows.chunks_exact(byte_width) {
                 row_pointers.push(row.as_ptr());
             }
+            self.write_row_pointers(&row_pointers)?;
+        }
+        Ok(())
+    }
 
-            let mut rows_left = row_pointers.len() as u32;
-            let mut row_pointers = row_pointers.as_ptr();
-            while rows_left > 0 {
-                unsafe {
-                    let rows_written = ffi::jpeg_write_scanlines(
-                        &mut self.compress.cinfo,
-                        row_pointers,
-                        rows_left,
-                    );
-                    debug_assert!(rows_left >= rows_written);
-                    if rows_written == 0 {
-                        return Err(io::ErrorKind::UnexpectedEof.into());
-                    }
-                    rows_left -= rows_written;
-                    row_pointers = row_pointers.add(rows_written as usize);
+    /// Writes rows from an iterator. Each row must be exactly width × number of components bytes long.
+    ///
+    /// Can be called multiple times, until all rows of the image have been written.
+    pub fn write_rows<I>(&mut self, rows: I) -> io::Result<()> where I: IntoIterator, I::Item: AsRef<[u8]> {
+        self.check_scanlines_input()?;
+
+        let byte_width = self.byte_width();
+        let mut rows = rows.into_iter();
+        loop {
+            // rows are kept alive until libjpeg copies them
+            let batch = rows.by_ref().take(MAX_MCU_HEIGHT).collect::<ArrayVec<_, MAX_MCU_HEIGHT>>();
+            if batch.is_empty() {
+                return Ok(());
+            }
+            let mut row_pointers = ArrayVec::<_, MAX_MCU_HEIGHT>::new();
+            for row in &batch {
+                let row = row.as_ref();
+                if row.len() != byte_width {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("row must be {byte_width} bytes long, got {}", row.len())));
+                }
+                row_pointers.push(row.as_ptr());
+            }
+            self.write_row_pointers(&row_pointers)?;
+        }
+    }
+
+    /// Writes all the remaining rows of the image, requesting them from the `source`.
+    pub fn write_rows_from(&mut self, source: &mut dyn RowSource) -> io::Result<()> {
+        self.check_scanlines_input()?;
+
+        let byte_width = self.byte_width();
+        let height = self.compress.cinfo.image_height as usize;
+        let mut y = self.compress.cinfo.next_scanline as usize;
+        let mut buffer = vec![0; byte_width * MAX_MCU_HEIGHT.min(height.saturating_sub(y))];
+        while y < height {
+            let rows = &mut buffer[..byte_width * MAX_MCU_HEIGHT.min(height - y)];
+            for row in rows.chunks_exact_mut(byte_width) {
+                source.read_row(y, row)?;
+                y += 1;
+            }
+            self.write_scanlines(rows)?;
+        }
+        Ok(())
+    }
+
+    fn check_scanlines_input(&self) -> io::Result<()> {
+        if self.compress.cinfo.raw_data_in != 0 ||
+            self.compress.cinfo.input_components <= 0 ||
+            self.compress.cinfo.image_width == 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        Ok(())
+    }
+
+    fn write_row_pointers(&mut self, row_pointers: &[*const u8]) -> io::Result<()> {
+        let mut rows_left = row_pointers.len() as u32;
+        let mut row_pointers = row_pointers.as_ptr();
+        while rows_left > 0 {
+            unsafe {
+                let rows_written = ffi::jpeg_write_scanlines(
+                    &mut self.compress.cinfo,
+                    row_pointers,
+                    rows_left,
+                );
+                debug_assert!(rows_left >= rows_written);
+                if rows_written == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
                 }
+                rows_left -= rows_written;
+                row_pointers = row_pointers.add(rows_written as usize);
             }
         }
         Ok(())
@@ -325,6 +490,11 @@ impl Compress {
     pub fn set_color_space(&mut self, color_space: ColorSpace) {
         unsafe {
             ffi::jpeg_set_colorspace(&mut self.cinfo, color_space);
+            // The progressive scan script depends on the number of components,
+            // and a stale one makes libjpeg read out of bounds
+            if !self.cinfo.scan_info.is_null() {
+                ffi::jpeg_simple_progression(&mut self.cinfo);
+            }
         }
     }
 
@@ -372,6 +542,28 @@ impl Compress {
         self.cinfo.optimize_coding = boolean::from(opt);
     }
 
+    /// Insert restart markers every `rows` rows of MCUs (0 = none).
+    /// They allow decoders to resynchronize after data corruption, and to decode parts of the file independently.
+    pub fn set_restart_in_rows(&mut self, rows: u16) {
+        self.cinfo.restart_in_rows = c_int::from(rows);
+        self.cinfo.restart_interval = 0;
+    }
+
+    /// Insert restart markers every `mcus` MCU blocks (0 = none). Overrides `set_restart_in_rows`.
+    pub fn set_restart_interval(&mut self, mcus: u16) {
+        self.cinfo.restart_interval = c_uint::from(mcus);
+        self.cinfo.restart_in_rows = 0;
+    }
+
+    /// MozJPEG's trellis quantization makes files smaller, but it's slow,
+    /// and it always optimizes Huffman tables, regardless of `set_optimize_coding`.
+    pub fn set_trellis_quantization(&mut self, opt: bool) {
+        unsafe {
+            ffi::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT, boolean::from(opt));
+            ffi::jpeg_c_set_bool_param(&mut self.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT_DC, boolean::from(opt));
+        }
+    }
+
     /// Specifies whether multiple scans should be considered during trellis
     /// quantization.
     pub fn set_use_scans_in_trellis(&mut self, opt: bool) {
@@ -431,6 +623,99 @@ impl Compress {
         }
     }
 
+    /// Installs a quantization table in one of four slots (0-3), which components can use via `set_component_qtable()`.
+    ///
+    /// Slots 0 and 1 are also set by `set_quality()`, `set_luma_qtable()` and `set_chroma_qtable()`.
+    pub fn set_qtable(&mut self, slot: usize, qtable: &QTable) {
+        assert!(slot < 4, "there are only 4 quantization table slots");
+        unsafe {
+            ffi::jpeg_add_quant_table(&mut self.cinfo, slot as c_int, qtable.as_ptr(), 100, 1);
+        }
+    }
+
+    /// Makes the component (e.g. 2 for Cr, or 3 for K in CMYK) use the table from the given slot (0-3).
+    ///
+    /// By default the first component uses slot 0, and all others use slot 1.
+    /// Changing the color space resets this, so it should be called after `set_color_space()`.
+    pub fn set_component_qtable(&mut self, component: usize, slot: usize) {
+        assert!(slot < 4, "there are only 4 quantization table slots");
+        self.components_mut()[component].quant_tbl_no = slot as c_int;
+    }
+
+    /// Uses the given Huffman table for DC coefficients of components assigned to the slot (0-3).
+    ///
+    /// The table must have codes for all symbols that the image needs. Fixed tables work only in sequential mode
+    /// without trellis quantization (otherwise libjpeg computes its own), so this disables progressive mode,
+    /// trellis quantization and `optimize_coding`.
+    pub fn set_dc_huffman_table(&mut self, slot: usize, table: &HuffmanTable) {
+        self.set_huffman_table(HuffmanClass::Dc, slot, table);
+    }
+
+    /// Uses the given Huffman table for AC coefficients of components assigned to the slot (0-3).
+    ///
+    /// See `set_dc_huffman_table()`.
+    pub fn set_ac_huffman_table(&mut self, slot: usize, table: &HuffmanTable) {
+        self.set_huffman_table(HuffmanClass::Ac, slot, table);
+    }
+
+    fn set_huffman_table(&mut self, class: HuffmanClass, slot: usize, table: &HuffmanTable) {
+        assert!(slot < 4, "there are only 4 Huffman table slots");
+        let tbl_ptr = match class {
+            HuffmanClass::Dc => &mut self.cinfo.dc_huff_tbl_ptrs[slot],
+            HuffmanClass::Ac => &mut self.cinfo.ac_huff_tbl_ptrs[slot],
+        };
+        unsafe {
+            if tbl_ptr.is_null() {
+                *tbl_ptr = ffi::jpeg_alloc_huff_table(&mut self.cinfo.common);
+            }
+            table.copy_to_c(&mut **tbl_ptr);
+        }
+        self.set_optimize_coding(false);
+        self.set_trellis_quantization(false);
+        self.set_optimize_scans(false);
+    }
+
+    /// Makes the component use DC and AC Huffman tables from the given slots (0-3).
+    ///
+    /// By default the first component uses slot 0, and all others use slot 1.
+    /// Changing the color space resets this, so it should be called after `set_color_space()`.
+    pub fn set_component_huffman_tables(&mut self, component: usize, dc_slot: usize, ac_slot: usize) {
+        assert!(dc_slot < 4 && ac_slot < 4, "there are only 4 Huffman table slots");
+        let c = &mut self.components_mut()[component];
+        c.dc_tbl_no = dc_slot as c_int;
+        c.ac_tbl_no = ac_slot as c_int;
+    }
+
+    /// Writes a tables-only stream (`jpeg_write_tables`): SOI, the current quantization and Huffman tables, and EOI.
+    ///
+    /// The tables are marked as written, so with `suppress_tables(true)` images compressed afterwards
+    /// will be abbreviated datastreams without them. Decode such images with `ReusableDecompress::read_tables()`.
+    ///
+    /// ## Panics
+    ///
+    /// It may panic, like all functions of this library.
+    pub fn write_tables<W: io::Write>(&mut self, writer: W) -> io::Result<W> {
+        let mut dest_mgr = DestinationMgr::new(writer, 1 << 12);
+        unsafe {
+            self.cinfo.dest = dest_mgr.iface_c_ptr();
+            ffi::jpeg_write_tables(&mut self.cinfo);
+        }
+        self.cinfo.dest = ptr::null_mut();
+        Ok(dest_mgr.into_inner())
+    }
+
+    /// Marks all current tables as already written (`jpeg_suppress_tables`), or as not written when `false`.
+    ///
+    /// While suppressed, `start_compress` writes only tables that have changed since, so the output is an abbreviated datastream
+    /// that needs the tables from `write_tables()` to be decoded. Call it after setting quality and tables, since these define new tables.
+    /// Optimized Huffman tables are always written, because they're different for every image.
+    pub fn suppress_tables(&mut self, suppress: bool) {
+        unsafe {
+            ffi::jpeg_suppress_tables(&mut self.cinfo, boolean::from(suppress));
+        }
+        self.write_all_tables = !suppress;
+    }
+
     /// Sets chroma subsampling, separately for Cb and Cr channels.
     /// Instead of setting samples per pixel, like in `cinfo`'s `x_samp_factor`,
     /// it sets size of chroma "pixels" per luma pixel.
@@ -467,6 +752,17 @@ impl<W: io::Write> CompressStarted<W> {
         Ok(self.dest_mgr.into_inner())
     }
 
+    /// Finalize compression, and return the writer and the `Compress` object for compressing another image.
+    ///
+    /// Settings of the previous image are kept. Image size needs to be set again, or use `Compress::reset()`.
+    pub fn finish_for_reuse(mut self) -> io::Result<(Compress, W)> {
+        unsafe {
+            ffi::jpeg_finish_compress(&mut self.compress.cinfo);
+        }
+        self.compress.cinfo.dest = ptr::null_mut();
+        Ok((self.compress, self.dest_mgr.into_inner()))
+    }
+
     #[doc(hidden)]
     #[deprecated(note = "use finish(); it now returns a writer given to start_compress()")]
     pub fn finish_compress(self) -> io::Result<W> {
@@ -546,6 +842,19 @@ fn write_mem() {
     cinfo.finish().unwrap();
 }
 
+#[test]
+fn color_space_updates_scan_script() {
+    let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+    cinfo.set_color_space(ColorSpace::JCS_GRAYSCALE);
+    let gray = Compress::new(ColorSpace::JCS_GRAYSCALE);
+    assert_eq!(gray.cinfo.num_scans, cinfo.cinfo.num_scans);
+    let scans = |c: &Compress| unsafe {
+        std::slice::from_raw_parts(c.cinfo.scan_info, c.cinfo.num_scans as usize).iter()
+            .map(|s| (s.comps_in_scan, s.component_index[0], s.Ss, s.Se, s.Ah, s.Al)).collect::<Vec<_>>()
+    };
+    assert_eq!(scans(&gray), scans(&cinfo));
+}
+
 #[test]
 fn convert_colorspace() {
     let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
@@ -563,3 +872,96 @@ fn convert_colorspace() {
     let res = cinfo.finish().unwrap();
     assert!(!res.is_empty());
 }
+
+#[test]
+fn progressive_after_move() {
+    // The progressive encoder keeps the `cinfo` pointer given to `jpeg_start_compress`,
+    // and uses it while writing scanlines, e.g. at restart markers
+    let mut cinfo = Compress::new(ColorSpace::JCS_GRAYSCALE);
+    cinfo.set_size(256, 128);
+    cinfo.set_progressive_mode();
+    cinfo.set_optimize_coding(false);
+    unsafe {
+        ffi::jpeg_c_set_bool_param(&mut cinfo.cinfo, J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT, boolean::from(false));
+    }
+    cinfo.cinfo.restart_interval = 3;
+    let cinfo_ptr: *const jpeg_compress_struct = &*cinfo.cinfo;
+    let mut started = vec![cinfo.start_compress(Vec::new()).unwrap()];
+    assert!(ptr::eq(cinfo_ptr, &*started[0].compress.cinfo));
+
+    let pixels: Vec<u8> = (0..256 * 128u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+    started[0].write_scanlines(&pixels).unwrap();
+    let jpeg = started.pop().unwrap().finish().unwrap();
+
+    let mut dec = crate::Decompress::new_mem(&jpeg).unwrap().grayscale().unwrap();
+    let decoded: Vec<u8> = dec.read_scanlines().unwrap();
+    dec.finish().unwrap();
+    let diff = pixels.iter().zip(&decoded).map(|(&a, &b)| u64::from(a.abs_diff(b))).sum::<u64>();
+    assert!(diff / (256 * 128) < 40);
+}
+
+#[test]
+fn compress_from_rows() {
+    let pixel = |x: usize, y: usize| [(x * 3) as u8, (y * 5) as u8, (x ^ y) as u8];
+    let (width, height) = (45, 37);
+    let image: Vec<u8> = (0..height).flat_map(|y| (0..width).flat_map(move |x| pixel(x, y))).collect();
+
+    let new_compress = || {
+        let mut cinfo = Compress::new(ColorSpace::JCS_RGB);
+        cinfo.set_size(width, height);
+        cinfo.set_progressive_mode();
+        cinfo.set_optimize_coding(true);
+        cinfo
+    };
+
+    let mut started = new_compress().start_compress(Vec::new()).unwrap();
+    started.write_scanlines(&image).unwrap();
+    let expected = started.finish().unwrap();
+
+    let mut next_y = 0;
+    let from_source = new_compress().compress_from(&mut |y: usize, row: &mut [u8]| {
+        assert_eq!(next_y, y);
+        next_y += 1;
+        row.copy_from_slice(&image[y * width * 3..][..width * 3]);
+        Ok(())
+    }, Vec::new()).unwrap();
+    assert_eq!(height, next_y);
+    assert_eq!(expected, from_source);
+
+    let mut started = new_compress().start_compress(Vec::new()).unwrap();
+    let mut rows = image.chunks(width * 3).map(|row| row.to_vec());
+    started.write_rows(rows.by_ref().take(20)).unwrap();
+    started.write_rows(rows).unwrap();
+    assert_eq!(expected, started.finish().unwrap());
+
+    let mut started = new_compress().start_compress(Vec::new()).unwrap();
+    assert!(started.write_rows([&image[..10]]).is_err());
+
+    let failing = new_compress().compress_from(&mut |y: usize, _: &mut [u8]| {
+        if y < 20 { Ok(()) } else { Err(io::ErrorKind::BrokenPipe.into()) }
+    }, Vec::new());
+    assert_eq!(io::ErrorKind::BrokenPipe, failing.unwrap_err().kind());
+}
+
+#[test]
+fn per_component_qtables() {
+    use crate::qtable::QTable;
+
+    let mut cinfo = Compress::new(ColorSpace::JCS_CMYK);
+    cinfo.set_size(16, 16);
+    let tables: Vec<_> = (1..=4).map(|n| QTable::from_natural([n * 3; 64]).unwrap()).collect();
+    for (slot, table) in tables.iter().enumerate() {
+        cinfo.set_qtable(slot, table);
+        cinfo.set_component_qtable(3 - slot, slot);
+    }
+    let mut started = cinfo.start_compress(Vec::new()).unwrap();
+    started.write_scanlines(&[77; 16 * 16 * 4]).unwrap();
+    let data = started.finish().unwrap();
+
+    let info = crate::Decompress::new_mem(&data).unwrap().info();
+    let slots: Vec<_> = info.components.iter().map(|c| c.qtable_slot).collect();
+    assert_eq!([3, 2, 1, 0], slots[..]);
+    for (slot, table) in tables.iter().enumerate() {
+        assert_eq!(Some(table), info.qtables[slot].as_ref());
+    }
+}
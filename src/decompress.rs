@@ -11,7 +11,12 @@ use crate::ffi::jpeg_decompress_struct;
 use crate::ffi::DCTSIZE;
 use crate::ffi::JPEG_LIB_VERSION;
 use crate::ffi::J_COLOR_SPACE as COLOR_SPACE;
+use crate::huffman::{HuffmanClass, HuffmanTable};
+use crate::incremental::IncrementalDecompress;
 use crate::marker::Marker;
+use crate::orientation::Orientation;
+use crate::parse;
+use crate::qtable::QualityEstimate;
 use crate::readsrc::SourceMgr;
 use libc::fdopen;
 use std::cmp::min;
@@ -19,6 +24,7 @@ use std::fs::File;
 use std::io;
 use std::io::BufRead;
 use std::io::BufReader;
+use std::io::Read;
 use std::marker::PhantomData;
 use std::mem;
 use std::mem::MaybeUninit;
@@ -60,6 +66,43 @@ pub enum DctMethod {
     Float,
 }
 
+impl From<DctMethod> for ffi::J_DCT_METHOD {
+    fn from(method: DctMethod) -> Self {
+        match method {
+            DctMethod::IntegerSlow => Self::JDCT_ISLOW,
+            DctMethod::IntegerFast => Self::JDCT_IFAST,
+            DctMethod::Float => Self::JDCT_FLOAT,
+        }
+    }
+}
+
+/// Result of `DecompressStarted::consume_input()`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InputStatus {
+    /// Ran out of data. Happens only with non-blocking sources.
+    Suspended,
+    /// Reached start of a new scan
+    ReachedSos,
+    /// Reached end of the image, and all scans are complete
+    ReachedEoi,
+    /// Completed one row of MCU blocks
+    RowCompleted,
+    /// Completed the last row of MCU blocks of a scan
+    ScanCompleted,
+}
+
+impl InputStatus {
+    pub(crate) fn from_c(status: c_int) -> Self {
+        match status {
+            1 => Self::ReachedSos,
+            2 => Self::ReachedEoi,
+            3 => Self::RowCompleted,
+            4 => Self::ScanCompleted,
+            _ => Self::Suspended,
+        }
+    }
+}
+
 /// Use `Decompress` static methods instead of creating this directly
 pub struct DecompressBuilder<'markers> {
     save_markers: &'markers [Marker],
@@ -117,6 +160,18 @@ impl<'markers> DecompressBuilder<'markers> {
     pub fn from_reader<R: BufRead>(self, reader: R) -> io::Result<Decompress<R>> {
         Decompress::from_builder_and_reader(self, reader)
     }
+
+    /// Creates a decoder that can decode multiple files, one after another. See `ReusableDecompress`.
+    #[must_use]
+    pub fn reusable(self) -> ReusableDecompress {
+        ReusableDecompress::new(self.err_mgr.unwrap_or_else(unwinding_error_mgr), self.save_markers)
+    }
+
+    /// Creates a non-blocking decoder that is given data in chunks, as it arrives.
+    #[must_use]
+    pub fn incremental(self) -> IncrementalDecompress {
+        IncrementalDecompress::from_builder(self.err_mgr.unwrap_or_else(unwinding_error_mgr), self.save_markers)
+    }
 }
 
 impl<'markers> Default for DecompressBuilder<'markers> {
@@ -137,9 +192,147 @@ impl<'markers> Default for DecompressBuilder<'markers> {
 /// # Ok(()) }
 /// ```
 pub struct Decompress<R> {
-    cinfo: jpeg_decompress_struct,
+    pub(crate) cinfo: jpeg_decompress_struct,
     err_mgr: Box<ErrorMgr>,
     src_mgr: Option<Box<SourceMgr<R>>>,
+    /// Set by `apply_exif_orientation()`
+    pub(crate) orientation: Orientation,
+}
+
+/// Decoder that is ready to read the next file, keeping libjpeg's state from the previous ones
+///
+/// Reusing the decoder saves some allocations when decoding many files. Huffman and quantization tables
+/// defined by previous files are kept, which allows decoding abbreviated datastreams that omit them.
+/// Markers are saved as configured in the builder.
+///
+/// Create it with `DecompressBuilder::reusable()`, and get it back with `DecompressStarted::finish_for_reuse()`.
+pub struct ReusableDecompress {
+    cinfo: jpeg_decompress_struct,
+    err_mgr: Box<ErrorMgr>,
+}
+
+impl ReusableDecompress {
+    fn new(err_mgr: Box<ErrorMgr>, save_markers: &[Marker]) -> Self {
+        unsafe {
+            let mut newself = Self {
+                cinfo: mem::zeroed(),
+                err_mgr,
+            };
+            newself.cinfo.common.err = addr_of_mut!(*newself.err_mgr);
+            ffi::jpeg_create_decompress(&mut newself.cinfo);
+            for &marker in save_markers {
+                ffi::jpeg_save_markers(&mut newself.cinfo, marker.into(), 0xFFFF);
+            }
+            newself
+        }
+    }
+
+    /// Reads the header of the next file. See `DecompressBuilder::from_reader`.
+    pub fn from_reader<R: BufRead>(self, reader: R) -> io::Result<Decompress<R>> {
+        let src_mgr = Box::new(SourceMgr::new(reader)?);
+        let this = mem::ManuallyDrop::new(self);
+        // Safety: fields are moved out of a struct that won't be dropped
+        let mut dec = unsafe {
+            Decompress {
+                cinfo: ptr::read(&this.cinfo),
+                err_mgr: ptr::read(&this.err_mgr),
+                src_mgr: Some(src_mgr),
+                orientation: Orientation::Normal,
+            }
+        };
+        dec.cinfo.src = unsafe { dec.src_mgr.as_mut().unwrap().iface_c_ptr() };
+        dec.read_header()?;
+        Ok(dec)
+    }
+
+    /// Reads the header of the next file from a `Vec` or a slice
+    #[inline]
+    pub fn from_mem(self, mem: &[u8]) -> io::Result<Decompress<&[u8]>> {
+        self.from_reader(mem)
+    }
+
+    /// Loads quantization and Huffman tables from a tables-only stream (such as one from `Compress::write_tables()`),
+    /// to decode abbreviated images that don't contain them.
+    ///
+    /// Returns the reader positioned after the stream's EOI. Fails if the stream contains an image.
+    ///
+    /// ## Panics
+    ///
+    /// It may panic, like all functions of this library.
+    pub fn read_tables<R: BufRead>(&mut self, reader: R) -> io::Result<R> {
+        let mut src_mgr = Box::new(SourceMgr::new(reader)?);
+        self.cinfo.src = unsafe { src_mgr.iface_c_ptr() };
+        // libjpeg resets itself after a tables-only stream, but not after an image header
+        const JPEG_HEADER_TABLES_ONLY: c_int = 2;
+        let tables_only = JPEG_HEADER_TABLES_ONLY == unsafe { ffi::jpeg_read_header(&mut self.cinfo, 0) };
+        if !tables_only {
+            unsafe { ffi::jpeg_abort_decompress(&mut self.cinfo) };
+        }
+        self.cinfo.src = ptr::null_mut();
+        if !tables_only {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "expected a tables-only JPEG stream"));
+        }
+        Ok(src_mgr.into_inner())
+    }
+
+    /// Like `read_tables`, from a slice
+    #[inline]
+    pub fn read_tables_from_mem(&mut self, mem: &[u8]) -> io::Result<()> {
+        self.read_tables(mem).map(drop)
+    }
+}
+
+impl Drop for ReusableDecompress {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::jpeg_destroy_decompress(&mut self.cinfo);
+        }
+    }
+}
+
+/// Reader of an image in a `DecompressSequence`: the SOI marker that has been found, followed by the rest of the stream
+pub type SequenceReader<'a, R> = io::Chain<&'static [u8], &'a mut R>;
+
+/// Decodes multiple JPEG images from one stream, one after another.
+///
+/// It's for concatenated JPEG files, or MPF files with secondary images appended after the primary one.
+/// Any bytes between images (such as padding) are skipped.
+///
+/// Each `Decompress` reads from the stream, so it must be finished (e.g. with `finish()`) before the next one is read,
+/// to leave the stream at the end of the image. Otherwise the search for the next image starts where decoding stopped.
+pub struct DecompressSequence<'markers, R> {
+    reader: R,
+    save_markers: &'markers [Marker],
+}
+
+impl<'markers, R: BufRead> DecompressSequence<'markers, R> {
+    #[must_use]
+    pub fn new(reader: R) -> Self {
+        Self::with_markers(reader, NO_MARKERS)
+    }
+
+    /// Every image's `Decompress` saves these markers. See `Decompress::with_markers`.
+    #[must_use]
+    pub fn with_markers(reader: R, save_markers: &'markers [Marker]) -> Self {
+        Self { reader, save_markers }
+    }
+
+    /// Reads the header of the next image. Returns `None` if there are no more images in the stream.
+    ///
+    /// This is a lending iterator: the `Decompress` borrows the stream, so use it with `while let Some(dec) = seq.next_image()?`.
+    pub fn next_image(&mut self) -> io::Result<Option<Decompress<SequenceReader<'_, R>>>> {
+        if !parse::skip_to_soi(&mut self.reader)? {
+            return Ok(None);
+        }
+        let soi: &'static [u8] = &[0xFF, parse::SOI];
+        DecompressBuilder::new().with_markers(self.save_markers).from_reader(soi.chain(&mut self.reader)).map(Some)
+    }
+
+    /// Returns the stream, positioned after the last image that has been finished
+    #[must_use]
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
 }
 
 /// Marker type and data slice returned by `MarkerIter`
@@ -150,8 +343,8 @@ pub struct MarkerData<'a> {
 
 /// See `Decompress.markers()`
 pub struct MarkerIter<'a> {
-    marker_list: *mut ffi::jpeg_marker_struct,
-    _references: ::std::marker::PhantomData<MarkerData<'a>>,
+    pub(crate) marker_list: *mut ffi::jpeg_marker_struct,
+    pub(crate) _references: ::std::marker::PhantomData<MarkerData<'a>>,
 }
 
 impl<'a> Iterator for MarkerIter<'a> {
@@ -235,26 +428,31 @@ impl<R> Decompress<R> {
     }
 
     fn from_builder_and_reader(builder: DecompressBuilder<'_>, reader: R) -> io::Result<Self> where R: BufRead {
-        let src_mgr = Box::new(SourceMgr::new(reader)?);
-        let err_mgr = builder.err_mgr.unwrap_or_else(unwinding_error_mgr);
+        builder.reusable().from_reader(reader)
+    }
+
+    /// Libjpeg's object without the source
+    fn into_reusable(self) -> (ReusableDecompress, Option<Box<SourceMgr<R>>>) {
+        let mut this = mem::ManuallyDrop::new(self);
+        this.cinfo.src = ptr::null_mut();
+        // Safety: fields are moved out of a struct that won't be dropped
         unsafe {
-            let mut newself = Decompress {
-                cinfo: mem::zeroed(),
-                src_mgr: Some(src_mgr),
-                err_mgr,
-            };
-            let src_ptr = newself.src_mgr.as_mut().unwrap().iface_c_ptr();
-            newself.cinfo.common.err = addr_of_mut!(*newself.err_mgr);
-            ffi::jpeg_create_decompress(&mut newself.cinfo);
-            newself.cinfo.src = src_ptr;
-            for &marker in builder.save_markers {
-                newself.save_marker(marker);
-            }
-            newself.read_header()?;
-            Ok(newself)
+            (ReusableDecompress {
+                cinfo: ptr::read(&this.cinfo),
+                err_mgr: ptr::read(&this.err_mgr),
+            }, ptr::read(&this.src_mgr))
         }
     }
 
+    /// Stops decoding this file without reading the rest of it,
+    /// and returns the decoder for decoding another one
+    pub fn abort_for_reuse(mut self) -> ReusableDecompress {
+        unsafe {
+            ffi::jpeg_abort_decompress(&mut self.cinfo);
+        }
+        self.into_reusable().0
+    }
+
     #[inline]
     #[must_use]
     pub fn components(&self) -> &[CompInfo] {
@@ -328,13 +526,70 @@ impl<R> Decompress<R> {
         }
     }
 
-    #[inline]
-    fn save_marker(&mut self, marker: Marker) {
+    /// Guesses the quality setting the file has been encoded with, from quantization tables of its first two components
+    #[must_use]
+    pub fn estimate_quality(&self) -> Option<QualityEstimate> {
+        self.info().estimate_quality()
+    }
+
+    /// Huffman table for DC coefficients defined in the slot (0-3).
+    ///
+    /// Only tables defined before the first scan are known at this point. Progressive files usually define more tables
+    /// between scans, which `JpegInfo::from_mem()` can list. Files without tables (e.g. Motion-JPEG) use standard tables.
+    #[must_use]
+    pub fn dc_huffman_table(&self, slot: usize) -> Option<HuffmanTable> {
+        self.huffman_table_ptrs(HuffmanClass::Dc).get(slot)
+            .and_then(|&tbl| unsafe { tbl.as_ref() }).map(HuffmanTable::from_c)
+    }
+
+    /// Huffman table for AC coefficients defined in the slot (0-3). See `dc_huffman_table()`.
+    #[must_use]
+    pub fn ac_huffman_table(&self, slot: usize) -> Option<HuffmanTable> {
+        self.huffman_table_ptrs(HuffmanClass::Ac).get(slot)
+            .and_then(|&tbl| unsafe { tbl.as_ref() }).map(HuffmanTable::from_c)
+    }
+
+    fn huffman_table_ptrs(&self, class: HuffmanClass) -> &[*mut ffi::JHUFF_TBL; 4] {
+        // mozjpeg-sys doesn't make these fields public. In the C struct they directly follow quant_tbl_ptrs
+        // (dc_huff_tbl_ptrs, then ac_huff_tbl_ptrs), and all are arrays of 4 pointers, so there's no padding.
+        let index = match class {
+            HuffmanClass::Dc => 1,
+            HuffmanClass::Ac => 2,
+        };
+        let base = ptr::addr_of!(self.cinfo).cast::<u8>();
         unsafe {
-            ffi::jpeg_save_markers(&mut self.cinfo, marker.into(), 0xFFFF);
+            let quant_offset = ptr::addr_of!(self.cinfo.quant_tbl_ptrs).cast::<u8>().offset_from(base) as usize;
+            &*base.add(quant_offset + index * mem::size_of::<[*mut ffi::JHUFF_TBL; 4]>()).cast::<[*mut ffi::JHUFF_TBL; 4]>()
         }
     }
 
+    /// ICC profile reassembled from APP2 markers, if there are any and all of its chunks are present.
+    ///
+    /// Requires `APP(2)` markers to be enabled via `with_markers()`
+    #[must_use]
+    pub fn icc_profile(&self) -> Option<Vec<u8>> {
+        let mut chunks: Vec<_> = self.markers()
+            .filter(|m| m.marker == Marker::APP(2) && m.data.len() > 14 && m.data.starts_with(b"ICC_PROFILE\0"))
+            .map(|m| (m.data[12], m.data[13], &m.data[14..]))
+            .collect();
+        chunks.sort_by_key(|&(seq, ..)| seq);
+        // older versions of this crate numbered chunks from 0
+        let first = chunks.first().map_or(1, |&(seq, ..)| seq.min(1));
+        let complete = !chunks.is_empty() && chunks.iter().enumerate()
+            .all(|(i, &(seq, num, _))| usize::from(seq - first) == i && usize::from(num) == chunks.len());
+        complete.then(|| chunks.into_iter().flat_map(|(.., data)| data).copied().collect())
+    }
+
+    /// width,height of the image as it will be output, after scaling set with `scale()`
+    #[must_use]
+    pub fn output_size(&mut self) -> (usize, usize) {
+        unsafe {
+            ffi::jpeg_calc_output_dimensions(&mut self.cinfo);
+        }
+        let size = (self.cinfo.output_width as usize, self.cinfo.output_height as usize);
+        if self.orientation.swaps_dimensions() { (size.1, size.0) } else { size }
+    }
+
     /// width,height
     #[inline]
     #[must_use]
@@ -345,13 +600,15 @@ impl<R> Decompress<R> {
     #[inline]
     #[must_use]
     pub fn width(&self) -> usize {
-        self.cinfo.image_width as usize
+        let dim = if self.orientation.swaps_dimensions() { self.cinfo.image_height } else { self.cinfo.image_width };
+        dim as usize
     }
 
     #[inline]
     #[must_use]
     pub fn height(&self) -> usize {
-        self.cinfo.image_height as usize
+        let dim = if self.orientation.swaps_dimensions() { self.cinfo.image_width } else { self.cinfo.image_height };
+        dim as usize
     }
 
     /// Start decompression with conversion to RGB
@@ -380,11 +637,7 @@ impl<R> Decompress<R> {
 
     /// Selects the algorithm used for the DCT step.
     pub fn dct_method(&mut self, method: DctMethod) {
-        self.cinfo.dct_method = match method {
-            DctMethod::IntegerSlow => ffi::J_DCT_METHOD::JDCT_ISLOW,
-            DctMethod::IntegerFast => ffi::J_DCT_METHOD::JDCT_IFAST,
-            DctMethod::Float => ffi::J_DCT_METHOD::JDCT_FLOAT,
-        }
+        self.cinfo.dct_method = method.into();
     }
 
     // If `true`, do careful upsampling of chroma components.  If `false`,
@@ -404,6 +657,15 @@ impl<R> Decompress<R> {
         self.cinfo.do_block_smoothing = ffi::boolean::from(value);
     }
 
+    /// If `true`, the whole file is buffered in coefficient form, and any number of output passes
+    /// can be made from it, e.g. to display refinements of a progressive JPEG as its scans arrive.
+    ///
+    /// Output passes are made with `DecompressStarted::start_output()`/`finish_output()`,
+    /// and input is read with `consume_input()`. Default is `false`.
+    pub fn buffered_image(&mut self, value: bool) {
+        self.cinfo.buffered_image = ffi::boolean::from(value);
+    }
+
     #[inline(always)]
     pub fn raw(mut self) -> io::Result<DecompressStarted<R>> {
         self.cinfo.raw_data_out = ffi::boolean::from(true);
@@ -447,11 +709,21 @@ pub enum Format<R> {
 /// See methods on `Decompress`
 pub struct DecompressStarted<R> {
     dec: Decompress<R>,
+    /// Buffered-image mode needs `start_output` before scanlines can be read
+    in_output_pass: bool,
+    /// The whole image, transformed by `dec.orientation` on the first read
+    oriented_pixels: Vec<u8>,
+    /// Next row of `oriented_pixels` to output
+    oriented_row: usize,
 }
 
 impl<R> DecompressStarted<R> {
-    fn start_decompress(dec: Decompress<R>) -> io::Result<Self> {
-        let mut dec = Self { dec };
+    fn start_decompress(mut dec: Decompress<R>) -> io::Result<Self> {
+        let in_output_pass = 0 == dec.cinfo.buffered_image;
+        if !in_output_pass || 0 != dec.cinfo.raw_data_out {
+            dec.orientation = Orientation::Normal;
+        }
+        let mut dec = Self { dec, in_output_pass, oriented_pixels: Vec::new(), oriented_row: 0 };
         if 0 != unsafe { ffi::jpeg_start_decompress(&mut dec.dec.cinfo) } {
             Ok(dec)
         } else {
@@ -472,7 +744,157 @@ impl<R> DecompressStarted<R> {
     }
 
     fn can_read_more_scanlines(&self) -> bool {
-        self.dec.cinfo.output_scanline < self.dec.cinfo.output_height
+        self.in_output_pass && self.dec.cinfo.output_scanline < self.dec.cinfo.output_height
+    }
+
+    fn is_oriented(&self) -> bool {
+        self.dec.orientation != Orientation::Normal
+    }
+
+    /// Row of the output image that will be read next
+    fn next_row(&self) -> usize {
+        if self.is_oriented() { self.oriented_row } else { self.dec.cinfo.output_scanline as usize }
+    }
+
+    fn unsupported_with_orientation() -> io::Error {
+        io::Error::new(io::ErrorKind::Unsupported, "can't crop or skip scanlines with EXIF orientation applied")
+    }
+
+    fn is_buffered_image(&self) -> bool {
+        0 != self.dec.cinfo.buffered_image
+    }
+
+    /// `true` if the file is progressive (or multi-scan), and buffered-image mode can show its refinements
+    #[must_use]
+    pub fn has_multiple_scans(&self) -> bool {
+        0 != unsafe { ffi::jpeg_has_multiple_scans(&self.dec.cinfo) }
+    }
+
+    /// `true` if the whole file has been read
+    #[must_use]
+    pub fn input_complete(&self) -> bool {
+        0 != unsafe { ffi::jpeg_input_complete(&self.dec.cinfo) }
+    }
+
+    /// Number of the scan being read from the file, starting at 1
+    #[must_use]
+    pub fn input_scan_number(&self) -> usize {
+        self.dec.cinfo.input_scan_number as usize
+    }
+
+    /// Number of the scan used for the current output pass, see `start_output()`
+    #[must_use]
+    pub fn output_scan_number(&self) -> usize {
+        self.dec.cinfo.output_scan_number as usize
+    }
+
+    /// Buffered-image mode only. Reads more of the file without producing any output.
+    pub fn consume_input(&mut self) -> InputStatus {
+        InputStatus::from_c(unsafe { ffi::jpeg_consume_input(&mut self.dec.cinfo) })
+    }
+
+    /// Buffered-image mode only. Starts an output pass that displays the image
+    /// with data of all scans up to `scan_number` (usually `input_scan_number()`).
+    ///
+    /// Read the pixels using `read_scanlines`, and then call `finish_output()`.
+    pub fn start_output(&mut self, scan_number: usize) -> io::Result<()> {
+        if !self.is_buffered_image() || self.in_output_pass {
+            return Err(io::Error::new(io::ErrorKind::Other, "output pass requires buffered image mode and finish_output()"));
+        }
+        if 0 == unsafe { ffi::jpeg_start_output(&mut self.dec.cinfo, scan_number.min(c_int::MAX as usize) as c_int) } {
+            return io_suspend_err();
+        }
+        self.in_output_pass = true;
+        Ok(())
+    }
+
+    /// Buffered-image mode only. Ends the output pass, even if not all scanlines have been read.
+    pub fn finish_output(&mut self) -> io::Result<()> {
+        if !self.is_buffered_image() || !self.in_output_pass {
+            return Err(io::Error::new(io::ErrorKind::Other, "no output pass to finish"));
+        }
+        if 0 == unsafe { ffi::jpeg_finish_output(&mut self.dec.cinfo) } {
+            return io_suspend_err();
+        }
+        self.in_output_pass = false;
+        Ok(())
+    }
+
+    /// Decode only `width` pixels of every row, starting at `x`. Must be called before reading any scanlines.
+    ///
+    /// libjpeg can only crop at iMCU boundaries, so the range is widened to the left and right as needed.
+    /// Returns the actual `(x, width)` of the rows that will be output, and `width()` is updated to match.
+    /// Coordinates are in pixels of the (scaled) output image.
+    ///
+    /// Pixels at the edges of the range may differ slightly from a full decode, because of chroma upsampling.
+    /// See `decode_region` for exact cropping.
+    pub fn crop_scanline(&mut self, x: usize, width: usize) -> io::Result<(usize, usize)> {
+        if self.is_oriented() {
+            return Err(Self::unsupported_with_orientation());
+        }
+        if !self.in_output_pass || self.dec.cinfo.output_scanline != 0 {
+            return Err(io::Error::new(io::ErrorKind::Other, "crop must be set before reading scanlines"));
+        }
+        if width == 0 || x.checked_add(width).map_or(true, |end| end > self.width()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("crop {x}+{width} out of bounds of {}", self.width())));
+        }
+        // both fit, because they're <= output_width
+        let mut xoffset = x as ffi::JDIMENSION;
+        let mut width = width as ffi::JDIMENSION;
+        unsafe {
+            ffi::jpeg_crop_scanline(&mut self.dec.cinfo, &mut xoffset, &mut width);
+        }
+        Ok((xoffset as usize, width as usize))
+    }
+
+    /// Skips decoding of the next `lines` rows. This is faster than reading them,
+    /// though the rows before a skipped iMCU row still need to be partially decoded.
+    ///
+    /// Returns number of rows skipped, which is less than requested at the end of the image.
+    /// Not supported in buffered-image mode.
+    pub fn skip_scanlines(&mut self, lines: usize) -> io::Result<usize> {
+        if self.is_buffered_image() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "can't skip scanlines in buffered image mode"));
+        }
+        if self.is_oriented() {
+            return Err(Self::unsupported_with_orientation());
+        }
+        let lines = lines.min(self.height() - self.dec.cinfo.output_scanline as usize);
+        if lines == 0 {
+            return Ok(0);
+        }
+        Ok(unsafe { ffi::jpeg_skip_scanlines(&mut self.dec.cinfo, lines as ffi::JDIMENSION) } as usize)
+    }
+
+    /// Decodes exactly the `width`x`height` rectangle at `x`,`y`, using `crop_scanline` and `skip_scanlines`
+    /// to avoid decoding most of the rest of the image. Coordinates are in pixels of the (scaled) output image.
+    ///
+    /// Must be called before reading any scanlines. Skips all the remaining rows, so `finish()` can be called afterwards.
+    pub fn decode_region<T: Pod>(&mut self, x: usize, y: usize, width: usize, height: usize) -> io::Result<Vec<T>> {
+        if height == 0 || y.checked_add(height).map_or(true, |end| end > self.height()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("region {y}+{height} out of bounds of {}", self.height())));
+        }
+        if width == 0 || x.checked_add(width).map_or(true, |end| end > self.width()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("region {x}+{width} out of bounds of {}", self.width())));
+        }
+        let item_size = pixel_items_per_pixel::<T>(self.color_space().num_components())?;
+        // Fancy upsampling of chroma needs neighboring pixels, and edges of the cropped area would be replicated instead
+        let margin = self.dec.cinfo.max_h_samp_factor.max(1) as usize;
+        let margin_x = x.saturating_sub(margin);
+        let margin_end = (x + width + margin).min(self.width());
+        let (crop_x, crop_width) = self.crop_scanline(margin_x, margin_end - margin_x)?;
+        self.skip_scanlines(y)?;
+
+        let mut row = vec![T::zeroed(); crop_width * item_size];
+        let row_start = (x - crop_x) * item_size;
+        let mut image_dst: Vec<T> = Vec::new();
+        image_dst.try_reserve_exact(width * height * item_size).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        for _ in 0..height {
+            self.read_scanlines_into(&mut row)?;
+            image_dst.extend_from_slice(&row[row_start..row_start + width * item_size]);
+        }
+        self.skip_scanlines(usize::MAX)?;
+        Ok(image_dst)
     }
 
     /// Append data
@@ -533,12 +955,14 @@ impl<R> DecompressStarted<R> {
 
     #[must_use]
     pub fn width(&self) -> usize {
-        self.dec.cinfo.output_width as usize
+        let dim = if self.dec.orientation.swaps_dimensions() { self.dec.cinfo.output_height } else { self.dec.cinfo.output_width };
+        dim as usize
     }
 
     #[must_use]
     pub fn height(&self) -> usize {
-        self.dec.cinfo.output_height as usize
+        let dim = if self.dec.orientation.swaps_dimensions() { self.dec.cinfo.output_width } else { self.dec.cinfo.output_height };
+        dim as usize
     }
 
     /// Supports any pixel type that is marked as "plain old data", see bytemuck crate.
@@ -554,7 +978,7 @@ impl<R> DecompressStarted<R> {
             ));
         }
         let width = self.width();
-        let height = self.height();
+        let height = self.height() - self.next_row();
         let mut image_dst: Vec<T> = Vec::new();
         let required_len = height * width * (num_components / mem::size_of::<T>());
         image_dst.try_reserve_exact(required_len).map_err(|_| io::ErrorKind::OutOfMemory)?;
@@ -579,16 +1003,7 @@ impl<R> DecompressStarted<R> {
     /// Returns written-to slice
     pub fn read_scanlines_into_uninit<'dest, T: Pod>(&mut self, dest: &'dest mut [MaybeUninit<T>]) -> io::Result<&'dest mut [T]> {
         let num_components = self.color_space().num_components();
-        let item_size = if mem::size_of::<T>() == 1 {
-            num_components
-        } else if num_components == mem::size_of::<T>() {
-            1
-        } else {
-            return Err(io::Error::new(
-                io::ErrorKind::Unsupported,
-                format!("pixel size must have {num_components} bytes, but has {}", mem::size_of::<T>()),
-            ));
-        };
+        let item_size = pixel_items_per_pixel::<T>(num_components)?;
         let width = self.width();
         let height = self.height();
         let line_width = width * item_size;
@@ -598,6 +1013,9 @@ impl<R> DecompressStarted<R> {
                 format!("destination slice length must be multiple of {width}x{num_components} bytes long, got {}B", std::mem::size_of_val(dest)),
             ));
         }
+        if self.is_oriented() {
+            return self.read_oriented_into_uninit(dest);
+        }
         for row in dest.chunks_exact_mut(line_width) {
             if !self.can_read_more_scanlines() {
                 return Err(io::ErrorKind::UnexpectedEof.into());
@@ -619,6 +1037,29 @@ impl<R> DecompressStarted<R> {
         Ok(dest_init)
     }
 
+    /// Decodes the whole image on the first call, and copies the next rows of the transformed image
+    fn read_oriented_into_uninit<'dest, T: Pod>(&mut self, dest: &'dest mut [MaybeUninit<T>]) -> io::Result<&'dest mut [T]> {
+        if self.oriented_pixels.is_empty() {
+            let orientation = mem::replace(&mut self.dec.orientation, Orientation::Normal);
+            let (width, height) = (self.width(), self.height());
+            let pixels = self.read_scanlines::<u8>();
+            self.dec.orientation = orientation;
+            let bytes_per_pixel = self.color_space().num_components();
+            self.oriented_pixels = orientation.apply(&pixels?, width, height, bytes_per_pixel);
+        }
+        let line_bytes = self.width() * self.color_space().num_components();
+        let dest_bytes = mem::size_of_val(dest);
+        let start = self.oriented_row * line_bytes;
+        let src = self.oriented_pixels.get(start..start + dest_bytes).ok_or(io::ErrorKind::UnexpectedEof)?;
+        // Safety: T is Pod, and all of its bytes are written
+        let dest_init = unsafe {
+            ptr::copy_nonoverlapping(src.as_ptr(), dest.as_mut_ptr().cast::<u8>(), dest_bytes);
+            std::mem::transmute::<&'dest mut [MaybeUninit<T>], &'dest mut [T]>(dest)
+        };
+        self.oriented_row += dest_bytes / line_bytes;
+        Ok(dest_init)
+    }
+
     #[deprecated(note = "use read_scanlines::<u8>")]
     #[doc(hidden)]
     pub fn read_scanlines_flat(&mut self) -> io::Result<Vec<u8>> {
@@ -662,8 +1103,21 @@ impl<R> DecompressStarted<R> {
         self.finish_internal()
     }
 
+    /// Finish decompress, and return the reader and the decoder for decoding another file.
+    ///
+    /// See `ReusableDecompress`.
+    pub fn finish_for_reuse(mut self) -> io::Result<(ReusableDecompress, R)> where R: BufRead {
+        self.finish_internal()?;
+        let (reusable, src_mgr) = self.dec.into_reusable();
+        let mgr = src_mgr.ok_or(io::ErrorKind::Other)?;
+        Ok((reusable, mgr.into_inner()))
+    }
+
     #[inline]
     fn finish_internal(&mut self) -> io::Result<()> {
+        if self.is_buffered_image() && self.in_output_pass {
+            self.finish_output()?;
+        }
         if 0 != unsafe { ffi::jpeg_finish_decompress(&mut self.dec.cinfo) } {
             Ok(())
         } else {
@@ -672,6 +1126,20 @@ impl<R> DecompressStarted<R> {
     }
 }
 
+/// How many `T`s make one pixel
+pub(crate) fn pixel_items_per_pixel<T>(num_components: usize) -> io::Result<usize> {
+    if mem::size_of::<T>() == 1 {
+        Ok(num_components)
+    } else if num_components == mem::size_of::<T>() {
+        Ok(1)
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("pixel size must have {num_components} bytes, but has {}", mem::size_of::<T>()),
+        ))
+    }
+}
+
 #[cold]
 fn io_suspend_err<T>() -> io::Result<T> {
     Err(io::ErrorKind::WouldBlock.into())
@@ -915,3 +1383,228 @@ fn drops_reader() {
     drop(r);
     assert_eq!(1, drop_count);
 }
+
+#[test]
+fn buffered_image_passes() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let expected: Vec<[u8; 3]> = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines().unwrap();
+
+    let mut dinfo = Decompress::new_mem(&data).unwrap();
+    dinfo.buffered_image(true);
+    let mut dinfo = dinfo.rgb().unwrap();
+    assert!(dinfo.has_multiple_scans());
+    assert!(dinfo.read_scanlines::<[u8; 3]>().is_err());
+
+    let mut passes = 0;
+    let mut last_pass = Vec::new();
+    while !dinfo.input_complete() {
+        while !matches!(dinfo.consume_input(), InputStatus::ScanCompleted | InputStatus::ReachedEoi) {}
+        dinfo.start_output(dinfo.input_scan_number()).unwrap();
+        last_pass = dinfo.read_scanlines::<[u8; 3]>().unwrap();
+        dinfo.finish_output().unwrap();
+        passes += 1;
+    }
+    assert!(passes > 1);
+    assert_eq!(expected, last_pass);
+    dinfo.finish().unwrap();
+}
+
+#[test]
+fn region() {
+    let mut comp = crate::Compress::new(crate::ColorSpace::JCS_RGB);
+    comp.set_size(200, 150);
+    let mut comp = comp.start_compress(Vec::new()).unwrap();
+    let pixels: Vec<u8> = (0..200 * 150 * 3).map(|i| ((i * 7) ^ (i / 500)) as u8).collect();
+    comp.write_scanlines(&pixels).unwrap();
+    let data = comp.finish().unwrap();
+
+    let full: Vec<[u8; 3]> = Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines().unwrap();
+    let full_width = 200;
+
+    for (x, y, w, h) in [(0, 0, 1, 1), (13, 17, 29, 31), (33, 51, 110, 40), (199, 149, 1, 1), (0, 0, 200, 150)] {
+        let mut dinfo = Decompress::new_mem(&data).unwrap().rgb().unwrap();
+        let region: Vec<[u8; 3]> = dinfo.decode_region(x, y, w, h).unwrap();
+        dinfo.finish().unwrap();
+        let expected: Vec<_> = full.chunks_exact(full_width).skip(y).take(h).flat_map(|row| &row[x..x + w]).copied().collect();
+        assert_eq!(expected, region, "{x},{y} {w}x{h}");
+    }
+
+    let mut dinfo = Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    let (x, w) = dinfo.crop_scanline(13, 29).unwrap();
+    assert!(x <= 13 && x + w >= 13 + 29);
+    assert_eq!(w, dinfo.width());
+    assert_eq!(5, dinfo.skip_scanlines(5).unwrap());
+    assert_eq!(dinfo.height() - 5, dinfo.read_scanlines::<[u8; 3]>().unwrap().len() / w);
+    assert!(dinfo.crop_scanline(0, 1).is_err());
+    dinfo.finish().unwrap();
+}
+
+#[test]
+fn icc_profile_chunks() {
+    use crate::compress::{Compress, CompressStarted};
+
+    let encode = |write: &dyn Fn(&mut CompressStarted<Vec<u8>>)| {
+        let mut comp = Compress::new(ColorSpace::JCS_GRAYSCALE);
+        comp.set_size(8, 8);
+        let mut started = comp.start_compress(Vec::new()).unwrap();
+        write(&mut started);
+        started.write_scanlines(&[128; 64]).unwrap();
+        started.finish().unwrap()
+    };
+    let profile: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+
+    let jpeg = encode(&|started| started.write_icc_profile(&profile));
+    let dec = Decompress::with_markers(&[Marker::APP(2)]).from_mem(&jpeg).unwrap();
+    let seq: Vec<_> = dec.markers().map(|m| (m.data[12], m.data[13])).collect();
+    assert_eq!(vec![(1, 2), (2, 2)], seq);
+    assert_eq!(Some(&profile), dec.icc_profile().as_ref());
+
+    // chunks numbered from 0, as written by older versions
+    let legacy: Vec<Vec<u8>> = profile.chunks(65519).enumerate().map(|(i, chunk)| {
+        [&b"ICC_PROFILE\0"[..], &[i as u8, 2], chunk].concat()
+    }).collect();
+    let jpeg = encode(&|started| legacy.iter().for_each(|data| started.write_marker(Marker::APP(2), data)));
+    let dec = Decompress::with_markers(&[Marker::APP(2)]).from_mem(&jpeg).unwrap();
+    assert_eq!(Some(&profile), dec.icc_profile().as_ref());
+
+    let jpeg = encode(&|started| started.write_marker(Marker::APP(2), &legacy[1]));
+    let dec = Decompress::with_markers(&[Marker::APP(2)]).from_mem(&jpeg).unwrap();
+    assert_eq!(None, dec.icc_profile());
+}
+
+#[test]
+fn estimates_quality() {
+    use crate::qtable::{AnnexK_Chroma, AnnexK_Luma};
+
+    let mut comp = crate::Compress::new(crate::ColorSpace::JCS_RGB);
+    comp.set_size(16, 16);
+    comp.set_luma_qtable(&AnnexK_Luma);
+    comp.set_chroma_qtable(&AnnexK_Chroma);
+    let mut comp = comp.start_compress(Vec::new()).unwrap();
+    comp.write_scanlines(&[128; 16 * 16 * 3]).unwrap();
+    let data = comp.finish().unwrap();
+
+    let est = Decompress::new_mem(&data).unwrap().estimate_quality().unwrap();
+    assert_eq!(50, est.luma_quality);
+    assert_eq!(Some(50), est.chroma_quality);
+    assert_eq!(1., est.confidence);
+    assert_eq!("Annex-K Luma", est.preset);
+
+    let mut started = Decompress::new_mem(&data).unwrap().rgb().unwrap();
+    assert_eq!(Some(est), crate::qtable::QualityEstimate::from_components(started.components()));
+    let _: Vec<[u8; 3]> = started.read_scanlines().unwrap();
+    started.finish().unwrap();
+}
+
+#[test]
+fn custom_huffman_tables() {
+    let pixels: Vec<u8> = (0..64 * 48 * 3).map(|i| ((i * 13) ^ (i / 97)) as u8).collect();
+    let compress = |tables: Option<(HuffmanTable, HuffmanTable)>| {
+        let mut comp = crate::Compress::new(crate::ColorSpace::JCS_RGB);
+        comp.set_size(64, 48);
+        if let Some((dc, ac)) = &tables {
+            for slot in 0..2 {
+                comp.set_dc_huffman_table(slot, dc);
+                comp.set_ac_huffman_table(slot, ac);
+            }
+        } else {
+            comp.set_optimize_scans(false);
+        }
+        let mut comp = comp.start_compress(Vec::new()).unwrap();
+        comp.write_scanlines(&pixels).unwrap();
+        comp.finish().unwrap()
+    };
+
+    let optimized = compress(None);
+    let dec = Decompress::new_mem(&optimized).unwrap();
+    let (dc, ac) = (dec.dc_huffman_table(0).unwrap(), dec.ac_huffman_table(0).unwrap());
+    assert!(dec.dc_huffman_table(3).is_none());
+    let layout = crate::JpegInfo::from_mem(&optimized).unwrap().layout.unwrap();
+    let parsed = layout.huffman_tables.iter().find(|t| t.class == HuffmanClass::Ac && t.slot == 0).unwrap();
+    assert_eq!(ac, parsed.table);
+
+    let fixed = compress(Some((dc.clone(), ac.clone())));
+    let dec = Decompress::new_mem(&fixed).unwrap();
+    assert_eq!(Some(&dc), dec.dc_huffman_table(1).as_ref());
+    assert_eq!(Some(&ac), dec.ac_huffman_table(1).as_ref());
+    let _: Vec<[u8; 3]> = dec.rgb().unwrap().read_scanlines().unwrap();
+}
+
+#[test]
+fn abbreviated_streams() {
+    let pixels: Vec<u8> = (0..32 * 24 * 3).map(|i| (i * 7 % 251) as u8).collect();
+    let mut comp = crate::Compress::new(crate::ColorSpace::JCS_RGB);
+    comp.set_size(32, 24);
+    comp.set_quality(80.);
+    let tables = comp.write_tables(Vec::new()).unwrap();
+    comp.suppress_tables(true);
+    let mut images = Vec::new();
+    for _ in 0..2 {
+        comp.set_size(32, 24);
+        let mut started = comp.start_compress(Vec::new()).unwrap();
+        started.write_scanlines(&pixels).unwrap();
+        let (reused, image) = started.finish_for_reuse().unwrap();
+        comp = reused;
+        assert!(!image.windows(2).any(|w| w == [0xFF, 0xDB]), "no DQT");
+        images.push(image);
+    }
+    assert!(Decompress::new_mem(&tables).is_err());
+
+    let mut dec = Decompress::builder().reusable();
+    assert!(dec.read_tables_from_mem(&images[0]).is_err());
+    dec.read_tables_from_mem(&tables).unwrap();
+    for image in &images {
+        let mut started = dec.from_mem(image).unwrap().rgb().unwrap();
+        let decoded: Vec<[u8; 3]> = started.read_scanlines().unwrap();
+        dec = started.finish_for_reuse().unwrap().0;
+        // Tables can be moved from the tables-only stream into the image
+        let complete = [&tables[..tables.len() - 2], &image[2..]].concat();
+        let expected: Vec<[u8; 3]> = Decompress::new_mem(&complete).unwrap().rgb().unwrap().read_scanlines().unwrap();
+        assert!(expected == decoded);
+    }
+
+    let original = std::fs::read("tests/test.jpg").unwrap();
+    let mut stats = crate::huffman::HuffmanStats::new();
+    stats.add_image(&original).unwrap();
+    let abbreviated = stats.encode_abbreviated(&original).unwrap();
+    let mut dec = Decompress::builder().reusable();
+    dec.read_tables(&stats.tables_only()[..]).unwrap();
+    let from_abbreviated: Vec<[u8; 3]> = dec.from_mem(&abbreviated).unwrap().rgb().unwrap().read_scanlines().unwrap();
+    let complete = stats.encode(&original).unwrap();
+    let from_complete: Vec<[u8; 3]> = Decompress::new_mem(&complete).unwrap().rgb().unwrap().read_scanlines().unwrap();
+    assert!(from_complete == from_abbreviated);
+}
+
+#[test]
+fn sequence_of_images() {
+    let first = std::fs::read("tests/test.jpg").unwrap();
+    let mut comp = crate::Compress::new(crate::ColorSpace::JCS_GRAYSCALE);
+    comp.set_size(8, 3);
+    let mut started = comp.start_compress(Vec::new()).unwrap();
+    started.write_scanlines(&[128; 8 * 3]).unwrap();
+    let second = started.finish().unwrap();
+
+    let mut stream = first.clone();
+    stream.extend_from_slice(&[0; 5]);
+    stream.extend_from_slice(&second);
+    stream.extend_from_slice(&[0xFF, 0xFF]);
+    stream.extend_from_slice(&first);
+    stream.extend_from_slice(b"trailer\xFF");
+
+    let mut seq = DecompressSequence::with_markers(BufReader::with_capacity(100, &stream[..]), ALL_MARKERS);
+    let mut sizes = Vec::new();
+    while let Some(dec) = seq.next_image().unwrap() {
+        sizes.push(dec.size());
+        let mut started = dec.grayscale().unwrap();
+        let _: Vec<u8> = started.read_scanlines().unwrap();
+        started.finish().unwrap();
+    }
+    assert_eq!(vec![(45, 30), (8, 3), (45, 30)], sizes);
+    assert!(seq.next_image().unwrap().is_none());
+
+    let mut seq = DecompressSequence::new(&second[..]);
+    let mut started = seq.next_image().unwrap().unwrap().grayscale().unwrap();
+    assert_eq!(vec![128u8; 8 * 3], started.read_scanlines::<u8>().unwrap());
+    started.finish_into_inner().unwrap();
+    assert!(seq.next_image().unwrap().is_none());
+}
@@ -0,0 +1,97 @@
+//! Minimal reader of TIFF-formatted EXIF data, for the few tags that matter for decoding.
+use crate::decompress::MarkerData;
+use crate::marker::Marker;
+
+/// Start of the APP1 marker data with EXIF
+pub(crate) const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+pub(crate) const TAG_ORIENTATION: u16 = 0x0112;
+pub(crate) const TAG_JPEG_INTERCHANGE_FORMAT: u16 = 0x0201;
+pub(crate) const TAG_JPEG_INTERCHANGE_FORMAT_LENGTH: u16 = 0x0202;
+
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+
+/// TIFF data after the `Exif\0\0` header
+#[derive(Copy, Clone)]
+pub(crate) struct Tiff<'a> {
+    pub(crate) data: &'a [u8],
+    big_endian: bool,
+}
+
+#[derive(Copy, Clone)]
+pub(crate) struct IfdEntry {
+    pub(crate) tag: u16,
+    typ: u16,
+    count: u32,
+    /// Offset of the value (or offset to the value) field
+    value_pos: usize,
+}
+
+impl<'a> Tiff<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(..4)? {
+            b"MM\0\x2A" => true,
+            b"II\x2A\0" => false,
+            _ => return None,
+        };
+        Some(Self { data, big_endian })
+    }
+
+    /// EXIF data from the first APP1 `Exif` marker
+    pub(crate) fn from_markers<'m>(mut markers: impl Iterator<Item = MarkerData<'m>>) -> Option<Tiff<'m>> {
+        markers.find(|m| m.marker == Marker::APP(1) && m.data.starts_with(EXIF_HEADER))
+            .and_then(|m| Tiff::new(&m.data[EXIF_HEADER.len()..]))
+    }
+
+    pub(crate) fn u16_at(&self, pos: usize) -> Option<u16> {
+        let b = self.data.get(pos..pos.checked_add(2)?)?;
+        let b = [b[0], b[1]];
+        Some(if self.big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) })
+    }
+
+    pub(crate) fn u32_at(&self, pos: usize) -> Option<u32> {
+        let b = self.data.get(pos..pos.checked_add(4)?)?;
+        let b = [b[0], b[1], b[2], b[3]];
+        Some(if self.big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) })
+    }
+
+    /// Offset of IFD0, the main image's tags
+    pub(crate) fn first_ifd(&self) -> Option<usize> {
+        Some(self.u32_at(4)? as usize)
+    }
+
+    /// Offset of the IFD linked after `ifd` (IFD1 is the thumbnail's). `None` at the end of the chain.
+    pub(crate) fn next_ifd(&self, ifd: usize) -> Option<usize> {
+        let count = usize::from(self.u16_at(ifd)?);
+        let next = self.u32_at(ifd + 2 + count * 12)? as usize;
+        // a loop back would never end
+        (next > ifd).then_some(next)
+    }
+
+    pub(crate) fn ifd_entries(self, ifd: usize) -> impl Iterator<Item = IfdEntry> + 'a {
+        let count = self.u16_at(ifd).unwrap_or(0);
+        (0..usize::from(count)).map_while(move |i| {
+            let pos = ifd + 2 + i * 12;
+            Some(IfdEntry {
+                tag: self.u16_at(pos)?,
+                typ: self.u16_at(pos + 2)?,
+                count: self.u32_at(pos + 4)?,
+                value_pos: pos + 8,
+            })
+        })
+    }
+
+    /// Value of a single SHORT or LONG tag in the IFD
+    pub(crate) fn find_u32(self, ifd: usize, tag: u16) -> Option<u32> {
+        let entry = self.ifd_entries(ifd).find(|e| e.tag == tag)?;
+        if entry.count != 1 {
+            return None;
+        }
+        match entry.typ {
+            TYPE_SHORT => self.u16_at(entry.value_pos).map(u32::from),
+            TYPE_LONG => self.u32_at(entry.value_pos),
+            _ => None,
+        }
+    }
+}
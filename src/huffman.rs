@@ -0,0 +1,460 @@
+//! Huffman tables for entropy coding of baseline JPEG files
+use crate::compress::Compress;
+use crate::decompress::Decompress;
+use crate::ffi;
+use crate::ffi::JHUFF_TBL;
+use crate::marker::Marker;
+use crate::parse::{without_segments, DHT};
+use crate::qtable::ZIGZAG_ORDER;
+use std::io;
+
+// Annex K.3 tables, as in libjpeg's jstdhuff.c
+const DC_LUMA_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const DC_CHROMA_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+const AC_LUMA_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 125];
+const AC_LUMA_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7,
+    0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5,
+    0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
+    0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+const AC_CHROMA_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 119];
+const AC_CHROMA_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0,
+    0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26,
+    0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5,
+    0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3,
+    0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
+    0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+/// Whether a table codes DC coefficients or AC coefficients
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HuffmanClass {
+    Dc,
+    Ac,
+}
+
+/// Huffman table as defined in a DHT marker: number of codes of each length, and symbols they code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HuffmanTable {
+    code_counts: [u8; 16],
+    values: Vec<u8>,
+}
+
+impl HuffmanTable {
+    /// `code_counts[n]` is the number of codes that are `n+1` bits long.
+    /// `values` are symbols in order of increasing code length, so their number must be the sum of the counts.
+    ///
+    /// Fails if the codes don't fit in 16 bits (excluding the all-ones codes that JPEG doesn't use).
+    pub fn new(code_counts: [u8; 16], values: &[u8]) -> io::Result<Self> {
+        let total = code_counts.iter().map(|&c| usize::from(c)).sum::<usize>();
+        if total != values.len() || total > 256 {
+            return Err(invalid(format!("Huffman table has {} codes, but {} values", total, values.len())));
+        }
+        // canonical codes are assigned in order, so the next unused code must not exceed the last one available
+        let mut code = 0u32;
+        for (bits, &count) in (1..).zip(&code_counts) {
+            code += u32::from(count);
+            if code >= 1 << bits {
+                return Err(invalid(format!("Huffman table has too many codes of length {bits}")));
+            }
+            code <<= 1;
+        }
+        Ok(Self { code_counts, values: values.to_vec() })
+    }
+
+    /// Example tables from the JPEG spec (Annex K), for luma or chroma. libjpeg uses them when `optimize_coding` is off,
+    /// and Motion-JPEG frames that have no DHT marker are implicitly coded with them.
+    #[must_use]
+    pub fn standard(class: HuffmanClass, chroma: bool) -> Self {
+        let (code_counts, values): (_, &[u8]) = match (class, chroma) {
+            (HuffmanClass::Dc, false) => (DC_LUMA_BITS, &DC_VALUES),
+            (HuffmanClass::Dc, true) => (DC_CHROMA_BITS, &DC_VALUES),
+            (HuffmanClass::Ac, false) => (AC_LUMA_BITS, &AC_LUMA_VALUES),
+            (HuffmanClass::Ac, true) => (AC_CHROMA_BITS, &AC_CHROMA_VALUES),
+        };
+        Self { code_counts, values: values.to_vec() }
+    }
+
+    /// Number of codes of each length from 1 to 16 bits
+    #[must_use]
+    pub fn code_counts(&self) -> &[u8; 16] {
+        &self.code_counts
+    }
+
+    /// Symbols in order of increasing code length
+    #[must_use]
+    pub fn values(&self) -> &[u8] {
+        &self.values
+    }
+
+    /// Optimal table for the given symbol frequencies, using the same algorithm as libjpeg's `optimize_coding`.
+    ///
+    /// Only symbols with non-zero frequency get a code. Codes are limited to 16 bits.
+    #[must_use]
+    pub fn optimal(frequencies: &[u64; 256]) -> Self {
+        // one extra symbol with the lowest frequency reserves the all-ones code
+        let mut freq = [0u64; 257];
+        freq[..256].copy_from_slice(frequencies);
+        freq[256] = 1;
+        let mut code_size = [0usize; 257];
+        let mut others = [None::<usize>; 257];
+
+        loop {
+            // two least frequent symbols (the higher-numbered one on ties, like libjpeg)
+            let least = |exclude: Option<usize>| {
+                let mut found = None;
+                let mut min = u64::MAX;
+                for (i, &f) in freq.iter().enumerate() {
+                    if f != 0 && f <= min && Some(i) != exclude {
+                        min = f;
+                        found = Some(i);
+                    }
+                }
+                found
+            };
+            let Some(c1) = least(None) else { break };
+            let Some(c2) = least(Some(c1)) else { break };
+
+            freq[c1] += freq[c2];
+            freq[c2] = 0;
+            // increment code sizes in both branches of the merged tree, and join the chains
+            let mut c = c1;
+            code_size[c] += 1;
+            while let Some(next) = others[c] {
+                c = next;
+                code_size[c] += 1;
+            }
+            others[c] = Some(c2);
+            let mut c = c2;
+            code_size[c] += 1;
+            while let Some(next) = others[c] {
+                c = next;
+                code_size[c] += 1;
+            }
+        }
+
+        let mut bits = [0u32; 33];
+        for &size in code_size.iter().filter(|&&size| size > 0) {
+            bits[size.min(32)] += 1;
+        }
+        // moves pairs of too-long codes up the tree, as in JPEG spec's Adjust_BITS
+        for i in (17..=32).rev() {
+            while bits[i] > 0 {
+                let mut j = i - 2;
+                while bits[j] == 0 {
+                    j -= 1;
+                }
+                bits[i] -= 2;
+                bits[i - 1] += 1;
+                bits[j + 1] += 2;
+                bits[j] -= 1;
+            }
+        }
+        // the reserved symbol has the longest code
+        if let Some(longest) = (1..=16).rev().find(|&i| bits[i] > 0) {
+            bits[longest] -= 1;
+        }
+
+        let mut values = Vec::new();
+        for size in 1..=32 {
+            values.extend((0..=255u8).filter(|&v| code_size[usize::from(v)] == size));
+        }
+        let mut code_counts = [0; 16];
+        for (count, &b) in code_counts.iter_mut().zip(&bits[1..=16]) {
+            *count = b as u8;
+        }
+        Self { code_counts, values }
+    }
+
+    /// DHT segment defining this table in `slot` (0-3), including the marker and length
+    #[must_use]
+    pub fn to_dht(&self, class: HuffmanClass, slot: u8) -> Vec<u8> {
+        assert!(slot < 4);
+        let len = 2 + 1 + 16 + self.values.len();
+        let mut out = Vec::with_capacity(2 + len);
+        out.extend_from_slice(&[0xFF, 0xC4]);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+        out.push(if class == HuffmanClass::Ac { 0x10 } else { 0 } | slot);
+        out.extend_from_slice(&self.code_counts);
+        out.extend_from_slice(&self.values);
+        out
+    }
+
+    pub(crate) fn from_c(tbl: &JHUFF_TBL) -> Self {
+        let code_counts: [u8; 16] = tbl.bits[1..].try_into().unwrap();
+        let total = code_counts.iter().map(|&c| usize::from(c)).sum::<usize>();
+        Self { code_counts, values: tbl.huffval[..total.min(256)].to_vec() }
+    }
+
+    pub(crate) fn copy_to_c(&self, tbl: &mut JHUFF_TBL) {
+        tbl.bits[0] = 0;
+        tbl.bits[1..].copy_from_slice(&self.code_counts);
+        tbl.huffval = [0; 256];
+        tbl.huffval[..self.values.len()].copy_from_slice(&self.values);
+    }
+}
+
+/// Symbol statistics of a set of images, for coding all of them with the same optimal Huffman tables.
+///
+/// Compress the images as usual (e.g. with `Compress`), and add all of them to the statistics with `add_image()`.
+/// Then losslessly re-encode each of them with `encode()`, which makes baseline files using the shared tables.
+/// The tables can be stored only once, in a stream from `tables_only()`, if the images are encoded with `encode_abbreviated()`.
+#[derive(Clone)]
+pub struct HuffmanStats {
+    dc: [[u64; 256]; 4],
+    ac: [[u64; 256]; 4],
+}
+
+impl Default for HuffmanStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HuffmanStats {
+    #[must_use]
+    pub fn new() -> Self {
+        Self { dc: [[0; 256]; 4], ac: [[0; 256]; 4] }
+    }
+
+    /// First pass: counts symbols the image will need when it's re-encoded by `encode()`
+    pub fn add_image(&mut self, jpeg: &[u8]) -> io::Result<()> {
+        with_coefficients(jpeg, |dec, comp, coefs| {
+            unsafe { self.count_image(dec, &comp, coefs) };
+            Ok(())
+        })
+    }
+
+    /// Optimal table for DC coefficients in the slot, if any image uses it
+    #[must_use]
+    pub fn dc_table(&self, slot: usize) -> Option<HuffmanTable> {
+        self.dc.get(slot).filter(|f| f.iter().any(|&f| f > 0)).map(HuffmanTable::optimal)
+    }
+
+    /// Optimal table for AC coefficients in the slot, if any image uses it
+    #[must_use]
+    pub fn ac_table(&self, slot: usize) -> Option<HuffmanTable> {
+        self.ac.get(slot).filter(|f| f.iter().any(|&f| f > 0)).map(HuffmanTable::optimal)
+    }
+
+    /// Second pass: losslessly re-encodes the image as baseline JPEG using the shared tables.
+    /// APPn and COM markers are kept.
+    ///
+    /// The image must have been added to the statistics, otherwise the tables may lack codes it needs.
+    pub fn encode(&self, jpeg: &[u8]) -> io::Result<Vec<u8>> {
+        with_coefficients(jpeg, |dec, mut comp, coefs| unsafe {
+            for slot in 0..4 {
+                if let Some(table) = self.dc_table(slot) {
+                    comp.set_dc_huffman_table(slot, &table);
+                }
+                if let Some(table) = self.ac_table(slot) {
+                    comp.set_ac_huffman_table(slot, &table);
+                }
+            }
+            let mut started = comp.start_compress_coefficients(Vec::with_capacity(jpeg.len()), coefs);
+            for m in dec.markers() {
+                // the compressor writes its own JFIF and Adobe markers
+                let own = (m.marker == Marker::APP(0) && m.data.starts_with(b"JFIF\0"))
+                    || (m.marker == Marker::APP(14) && m.data.starts_with(b"Adobe"));
+                if !own {
+                    started.write_marker(m.marker, m.data);
+                }
+            }
+            started.finish()
+        })
+    }
+
+    /// Like `encode()`, but without Huffman tables, which the decoder must get from `tables_only()` first
+    pub fn encode_abbreviated(&self, jpeg: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(without_segments(&self.encode(jpeg)?, DHT))
+    }
+
+    /// Tables-only JPEG datastream (SOI, DHT markers, EOI) defining all the shared tables
+    #[must_use]
+    pub fn tables_only(&self) -> Vec<u8> {
+        let mut out = vec![0xFF, 0xD8];
+        for slot in 0..4 {
+            if let Some(table) = self.dc_table(slot) {
+                out.extend(table.to_dht(HuffmanClass::Dc, slot as u8));
+            }
+            if let Some(table) = self.ac_table(slot) {
+                out.extend(table.to_dht(HuffmanClass::Ac, slot as u8));
+            }
+        }
+        out.extend_from_slice(&[0xFF, 0xD9]);
+        out
+    }
+
+    /// Visits blocks in the order libjpeg's `jctrans.c` encodes them in a single sequential scan,
+    /// including dummy blocks that pad partial MCUs at the edges.
+    unsafe fn count_image(&mut self, dec: &mut Decompress<&[u8]>, comp: &Compress, coefs: *mut *mut ffi::jvirt_barray_control) {
+        // sizes are known only to the decompressor, and table numbers only to the compressor
+        let comps: Vec<_> = dec.components().iter().zip(comp.components()).map(|(d, c)| (
+            (d.h_samp_factor as usize, d.v_samp_factor as usize),
+            (d.width_in_blocks as usize, d.height_in_blocks as usize),
+            (c.dc_tbl_no as usize & 3, c.ac_tbl_no as usize & 3),
+        )).collect();
+        let cinfo = &mut dec.cinfo;
+        let interleaved = comps.len() > 1;
+        let (mcu_cols, mcu_rows) = if interleaved {
+            let (mcu_w, mcu_h) = (cinfo.max_h_samp_factor as usize * 8, cinfo.max_v_samp_factor as usize * 8);
+            ((cinfo.image_width as usize + mcu_w - 1) / mcu_w, (cinfo.image_height as usize + mcu_h - 1) / mcu_h)
+        } else {
+            comps[0].1
+        };
+        let access = (*cinfo.common.mem).access_virt_barray.unwrap();
+        let mut last_dc = [0i16; 4];
+        for mcu_row in 0..mcu_rows {
+            for (ci, &(sampling, (width, height), (dc_slot, ac_slot))) in comps.iter().enumerate() {
+                let (mcu_width, mcu_height) = if interleaved { sampling } else { (1, 1) };
+                let rows = access(&mut cinfo.common, *coefs.add(ci), (mcu_row * mcu_height) as _, mcu_height as _, 0);
+                let (dc, ac) = (&mut self.dc[dc_slot], &mut self.ac[ac_slot]);
+                for mcu_col in 0..mcu_cols {
+                    for y in 0..mcu_height {
+                        let row_y = mcu_row * mcu_height + y;
+                        for x in 0..mcu_width {
+                            let col = mcu_col * mcu_width + x;
+                            // dummy blocks repeat DC of the previous block, and have no AC
+                            let block = if col < width && row_y < height { Some(&*(*rows.add(y)).add(col)) } else { None };
+                            count_block(block, &mut last_dc[ci], dc, ac);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Reads DCT coefficients, and sets up a baseline compressor for writing them, like jpegtran does
+fn with_coefficients<T>(jpeg: &[u8], f: impl FnOnce(&mut Decompress<&[u8]>, Compress, *mut *mut ffi::jvirt_barray_control) -> io::Result<T>) -> io::Result<T> {
+    let mut dec = Decompress::with_markers(crate::ALL_MARKERS).from_mem(jpeg)?;
+    unsafe {
+        let coefs = ffi::jpeg_read_coefficients(&mut dec.cinfo);
+        if coefs.is_null() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no image in the JPEG file"));
+        }
+        let mut comp = Compress::new(dec.color_space());
+        ffi::jpeg_copy_critical_parameters(&dec.cinfo, &mut comp.cinfo);
+        comp.set_optimize_scans(false);
+        f(&mut dec, comp, coefs)
+    }
+}
+
+/// Counts symbols `jchuff.c` would emit for the block. `None` is a dummy block.
+fn count_block(block: Option<&ffi::JBLOCK>, last_dc: &mut i16, dc: &mut [u64; 256], ac: &mut [u64; 256]) {
+    let Some(block) = block else {
+        dc[0] += 1;
+        ac[0] += 1;
+        return;
+    };
+    dc[bit_length(i32::from(block[0]) - i32::from(*last_dc))] += 1;
+    *last_dc = block[0];
+    let mut run = 0;
+    for &i in &ZIGZAG_ORDER[1..] {
+        let coef = block[usize::from(i)];
+        if coef == 0 {
+            run += 1;
+            continue;
+        }
+        while run > 15 {
+            ac[0xF0] += 1;
+            run -= 16;
+        }
+        ac[(run << 4) + bit_length(coef.into())] += 1;
+        run = 0;
+    }
+    if run > 0 {
+        ac[0] += 1;
+    }
+}
+
+#[inline]
+fn bit_length(value: i32) -> usize {
+    (32 - value.unsigned_abs().leading_zeros()) as usize
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[test]
+fn validates() {
+    let dc_luma = HuffmanTable::new([0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0], &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]).unwrap();
+    assert_eq!(dc_luma, HuffmanTable::standard(HuffmanClass::Dc, false));
+    assert_eq!(162, HuffmanTable::standard(HuffmanClass::Ac, true).values().len());
+    // libjpeg's defaults are the standard tables
+    let comp = Compress::new(crate::ColorSpace::JCS_YCbCr);
+    for chroma in [false, true] {
+        let c_table = |ptrs: &[*mut JHUFF_TBL; 4]| HuffmanTable::from_c(unsafe { &*ptrs[usize::from(chroma)] });
+        assert_eq!(c_table(&comp.cinfo.dc_huff_tbl_ptrs), HuffmanTable::standard(HuffmanClass::Dc, chroma));
+        assert_eq!(c_table(&comp.cinfo.ac_huff_tbl_ptrs), HuffmanTable::standard(HuffmanClass::Ac, chroma));
+    }
+    assert!(HuffmanTable::new([1; 16], &[0; 15]).is_err());
+    // two 1-bit codes would leave nothing for the all-ones prefix
+    assert!(HuffmanTable::new([2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[0, 1]).is_err());
+    assert!(HuffmanTable::new([1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[0, 1]).is_ok());
+}
+
+#[test]
+fn shared_tables() {
+    let encode = |color_space: crate::ColorSpace, seed: usize, width: usize, height: usize| {
+        let mut comp = Compress::new(color_space);
+        comp.set_size(width, height);
+        comp.set_optimize_scans(false);
+        comp.set_optimize_coding(true);
+        let mut comp = comp.start_compress(Vec::new()).unwrap();
+        let pixels: Vec<u8> = (0..width * height * comp.components().len()).map(|i| ((i * seed) ^ (i / 77)) as u8).collect();
+        comp.write_scanlines(&pixels).unwrap();
+        comp.finish().unwrap()
+    };
+    let decode = |jpeg: &[u8]| -> Vec<[u8; 3]> { Decompress::new_mem(jpeg).unwrap().rgb().unwrap().read_scanlines().unwrap() };
+    let images = [
+        encode(crate::ColorSpace::JCS_RGB, 3, 64, 48),
+        encode(crate::ColorSpace::JCS_RGB, 11, 17, 33),
+        encode(crate::ColorSpace::JCS_RGB, 7, 37, 21),
+        encode(crate::ColorSpace::JCS_GRAYSCALE, 5, 29, 11),
+    ];
+
+    // with one image, the tables are the same as libjpeg's optimized ones (like jpegtran -optimize)
+    for image in &images {
+        let mut stats = HuffmanStats::new();
+        stats.add_image(image).unwrap();
+        let optimized = with_coefficients(image, |_, mut comp, coefs| unsafe {
+            comp.set_optimize_coding(true);
+            comp.start_compress_coefficients(Vec::new(), coefs).finish()
+        }).unwrap();
+        let dec = Decompress::new_mem(&optimized).unwrap();
+        for slot in 0..4 {
+            assert_eq!(dec.dc_huffman_table(slot), stats.dc_table(slot));
+            assert_eq!(dec.ac_huffman_table(slot), stats.ac_table(slot));
+        }
+    }
+
+    let mut stats = HuffmanStats::new();
+    for image in &images {
+        stats.add_image(image).unwrap();
+    }
+    for image in &images {
+        let encoded = stats.encode(image).unwrap();
+        assert_eq!(decode(image), decode(&encoded));
+        let abbreviated = stats.encode_abbreviated(image).unwrap();
+        assert!(abbreviated.len() < encoded.len());
+        assert!(!abbreviated.windows(2).any(|w| w == [0xFF, 0xC4]));
+    }
+    assert!(stats.tables_only().starts_with(&[0xFF, 0xD8, 0xFF, 0xC4]));
+}
@@ -0,0 +1,247 @@
+//! Implementations of the `image` crate's `ImageEncoder` and `ImageDecoder` traits. Requires the `image` feature.
+//!
+//! ```rust,no_run
+//! # fn main() -> image::ImageResult<()> {
+//! use mozjpeg::image_codec::{JpegDecoder, JpegEncoder};
+//! let file = std::io::BufReader::new(std::fs::File::open("in.jpg")?);
+//! let img = image::DynamicImage::from_decoder(JpegDecoder::new(file)?)?;
+//!
+//! let mut encoder = JpegEncoder::new(std::fs::File::create("out.jpg")?);
+//! encoder.settings_mut().quality = 80.;
+//! img.write_with_encoder(encoder)?;
+//! # Ok(()) }
+//! ```
+use crate::batch::{panic_to_error, EncodeSettings};
+use crate::colorspace::ColorSpace;
+use crate::compress::Compress;
+use crate::decompress::Decompress;
+use crate::exif::EXIF_HEADER;
+use crate::marker::Marker;
+use image::error::{DecodingError, EncodingError, ImageFormatHint, ParameterError, ParameterErrorKind, UnsupportedError, UnsupportedErrorKind};
+use image::{ColorType, ExtendedColorType, ImageDecoder, ImageEncoder, ImageError, ImageFormat, ImageResult};
+use std::borrow::Cow;
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+/// `image::ImageEncoder` that compresses with MozJPEG
+///
+/// Accepts `L8`, `La8`, `Rgb8` and `Rgba8` pixels. Alpha is discarded.
+pub struct JpegEncoder<W> {
+    writer: W,
+    settings: EncodeSettings,
+    icc_profile: Option<Vec<u8>>,
+    exif: Option<Vec<u8>>,
+}
+
+impl<W: Write> JpegEncoder<W> {
+    /// Uses `EncodeSettings::default()`
+    pub fn new(writer: W) -> Self {
+        Self::new_with_settings(writer, EncodeSettings::default())
+    }
+
+    /// Quality, progressive mode, trellis quantization, quantization tables, etc.
+    pub fn new_with_settings(writer: W, settings: EncodeSettings) -> Self {
+        Self { writer, settings, icc_profile: None, exif: None }
+    }
+
+    /// Change MozJPEG's options before calling `write_image`
+    pub fn settings_mut(&mut self) -> &mut EncodeSettings {
+        &mut self.settings
+    }
+
+    fn compress(self, pixels: &[u8], width: usize, height: usize, color_space: ColorSpace) -> io::Result<()> {
+        let mut comp = Compress::new(color_space);
+        self.settings.apply(&mut comp);
+        comp.set_size(width, height);
+        let mut started = comp.start_compress(self.writer)?;
+        if let Some(exif) = &self.exif {
+            started.write_marker(Marker::APP(1), &[EXIF_HEADER, exif].concat());
+        }
+        if let Some(icc) = &self.icc_profile {
+            started.write_icc_profile(icc);
+        }
+        started.write_scanlines(pixels)?;
+        started.finish()?;
+        Ok(())
+    }
+}
+
+impl<W: Write> ImageEncoder for JpegEncoder<W> {
+    fn write_image(self, buf: &[u8], width: u32, height: u32, color_type: ExtendedColorType) -> ImageResult<()> {
+        if u64::from(width) * u64::from(height) * u64::from(color_type.bits_per_pixel() / 8) != buf.len() as u64 {
+            return Err(ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::DimensionMismatch)));
+        }
+        let (pixels, color_space) = match color_type {
+            ExtendedColorType::L8 => (Cow::Borrowed(buf), ColorSpace::JCS_GRAYSCALE),
+            ExtendedColorType::La8 => (buf.chunks_exact(2).map(|la| la[0]).collect(), ColorSpace::JCS_GRAYSCALE),
+            ExtendedColorType::Rgb8 => (Cow::Borrowed(buf), ColorSpace::JCS_RGB),
+            ExtendedColorType::Rgba8 => (Cow::Borrowed(buf), ColorSpace::JCS_EXT_RGBA),
+            _ => return Err(ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+                ImageFormat::Jpeg.into(), UnsupportedErrorKind::Color(color_type),
+            ))),
+        };
+        panic::catch_unwind(AssertUnwindSafe(|| self.compress(&pixels, width as usize, height as usize, color_space)))
+            .unwrap_or_else(|payload| Err(panic_to_error(payload)))
+            .map_err(|e| ImageError::Encoding(EncodingError::new(ImageFormat::Jpeg.into(), e)))
+    }
+
+    fn set_icc_profile(&mut self, icc_profile: Vec<u8>) -> Result<(), UnsupportedError> {
+        self.icc_profile = Some(icc_profile).filter(|icc| !icc.is_empty());
+        Ok(())
+    }
+
+    /// TIFF-formatted EXIF data, without the `Exif\0\0` header
+    fn set_exif_metadata(&mut self, exif: Vec<u8>) -> Result<(), UnsupportedError> {
+        self.exif = Some(exif).filter(|exif| !exif.is_empty());
+        Ok(())
+    }
+}
+
+/// `image::ImageDecoder` that decompresses with MozJPEG
+///
+/// Outputs `L8` for grayscale images and `Rgb8` for everything else (CMYK is converted to RGB).
+/// Reports ICC profile, EXIF metadata, and orientation from EXIF. Images can be scaled down during decoding with `scale()`.
+pub struct JpegDecoder<R> {
+    dec: Decompress<R>,
+    width: u32,
+    height: u32,
+}
+
+impl<R: BufRead> JpegDecoder<R> {
+    /// Reads the JPEG header
+    pub fn new(reader: R) -> ImageResult<Self> {
+        catch_decoding(|| {
+            let mut dec = Decompress::with_markers(&[Marker::APP(1), Marker::APP(2)]).from_reader(reader)?;
+            let (width, height) = dec.output_size();
+            Ok(Self { dec, width: width as u32, height: height as u32 })
+        })
+    }
+
+    /// Rescales the output image by `numerator / 8` during decompression, which is much faster than decoding
+    /// the full image and resizing it. `numerator` must be between 1 and 16. `dimensions()` reports the scaled size.
+    #[track_caller]
+    pub fn scale(&mut self, numerator: u8) {
+        self.dec.scale(numerator);
+        let (width, height) = self.dec.output_size();
+        (self.width, self.height) = (width as u32, height as u32);
+    }
+
+    fn is_grayscale(&self) -> bool {
+        self.dec.color_space() == ColorSpace::JCS_GRAYSCALE
+    }
+
+    fn is_cmyk(&self) -> bool {
+        matches!(self.dec.color_space(), ColorSpace::JCS_CMYK | ColorSpace::JCS_YCCK)
+    }
+
+    fn decode_into(self, buf: &mut [u8]) -> io::Result<()> {
+        if self.is_grayscale() {
+            let mut started = self.dec.grayscale()?;
+            started.read_scanlines_into(buf)?;
+            started.finish()
+        } else if self.is_cmyk() {
+            let mut started = self.dec.to_colorspace(ColorSpace::JCS_CMYK)?;
+            let cmyk: Vec<[u8; 4]> = started.read_scanlines()?;
+            started.finish()?;
+            // libjpeg outputs Adobe's inverted CMYK
+            for (rgb, [c, m, y, k]) in buf.chunks_exact_mut(3).zip(cmyk) {
+                let k = u16::from(k);
+                rgb.copy_from_slice(&[c, m, y].map(|v| (u16::from(v) * k / 255) as u8));
+            }
+            Ok(())
+        } else {
+            let mut started = self.dec.rgb()?;
+            started.read_scanlines_into(buf)?;
+            started.finish()
+        }
+    }
+}
+
+impl<R: BufRead> ImageDecoder for JpegDecoder<R> {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn color_type(&self) -> ColorType {
+        if self.is_grayscale() { ColorType::L8 } else { ColorType::Rgb8 }
+    }
+
+    fn original_color_type(&self) -> ExtendedColorType {
+        if self.is_cmyk() { ExtendedColorType::Cmyk8 } else { self.color_type().into() }
+    }
+
+    fn icc_profile(&mut self) -> ImageResult<Option<Vec<u8>>> {
+        Ok(self.dec.icc_profile())
+    }
+
+    fn exif_metadata(&mut self) -> ImageResult<Option<Vec<u8>>> {
+        Ok(self.dec.markers()
+            .find(|m| m.marker == Marker::APP(1) && m.data.starts_with(EXIF_HEADER))
+            .map(|m| m.data[EXIF_HEADER.len()..].to_vec()))
+    }
+
+    fn read_image(self, buf: &mut [u8]) -> ImageResult<()> {
+        if buf.len() as u64 != self.total_bytes() {
+            return Err(ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::DimensionMismatch)));
+        }
+        catch_decoding(|| self.decode_into(buf))
+    }
+
+    fn read_image_boxed(self: Box<Self>, buf: &mut [u8]) -> ImageResult<()> {
+        (*self).read_image(buf)
+    }
+}
+
+/// libjpeg's fatal errors are panics
+fn catch_decoding<T>(f: impl FnOnce() -> io::Result<T>) -> ImageResult<T> {
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|payload| Err(panic_to_error(payload)))
+        .map_err(|e| ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(ImageFormat::Jpeg), e)))
+}
+
+#[test]
+fn image_roundtrip() {
+    use image::metadata::Orientation;
+
+    let (width, height) = (64u32, 48u32);
+    let pixels: Vec<u8> = (0..width * height).flat_map(|i| [(i % width * 4) as u8, (i / width * 5) as u8, 100, 255]).collect();
+    let icc = vec![7; 70000];
+    // big-endian TIFF with one IFD entry: orientation (0x112), SHORT, 1 value = 6
+    let exif = vec![b'M', b'M', 0, 42, 0, 0, 0, 8, 0, 1, 0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    let mut jpeg = Vec::new();
+    let mut encoder = JpegEncoder::new(&mut jpeg);
+    encoder.settings_mut().quality = 95.;
+    encoder.settings_mut().trellis_quantization = false;
+    encoder.set_icc_profile(icc.clone()).unwrap();
+    encoder.set_exif_metadata(exif.clone()).unwrap();
+    assert!(encoder.write_image(&pixels[1..], width, height, ExtendedColorType::Rgba8).is_err());
+    let mut encoder = JpegEncoder::new(&mut jpeg);
+    encoder.set_icc_profile(icc.clone()).unwrap();
+    encoder.set_exif_metadata(exif.clone()).unwrap();
+    encoder.write_image(&pixels, width, height, ExtendedColorType::Rgba8).unwrap();
+
+    let mut decoder = JpegDecoder::new(&jpeg[..]).unwrap();
+    assert_eq!((width, height), decoder.dimensions());
+    assert_eq!(ColorType::Rgb8, decoder.color_type());
+    assert_eq!(Some(icc), decoder.icc_profile().unwrap());
+    assert_eq!(Some(exif), decoder.exif_metadata().unwrap());
+    assert_eq!(Orientation::Rotate90, decoder.orientation().unwrap());
+    let img = image::DynamicImage::from_decoder(decoder).unwrap().into_rgb8();
+    let px = img.get_pixel(40, 20).0;
+    assert!(px.iter().zip([160, 100, 100]).all(|(&a, b)| a.abs_diff(b) < 8), "{px:?}");
+
+    let mut decoder = JpegDecoder::new(&jpeg[..]).unwrap();
+    decoder.scale(4);
+    assert_eq!((width / 2, height / 2), decoder.dimensions());
+    let mut buf = vec![0; decoder.total_bytes() as usize];
+    decoder.read_image(&mut buf).unwrap();
+
+    let mut gray = Vec::new();
+    JpegEncoder::new(&mut gray).write_image(&[128; 16 * 2], 4, 4, ExtendedColorType::La8).unwrap();
+    let decoder = JpegDecoder::new(&gray[..]).unwrap();
+    assert_eq!(ColorType::L8, decoder.color_type());
+    assert_eq!((4, 4), decoder.dimensions());
+
+    assert!(JpegDecoder::new(&jpeg[..20]).and_then(|d| d.read_image(&mut [0; 64 * 48 * 3])).is_err());
+}
@@ -0,0 +1,589 @@
+//! Non-blocking decoding of data that arrives in chunks. See `IncrementalDecompress`.
+use bytemuck::Pod;
+use crate::colorspace::{ColorSpace, ColorSpaceExt};
+use crate::component::CompInfo;
+use crate::decompress::{pixel_items_per_pixel, DctMethod, InputStatus, MarkerIter};
+use crate::errormgr::ErrorMgr;
+use crate::ffi;
+use crate::ffi::jpeg_decompress_struct;
+use crate::marker::Marker;
+use crate::pushsrc::PushSourceMgr;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::mem::MaybeUninit;
+use std::ptr::addr_of_mut;
+use std::slice;
+
+/// Result of an operation that may need to wait for more input
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Progress<T> {
+    /// The operation has completed
+    Ready(T),
+    /// libjpeg has consumed all pushed data. Call `push()` and retry.
+    NeedMoreData,
+}
+
+impl<T> Progress<T> {
+    #[inline]
+    #[must_use]
+    pub fn is_ready(&self) -> bool {
+        matches!(self, Self::Ready(_))
+    }
+
+    /// `None` if more data is needed
+    #[inline]
+    pub fn ready(self) -> Option<T> {
+        match self {
+            Self::Ready(t) => Some(t),
+            Self::NeedMoreData => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum State {
+    Header,
+    HeaderRead,
+    Starting,
+    /// Buffered-image mode, between output passes
+    BufferedIdle,
+    StartingOutput,
+    Scanlines,
+    FinishingOutput,
+    Finishing,
+    Done,
+}
+
+/// Push-style decoder that never blocks waiting for input
+///
+/// Feed it bytes with `push()` as they arrive, and call the state-changing methods
+/// (`read_header`, `start_decompress`, `read_scanlines_into`, `finish`) in order.
+/// When libjpeg runs out of data they return `Progress::NeedMoreData`, and can be called again
+/// after more data has been pushed.
+///
+/// Progressive files can't be output until all their scans have arrived,
+/// so for them `start_decompress` will keep asking for data until the end of the file,
+/// unless `buffered_image(true)` is used to display each scan as it arrives.
+///
+/// ```rust
+/// # use mozjpeg::*;
+/// # use mozjpeg::incremental::Progress;
+/// # fn t(chunks: &[&[u8]]) -> std::io::Result<()> {
+/// let mut dec = Decompress::builder().incremental();
+/// let mut chunks = chunks.iter();
+/// while dec.read_header()? == Progress::NeedMoreData {
+///     dec.push(chunks.next().ok_or(std::io::ErrorKind::UnexpectedEof)?);
+/// }
+/// dec.to_colorspace(ColorSpace::JCS_RGB)?;
+/// # Ok(()) }
+/// ```
+pub struct IncrementalDecompress {
+    cinfo: jpeg_decompress_struct,
+    err_mgr: Box<ErrorMgr>,
+    src_mgr: PushSourceMgr,
+    state: State,
+}
+
+impl IncrementalDecompress {
+    pub(crate) fn from_builder(err_mgr: Box<ErrorMgr>, save_markers: &[Marker]) -> Self {
+        unsafe {
+            let mut newself = Self {
+                cinfo: mem::zeroed(),
+                err_mgr,
+                src_mgr: PushSourceMgr::new(),
+                state: State::Header,
+            };
+            newself.cinfo.common.err = addr_of_mut!(*newself.err_mgr);
+            ffi::jpeg_create_decompress(&mut newself.cinfo);
+            newself.cinfo.src = newself.src_mgr.iface_c_ptr();
+            for &marker in save_markers {
+                ffi::jpeg_save_markers(&mut newself.cinfo, marker.into(), 0xFFFF);
+            }
+            newself
+        }
+    }
+
+    /// Append a chunk of the JPEG file
+    ///
+    /// The data is copied, and kept only until libjpeg consumes it.
+    #[inline]
+    pub fn push(&mut self, data: &[u8]) {
+        self.src_mgr.push(data);
+    }
+
+    /// Mark that no more data will be pushed
+    ///
+    /// If the file turns out to be truncated, it will be decoded as far as possible
+    /// (like the blocking decoder does) instead of asking for more data.
+    #[inline]
+    pub fn end_of_input(&mut self) {
+        self.src_mgr.set_end_of_input();
+    }
+
+    /// Number of pushed bytes that libjpeg hasn't consumed yet
+    #[inline]
+    pub fn bytes_in_buffer(&mut self) -> usize {
+        self.src_mgr.bytes_in_buffer()
+    }
+
+    fn need_more_data<T>(&mut self) -> io::Result<Progress<T>> {
+        if self.src_mgr.is_end_of_input() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Progress::NeedMoreData)
+    }
+
+    #[cold]
+    fn wrong_state<T>(&self, what: &str) -> io::Result<T> {
+        Err(io::Error::new(io::ErrorKind::Other, format!("can't {what} in {:?} state", self.state)))
+    }
+
+    /// Reads markers up to the start of image data
+    ///
+    /// After it's ready, size, color space and markers can be read, and output options set.
+    pub fn read_header(&mut self) -> io::Result<Progress<()>> {
+        match self.state {
+            State::Header => {},
+            State::Done => return self.wrong_state("read header"),
+            _ => return Ok(Progress::Ready(())),
+        }
+        // require_image = 0 allows handling this error without unwinding
+        match unsafe { ffi::jpeg_read_header(&mut self.cinfo, 0) } {
+            0 => self.need_more_data(),
+            1 => {
+                self.state = State::HeaderRead;
+                Ok(Progress::Ready(()))
+            },
+            _ => Err(io::Error::new(io::ErrorKind::Other, "no image in the JPEG file")),
+        }
+    }
+
+    /// Set output color space. Must be called between `read_header` and `start_decompress`.
+    pub fn to_colorspace(&mut self, colorspace: ColorSpace) -> io::Result<()> {
+        if self.state != State::HeaderRead {
+            return self.wrong_state("set color space");
+        }
+        self.cinfo.out_color_space = colorspace;
+        Ok(())
+    }
+
+    /// Rescales the output image by `numerator / 8` during decompression.
+    /// `numerator` must be between 1 and 16.
+    #[track_caller]
+    #[inline]
+    pub fn scale(&mut self, numerator: u8) {
+        assert!(1 <= numerator && numerator <= 16, "numerator must be between 1 and 16");
+        self.cinfo.scale_num = numerator.into();
+        self.cinfo.scale_denom = 8;
+    }
+
+    /// Selects the algorithm used for the DCT step.
+    pub fn dct_method(&mut self, method: DctMethod) {
+        self.cinfo.dct_method = method.into();
+    }
+
+    /// See `Decompress::do_fancy_upsampling`
+    pub fn do_fancy_upsampling(&mut self, value: bool) {
+        self.cinfo.do_fancy_upsampling = ffi::boolean::from(value);
+    }
+
+    /// See `Decompress::buffered_image`. Must be called between `read_header` and `start_decompress`.
+    ///
+    /// In buffered-image mode scanlines are read in output passes between `start_output` and `finish_output`,
+    /// and input is read with `consume_input`.
+    pub fn buffered_image(&mut self, value: bool) -> io::Result<()> {
+        if self.state != State::HeaderRead {
+            return self.wrong_state("set buffered image mode");
+        }
+        self.cinfo.buffered_image = ffi::boolean::from(value);
+        Ok(())
+    }
+
+    fn is_buffered_image(&self) -> bool {
+        0 != self.cinfo.buffered_image
+    }
+
+    /// Prepares for reading scanlines
+    ///
+    /// For progressive files this reads all scans, so it will need the whole file,
+    /// except in buffered-image mode, which only needs the header.
+    pub fn start_decompress(&mut self) -> io::Result<Progress<()>> {
+        match self.state {
+            State::HeaderRead | State::Starting => {},
+            State::Scanlines | State::BufferedIdle => return Ok(Progress::Ready(())),
+            _ => return self.wrong_state("start decompression"),
+        }
+        self.state = State::Starting;
+        if 0 != unsafe { ffi::jpeg_start_decompress(&mut self.cinfo) } {
+            self.state = if self.is_buffered_image() { State::BufferedIdle } else { State::Scanlines };
+            Ok(Progress::Ready(()))
+        } else {
+            self.need_more_data()
+        }
+    }
+
+    /// Buffered-image mode only. Reads as much of the pushed data as possible without producing output.
+    ///
+    /// Returns `ReachedEoi` once the whole file has been read.
+    pub fn consume_input(&mut self) -> io::Result<Progress<InputStatus>> {
+        if !self.is_buffered_image() || !matches!(self.state, State::BufferedIdle | State::Scanlines) {
+            return self.wrong_state("consume input");
+        }
+        match InputStatus::from_c(unsafe { ffi::jpeg_consume_input(&mut self.cinfo) }) {
+            InputStatus::Suspended => self.need_more_data(),
+            status => Ok(Progress::Ready(status)),
+        }
+    }
+
+    /// Buffered-image mode only. Starts an output pass showing scans up to `scan_number`,
+    /// usually `input_scan_number()`. Then read scanlines and call `finish_output`.
+    pub fn start_output(&mut self, scan_number: usize) -> io::Result<Progress<()>> {
+        if !self.is_buffered_image() || !matches!(self.state, State::BufferedIdle | State::StartingOutput) {
+            return self.wrong_state("start output");
+        }
+        self.state = State::StartingOutput;
+        if 0 != unsafe { ffi::jpeg_start_output(&mut self.cinfo, scan_number.min(i32::MAX as usize) as _) } {
+            self.state = State::Scanlines;
+            Ok(Progress::Ready(()))
+        } else {
+            self.need_more_data()
+        }
+    }
+
+    /// Buffered-image mode only. Ends the output pass, even if not all scanlines have been read.
+    pub fn finish_output(&mut self) -> io::Result<Progress<()>> {
+        if !self.is_buffered_image() || !matches!(self.state, State::Scanlines | State::FinishingOutput) {
+            return self.wrong_state("finish output");
+        }
+        self.state = State::FinishingOutput;
+        if 0 != unsafe { ffi::jpeg_finish_output(&mut self.cinfo) } {
+            self.state = State::BufferedIdle;
+            Ok(Progress::Ready(()))
+        } else {
+            self.need_more_data()
+        }
+    }
+
+    /// `true` if all of the file has been read
+    #[inline]
+    #[must_use]
+    pub fn input_complete(&self) -> bool {
+        0 != unsafe { ffi::jpeg_input_complete(&self.cinfo) }
+    }
+
+    /// `true` if the file is progressive (or multi-scan). Available after `read_header`.
+    #[inline]
+    #[must_use]
+    pub fn has_multiple_scans(&self) -> bool {
+        self.state != State::Header && 0 != unsafe { ffi::jpeg_has_multiple_scans(&self.cinfo) }
+    }
+
+    /// Number of the scan being read from the input, starting at 1
+    #[inline]
+    #[must_use]
+    pub fn input_scan_number(&self) -> usize {
+        self.cinfo.input_scan_number as usize
+    }
+
+    /// Number of the scan shown in the current output pass
+    #[inline]
+    #[must_use]
+    pub fn output_scan_number(&self) -> usize {
+        self.cinfo.output_scan_number as usize
+    }
+
+    /// Decodes as many rows as are available and fit in `dest`
+    ///
+    /// Returns number of rows read, which is less than fits in `dest` if more data is needed
+    /// or the image has ended. Returns `NeedMoreData` if not even one row could be decoded.
+    ///
+    /// Pixels can be `u8` or any plain old data type of the size of a pixel, like in `DecompressStarted::read_scanlines_into`.
+    pub fn read_scanlines_into<T: Pod>(&mut self, dest: &mut [T]) -> io::Result<Progress<usize>> {
+        let dest_uninit = unsafe {
+            mem::transmute::<&mut [T], &mut [MaybeUninit<T>]>(dest)
+        };
+        self.read_scanlines_into_uninit(dest_uninit)
+    }
+
+    /// Same as `read_scanlines_into`, but for uninitialized memory
+    pub fn read_scanlines_into_uninit<T: Pod>(&mut self, dest: &mut [MaybeUninit<T>]) -> io::Result<Progress<usize>> {
+        if self.state != State::Scanlines {
+            return self.wrong_state("read scanlines");
+        }
+        let line_width = self.width() * pixel_items_per_pixel::<T>(self.color_space().num_components())?;
+        if line_width == 0 || dest.len() % line_width != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("destination slice length must be a multiple of {line_width} pixels, got {}", dest.len()),
+            ));
+        }
+        let mut rows_read = 0;
+        for row in dest.chunks_exact_mut(line_width) {
+            if self.is_scanlines_complete() {
+                break;
+            }
+            let mut row_ptr = row.as_mut_ptr().cast::<ffi::JSAMPLE>();
+            let rows = addr_of_mut!(row_ptr);
+            if 0 == unsafe { ffi::jpeg_read_scanlines(&mut self.cinfo, rows, 1) } {
+                break;
+            }
+            rows_read += 1;
+        }
+        if rows_read == 0 && !self.is_scanlines_complete() {
+            return self.need_more_data();
+        }
+        Ok(Progress::Ready(rows_read))
+    }
+
+    /// Decodes all rows that are available now
+    ///
+    /// Returns an empty `Vec` when all rows have been read.
+    pub fn read_scanlines<T: Pod>(&mut self) -> io::Result<Progress<Vec<T>>> {
+        if self.state != State::Scanlines {
+            return self.wrong_state("read scanlines");
+        }
+        let line_width = self.width() * pixel_items_per_pixel::<T>(self.color_space().num_components())?;
+        let rows_left = self.height() - self.output_scanline();
+        let mut image_dst: Vec<T> = Vec::new();
+        image_dst.try_reserve_exact(rows_left * line_width).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        let rows = match self.read_scanlines_into_uninit(&mut image_dst.spare_capacity_mut()[..rows_left * line_width])? {
+            Progress::Ready(rows) => rows,
+            Progress::NeedMoreData => return Ok(Progress::NeedMoreData),
+        };
+        unsafe { image_dst.set_len(rows * line_width); }
+        Ok(Progress::Ready(image_dst))
+    }
+
+    /// Consumes the rest of the file, up to the EOI marker.
+    ///
+    /// All scanlines must have been read. In buffered-image mode, `finish_output` must be called first.
+    pub fn finish(&mut self) -> io::Result<Progress<()>> {
+        match self.state {
+            State::Scanlines if self.is_scanlines_complete() && !self.is_buffered_image() => {},
+            State::BufferedIdle => {},
+            State::Finishing => {},
+            State::Done => return Ok(Progress::Ready(())),
+            _ => return self.wrong_state("finish"),
+        }
+        self.state = State::Finishing;
+        if 0 != unsafe { ffi::jpeg_finish_decompress(&mut self.cinfo) } {
+            self.state = State::Done;
+            Ok(Progress::Ready(()))
+        } else {
+            self.need_more_data()
+        }
+    }
+
+    /// Number of rows read so far
+    #[inline]
+    #[must_use]
+    pub fn output_scanline(&self) -> usize {
+        self.cinfo.output_scanline as usize
+    }
+
+    /// All rows have been read, and `finish()` can be called
+    #[inline]
+    #[must_use]
+    pub fn is_scanlines_complete(&self) -> bool {
+        self.state == State::Scanlines && self.cinfo.output_scanline >= self.cinfo.output_height
+    }
+
+    /// Width of the image. Available after `read_header`, and after `start_decompress` it takes scaling into account.
+    #[inline]
+    #[must_use]
+    pub fn width(&self) -> usize {
+        match self.state {
+            State::Header | State::HeaderRead | State::Starting => self.cinfo.image_width as usize,
+            _ => self.cinfo.output_width as usize,
+        }
+    }
+
+    /// Height of the image. Available after `read_header`, and after `start_decompress` it takes scaling into account.
+    #[inline]
+    #[must_use]
+    pub fn height(&self) -> usize {
+        match self.state {
+            State::Header | State::HeaderRead | State::Starting => self.cinfo.image_height as usize,
+            _ => self.cinfo.output_height as usize,
+        }
+    }
+
+    /// Color space of the output if decompression has started, otherwise of the JPEG file
+    #[inline]
+    #[must_use]
+    pub fn color_space(&self) -> ColorSpace {
+        match self.state {
+            State::Header | State::HeaderRead | State::Starting => self.cinfo.jpeg_color_space,
+            _ => self.cinfo.out_color_space,
+        }
+    }
+
+    /// Available after `read_header`
+    #[inline]
+    #[must_use]
+    pub fn components(&self) -> &[CompInfo] {
+        if self.cinfo.comp_info.is_null() {
+            return &[];
+        }
+        unsafe {
+            slice::from_raw_parts(self.cinfo.comp_info, self.cinfo.num_components as usize)
+        }
+    }
+
+    /// Markers are available after `read_header`, only if you enable them via `with_markers()`
+    #[inline]
+    #[must_use]
+    pub fn markers(&self) -> MarkerIter<'_> {
+        MarkerIter {
+            marker_list: self.cinfo.marker_list,
+            _references: PhantomData,
+        }
+    }
+}
+
+impl Drop for IncrementalDecompress {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::jpeg_destroy_decompress(&mut self.cinfo);
+        }
+    }
+}
+
+#[cfg(test)]
+fn decode_in_chunks(data: &[u8], chunk_size: usize) -> Vec<[u8; 3]> {
+    let mut chunks = data.chunks(chunk_size);
+    let mut dec = crate::Decompress::builder().with_markers(crate::ALL_MARKERS).incremental();
+    while dec.read_header().unwrap() == Progress::NeedMoreData {
+        dec.push(chunks.next().unwrap());
+    }
+    dec.to_colorspace(ColorSpace::JCS_RGB).unwrap();
+    while dec.start_decompress().unwrap() == Progress::NeedMoreData {
+        dec.push(chunks.next().unwrap());
+    }
+    let mut pixels = Vec::new();
+    while !dec.is_scanlines_complete() {
+        match dec.read_scanlines::<[u8; 3]>().unwrap() {
+            Progress::Ready(rows) => pixels.extend(rows),
+            Progress::NeedMoreData => match chunks.next() {
+                Some(chunk) => dec.push(chunk),
+                None => dec.end_of_input(),
+            },
+        }
+    }
+    while dec.finish().unwrap() == Progress::NeedMoreData {
+        match chunks.next() {
+            Some(chunk) => dec.push(chunk),
+            None => dec.end_of_input(),
+        }
+    }
+    assert_eq!(pixels.len(), dec.width() * dec.height());
+    pixels
+}
+
+#[test]
+fn chunked_baseline() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let expected: Vec<[u8; 3]> = crate::Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines().unwrap();
+    for chunk_size in [1, 7, 100, 1000, data.len()] {
+        assert_eq!(expected, decode_in_chunks(&data, chunk_size));
+    }
+}
+
+#[cfg(test)]
+fn encode_test_image(progressive: bool) -> Vec<u8> {
+    let mut comp = crate::Compress::new(ColorSpace::JCS_RGB);
+    comp.set_size(128, 96);
+    if progressive {
+        comp.set_progressive_mode();
+    } else {
+        comp.set_fastest_defaults();
+    }
+    let mut comp = comp.start_compress(Vec::new()).unwrap();
+    let pixels: Vec<u8> = (0..128 * 96 * 3).map(|i| ((i * 7) ^ (i / 300)) as u8).collect();
+    comp.write_scanlines(&pixels).unwrap();
+    comp.finish().unwrap()
+}
+
+#[test]
+fn chunked_progressive() {
+    let data = encode_test_image(true);
+    let expected: Vec<[u8; 3]> = crate::Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines().unwrap();
+    for chunk_size in [1, 13, 500] {
+        assert_eq!(expected, decode_in_chunks(&data, chunk_size));
+    }
+}
+
+#[test]
+fn truncated() {
+    let data = encode_test_image(false);
+    let mut dec = crate::Decompress::builder().incremental();
+    dec.push(&data[..data.len() / 2]);
+    assert!(dec.read_header().unwrap().is_ready());
+    assert!(dec.start_decompress().unwrap().is_ready());
+    let mut buf = vec![0u8; dec.width() * dec.height() * 3];
+    let Progress::Ready(rows) = dec.read_scanlines_into(&mut buf).unwrap() else { panic!() };
+    assert!(rows < dec.height());
+    assert_eq!(Progress::NeedMoreData, dec.read_scanlines_into(&mut buf[rows * 128 * 3..]).unwrap());
+    dec.end_of_input();
+    let Progress::Ready(more_rows) = dec.read_scanlines_into(&mut buf[rows * 128 * 3..]).unwrap() else { panic!() };
+    assert_eq!(dec.height(), rows + more_rows);
+    assert!(dec.finish().unwrap().is_ready());
+}
+
+#[test]
+fn buffered_progressive_passes() {
+    let data = encode_test_image(true);
+    let expected: Vec<[u8; 3]> = crate::Decompress::new_mem(&data).unwrap().rgb().unwrap().read_scanlines().unwrap();
+
+    let mut chunks = data.chunks(200);
+    let mut dec = crate::Decompress::builder().incremental();
+    assert!(dec.buffered_image(true).is_err());
+    while dec.read_header().unwrap() == Progress::NeedMoreData {
+        dec.push(chunks.next().unwrap());
+    }
+    dec.buffered_image(true).unwrap();
+    assert!(dec.has_multiple_scans());
+    dec.to_colorspace(ColorSpace::JCS_RGB).unwrap();
+    assert!(dec.start_decompress().unwrap().is_ready());
+
+    let mut passes = 0;
+    let mut last_pass;
+    loop {
+        let done = loop {
+            match dec.consume_input().unwrap() {
+                Progress::Ready(InputStatus::ReachedEoi) => break true,
+                Progress::Ready(_) => {},
+                Progress::NeedMoreData => match chunks.next() {
+                    Some(chunk) => dec.push(chunk),
+                    None => dec.end_of_input(),
+                },
+            }
+            if dec.input_scan_number() > dec.output_scan_number() + 1 {
+                break false;
+            }
+        };
+        assert!(dec.start_output(dec.input_scan_number()).unwrap().is_ready());
+        let mut pixels = Vec::new();
+        while !dec.is_scanlines_complete() {
+            match dec.read_scanlines::<[u8; 3]>().unwrap() {
+                Progress::Ready(rows) => pixels.extend(rows),
+                Progress::NeedMoreData => match chunks.next() {
+                    Some(chunk) => dec.push(chunk),
+                    None => dec.end_of_input(),
+                },
+            }
+        }
+        while dec.finish_output().unwrap() == Progress::NeedMoreData {
+            dec.push(chunks.next().unwrap());
+        }
+        passes += 1;
+        last_pass = pixels;
+        if done && dec.input_complete() {
+            break;
+        }
+    }
+    assert!(passes > 1);
+    assert!(dec.finish().unwrap().is_ready());
+    assert_eq!(expected, last_pass);
+}
@@ -0,0 +1,278 @@
+//! Structure of a JPEG file, for inspecting how it has been encoded. See `JpegInfo`.
+use crate::colorspace::ColorSpace;
+use crate::component::CompInfoExt;
+use crate::decompress::Decompress;
+use crate::ffi;
+use crate::huffman::{HuffmanClass, HuffmanTable};
+use crate::marker::Marker;
+use crate::parse::{self, Segments};
+use crate::qtable::{QTable, QualityEstimate};
+use std::io;
+
+/// Report about the image and how it has been encoded
+#[derive(Debug, Clone)]
+pub struct JpegInfo {
+    pub width: usize,
+    pub height: usize,
+    /// Color space of the JPEG data (not the output)
+    pub color_space: ColorSpace,
+    pub components: Vec<ComponentInfo>,
+    /// Quantization tables by slot number (0-3). Coefficients are in natural (row-major) order.
+    pub qtables: [Option<QTable>; 4],
+    /// `true` for progressive files, and sequential files with more than one scan
+    pub multiple_scans: bool,
+    /// APPn and COM markers saved by the `Decompress`, with sizes of their data
+    pub markers: Vec<(Marker, usize)>,
+    /// Details that are available only from the whole file. `None` when created by `Decompress::info()`.
+    pub layout: Option<Layout>,
+}
+
+/// A color channel of the frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComponentInfo {
+    pub id: u8,
+    /// h,v sampling factors (1..4), same as `CompInfoExt::sampling()`
+    pub sampling: (u8, u8),
+    /// Index into `JpegInfo::qtables`
+    pub qtable_slot: u8,
+}
+
+/// Contents of the file, in the order of markers
+#[derive(Debug, Clone)]
+pub struct Layout {
+    pub mode: CodingMode,
+    /// Arithmetic coding instead of Huffman
+    pub arithmetic: bool,
+    /// MCUs between restart markers, 0 if there are none. From the last DRI marker before the first scan.
+    pub restart_interval: u16,
+    /// Every Huffman table definition, including redefinitions between scans
+    pub huffman_tables: Vec<HuffmanTableInfo>,
+    pub scans: Vec<ScanInfo>,
+    /// Every marker segment, including SOI and EOI
+    pub segments: Vec<SegmentInfo>,
+    /// `false` if the file ends before EOI
+    pub complete: bool,
+    /// Number of bytes after the EOI marker
+    pub trailing_bytes: usize,
+}
+
+/// Type of the start of frame marker
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CodingMode {
+    /// SOF0
+    Baseline,
+    /// Extended sequential DCT, e.g. with 12-bit samples or more Huffman tables
+    Sequential,
+    Progressive,
+    Lossless,
+    /// Differential (hierarchical) frames
+    Hierarchical,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HuffmanTableInfo {
+    pub class: HuffmanClass,
+    /// Slot number (0-3)
+    pub slot: u8,
+    /// Offset of the DHT marker that defined it
+    pub offset: usize,
+    pub table: HuffmanTable,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanInfo {
+    /// Offset of the SOS marker
+    pub offset: usize,
+    /// Length of the SOS segment and the entropy-coded data after it
+    pub length: usize,
+    pub components: Vec<ScanComponent>,
+    /// First and last DCT coefficient in zigzag order. Always 0..63 in sequential files.
+    pub spectral_selection: (u8, u8),
+    /// Successive approximation bit position high and low. Both 0 unless progressive refinement is used.
+    pub successive_approximation: (u8, u8),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanComponent {
+    pub id: u8,
+    pub dc_table: u8,
+    pub ac_table: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentInfo {
+    /// Marker code (the byte after `0xFF`)
+    pub marker: u8,
+    /// Offset of the marker
+    pub offset: usize,
+    /// Size of the segment including the marker. For SOS it excludes the entropy-coded data.
+    pub length: usize,
+}
+
+impl SegmentInfo {
+    /// Standard abbreviation of the marker, e.g. `SOF2` or `APP1`
+    #[must_use]
+    pub fn name(&self) -> String {
+        match self.marker {
+            0xC4 => "DHT".into(),
+            0xC8 => "JPG".into(),
+            0xCC => "DAC".into(),
+            m @ 0xC0..=0xCF => format!("SOF{}", m - 0xC0),
+            m @ 0xD0..=0xD7 => format!("RST{}", m - 0xD0),
+            0xD8 => "SOI".into(),
+            0xD9 => "EOI".into(),
+            0xDA => "SOS".into(),
+            0xDB => "DQT".into(),
+            0xDC => "DNL".into(),
+            0xDD => "DRI".into(),
+            m @ 0xE0..=0xEF => format!("APP{}", m - 0xE0),
+            0xFE => "COM".into(),
+            m => format!("0x{m:02X}"),
+        }
+    }
+}
+
+impl JpegInfo {
+    /// Reads the header with libjpeg, and walks all markers of the file to find its scans and tables
+    pub fn from_mem(data: &[u8]) -> io::Result<Self> {
+        let mut info = Decompress::with_markers(crate::ALL_MARKERS).from_mem(data)?.info();
+        info.layout = Some(Layout::from_mem(data)?);
+        Ok(info)
+    }
+
+    /// Guesses the quality setting from quantization tables of the first two components
+    #[must_use]
+    pub fn estimate_quality(&self) -> Option<QualityEstimate> {
+        let table = |i: usize| self.qtables.get(usize::from(self.components.get(i)?.qtable_slot))?.as_ref();
+        Some(QualityEstimate::from_tables(table(0)?, table(1)))
+    }
+}
+
+impl Layout {
+    fn from_mem(data: &[u8]) -> io::Result<Self> {
+        let mut layout = Self {
+            mode: CodingMode::Baseline,
+            arithmetic: false,
+            restart_interval: 0,
+            huffman_tables: Vec::new(),
+            scans: Vec::new(),
+            segments: Vec::new(),
+            complete: false,
+            trailing_bytes: 0,
+        };
+        let mut frame = None;
+        for segment in Segments::new(data) {
+            let p = segment.payload;
+            layout.segments.push(SegmentInfo { marker: segment.marker, offset: segment.start, length: segment.end() - segment.start });
+            match segment.marker {
+                m if parse::is_sof(m) => frame = Some(m),
+                0xC4 => layout.huffman_tables.extend(parse_dht(p, segment.start)?),
+                parse::DRI if layout.scans.is_empty() && p.len() >= 2 => {
+                    layout.restart_interval = u16::from_be_bytes([p[0], p[1]]);
+                },
+                parse::SOS => {
+                    let count = usize::from(*p.first().ok_or_else(|| invalid("empty SOS"))?);
+                    let (components, params) = (p.get(1..1 + count * 2), p.get(1 + count * 2..));
+                    let (Some(components), Some(&[ss, se, a])) = (components, params) else { return Err(invalid("invalid SOS length")) };
+                    layout.scans.push(ScanInfo {
+                        offset: segment.start,
+                        length: parse::entropy_data_end(data, segment.end()) - segment.start,
+                        components: components.chunks_exact(2)
+                            .map(|c| ScanComponent { id: c[0], dc_table: c[1] >> 4, ac_table: c[1] & 15 })
+                            .collect(),
+                        spectral_selection: (ss, se),
+                        successive_approximation: (a >> 4, a & 15),
+                    });
+                },
+                parse::EOI => {
+                    layout.complete = true;
+                    layout.trailing_bytes = data.len() - segment.end();
+                },
+                _ => {},
+            }
+        }
+        let frame = frame.ok_or_else(|| invalid("no SOF marker"))?;
+        layout.arithmetic = frame >= 0xC8;
+        layout.mode = match frame & 7 {
+            0 if frame == 0xC0 => CodingMode::Baseline,
+            0 | 1 => CodingMode::Sequential,
+            2 => CodingMode::Progressive,
+            3 => CodingMode::Lossless,
+            _ => CodingMode::Hierarchical,
+        };
+        Ok(layout)
+    }
+}
+
+/// A DHT segment can define several tables
+fn parse_dht(mut p: &[u8], offset: usize) -> io::Result<Vec<HuffmanTableInfo>> {
+    let mut tables = Vec::new();
+    while let Some((&class_slot, rest)) = p.split_first() {
+        let counts = rest.get(..16).ok_or_else(|| invalid("truncated DHT"))?;
+        let num_values = counts.iter().map(|&c| usize::from(c)).sum::<usize>();
+        let values = rest.get(16..16 + num_values).ok_or_else(|| invalid("truncated DHT"))?;
+        tables.push(HuffmanTableInfo {
+            class: if class_slot >> 4 == 0 { HuffmanClass::Dc } else { HuffmanClass::Ac },
+            slot: class_slot & 15,
+            offset,
+            table: HuffmanTable::new(counts.try_into().unwrap(), values)?,
+        });
+        p = &rest[16 + num_values..];
+    }
+    Ok(tables)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl<R> Decompress<R> {
+    /// Report about the image from its header, as parsed by libjpeg.
+    ///
+    /// Scans, Huffman tables and other details of the file's structure need the whole file. Use `JpegInfo::from_mem()` for these.
+    #[must_use]
+    pub fn info(&self) -> JpegInfo {
+        let mut qtables = [None, None, None, None];
+        for (out, &tbl) in qtables.iter_mut().zip(&self.cinfo.quant_tbl_ptrs) {
+            *out = unsafe { tbl.as_ref() }.map(|tbl| QTable { coeffs: tbl.quantval.map(u32::from) });
+        }
+        JpegInfo {
+            width: self.width(),
+            height: self.height(),
+            color_space: self.color_space(),
+            components: self.components().iter().map(|c| ComponentInfo {
+                id: c.component_id as u8,
+                sampling: c.sampling(),
+                qtable_slot: c.quant_tbl_no as u8,
+            }).collect(),
+            qtables,
+            multiple_scans: 0 != unsafe { ffi::jpeg_has_multiple_scans(&self.cinfo) },
+            markers: self.markers().map(|m| (m.marker, m.data.len())).collect(),
+            layout: None,
+        }
+    }
+}
+
+#[test]
+fn reports_layout() {
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let info = JpegInfo::from_mem(&data).unwrap();
+    assert_eq!((45, 30), (info.width, info.height));
+    assert_eq!(3, info.components.len());
+    assert!(info.qtables[info.components[0].qtable_slot as usize].is_some());
+
+    let layout = info.layout.unwrap();
+    assert!(layout.complete);
+    assert_eq!(0, layout.trailing_bytes);
+    assert_eq!(info.multiple_scans, layout.scans.len() > 1);
+    assert_eq!(layout.mode == CodingMode::Progressive, info.multiple_scans);
+    assert!(!layout.huffman_tables.is_empty());
+    assert_eq!("SOI", layout.segments[0].name());
+    assert_eq!("EOI", layout.segments.last().unwrap().name());
+    let last_scan = layout.scans.last().unwrap();
+    assert_eq!(layout.segments.last().unwrap().offset, last_scan.offset + last_scan.length);
+
+    let trailer = std::fs::read("tests/trailer.jpg").unwrap();
+    let layout = JpegInfo::from_mem(&trailer).unwrap().layout.unwrap();
+    assert!(layout.trailing_bytes > 0);
+}
@@ -10,10 +10,14 @@ pub use crate::colorspace::ColorSpaceExt;
 pub use crate::component::CompInfo;
 pub use crate::component::CompInfoExt;
 pub use crate::compress::Compress;
+pub use crate::compress::RowSource;
 pub use crate::compress::ScanMode;
-pub use crate::decompress::{DctMethod, Format};
-pub use crate::decompress::{Decompress, ALL_MARKERS, NO_MARKERS};
+pub use crate::decompress::{DctMethod, Format, InputStatus};
+pub use crate::decompress::{Decompress, DecompressSequence, ReusableDecompress, ALL_MARKERS, NO_MARKERS};
 pub use crate::density::{PixelDensity, PixelDensityUnit};
+pub use crate::incremental::IncrementalDecompress;
+pub use crate::info::JpegInfo;
+pub use crate::parallel::{ParallelCompress, ParallelDecompress};
 use crate::ffi::boolean;
 use crate::ffi::jpeg_common_struct;
 use crate::ffi::jpeg_compress_struct;
@@ -31,16 +35,35 @@ use std::os::raw::{c_int, c_uchar, c_ulong, c_void};
 use std::ptr;
 use std::slice;
 
+#[cfg(any(feature = "futures", feature = "tokio"))]
+pub mod async_io;
+pub mod batch;
 mod colorspace;
 mod component;
 pub mod compress;
 pub mod decompress;
 mod density;
 mod errormgr;
+mod exif;
+pub mod huffman;
+#[cfg(feature = "image")]
+pub mod image_codec;
+pub mod incremental;
+pub mod info;
 mod marker;
+pub mod mjpeg;
+pub mod mpf;
+pub mod orientation;
+pub mod parallel;
+mod parse;
 /// Quantization table presets from MozJPEG
 pub mod qtable;
+mod pushsrc;
 mod readsrc;
+pub mod thumbnail;
+#[cfg(feature = "jpegtran")]
+pub mod transform;
+pub mod ultrahdr;
 mod writedst;
 
 #[test]
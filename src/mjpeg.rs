@@ -0,0 +1,241 @@
+//! Motion-JPEG streams from webcams and IP cameras. See `MjpegReader`.
+use crate::colorspace::{ColorSpace, ColorSpaceExt};
+use crate::decompress::{pixel_items_per_pixel, Decompress, ReusableDecompress};
+use crate::huffman::{HuffmanClass, HuffmanTable};
+use crate::parse::{is_sof, is_standalone, skip_to_soi, DHT, EOI, SOI, SOS};
+use bytemuck::Pod;
+use std::io::{self, BufRead, Read};
+
+/// Splits a Motion-JPEG stream into frames, and decodes them with one reused decoder.
+///
+/// Frames can be simply concatenated, or be parts of a `multipart/x-mixed-replace` HTTP body.
+/// Anything between frames (multipart boundaries, headers, padding) is skipped.
+///
+/// Webcams often leave out DHT markers, since MJPEG (AVI1) implies the standard Huffman tables from the JPEG spec.
+/// These tables are added to such frames, so that `next_frame()` always returns complete JPEG files.
+pub struct MjpegReader<R> {
+    reader: R,
+    decompress: Option<ReusableDecompress>,
+    frame: Vec<u8>,
+    standard_dht: Vec<u8>,
+}
+
+impl<R: BufRead> MjpegReader<R> {
+    /// Reads frames from a `BufRead`, e.g. `BufReader` wrapping a `TcpStream`
+    #[must_use]
+    pub fn new(reader: R) -> Self {
+        Self::with_decompress(reader, Decompress::builder().reusable())
+    }
+
+    /// Decodes frames with the given decoder, e.g. configured to save markers
+    #[must_use]
+    pub fn with_decompress(reader: R, decompress: ReusableDecompress) -> Self {
+        let mut standard_dht = Vec::new();
+        for (slot, chroma) in [(0, false), (1, true)] {
+            standard_dht.extend(HuffmanTable::standard(HuffmanClass::Dc, chroma).to_dht(HuffmanClass::Dc, slot));
+            standard_dht.extend(HuffmanTable::standard(HuffmanClass::Ac, chroma).to_dht(HuffmanClass::Ac, slot));
+        }
+        Self {
+            reader,
+            decompress: Some(decompress),
+            frame: Vec::new(),
+            standard_dht,
+        }
+    }
+
+    /// Reads the next frame as a JPEG file, without decoding it. Returns `None` at the end of the stream.
+    ///
+    /// Fails if the stream ends in the middle of a frame.
+    pub fn next_frame(&mut self) -> io::Result<Option<&[u8]>> {
+        self.frame.clear();
+        if !skip_to_soi(&mut self.reader)? {
+            return Ok(None);
+        }
+        self.frame.extend_from_slice(&[0xFF, SOI]);
+        let mut has_dht = false;
+        let mut arithmetic = false;
+        let mut next_marker = None;
+        loop {
+            let marker = match next_marker.take() {
+                Some(marker) => marker,
+                None => self.read_marker()?,
+            };
+            if marker == EOI {
+                self.frame.extend_from_slice(&[0xFF, EOI]);
+                return Ok(Some(&self.frame));
+            }
+            if is_standalone(marker) {
+                self.frame.extend_from_slice(&[0xFF, marker]);
+                continue;
+            }
+            if is_sof(marker) {
+                arithmetic = marker >= 0xC8;
+            }
+            if marker == SOS && !has_dht && !arithmetic {
+                self.frame.extend_from_slice(&self.standard_dht);
+                // once is enough for all scans of the frame
+                has_dht = true;
+            }
+            has_dht |= marker == DHT;
+            self.copy_segment(marker)?;
+            if marker == SOS {
+                next_marker = Some(self.copy_entropy_data()?);
+            }
+        }
+    }
+
+    /// Reads and decodes the next frame into `pixels` (resizing it as needed) in the given color space,
+    /// and returns its width and height. Returns `None` at the end of the stream.
+    ///
+    /// The same decoder and pixel buffer can be used for all frames, so there are no allocations per frame.
+    ///
+    /// ## Panics
+    ///
+    /// Like `Decompress`, it may panic on invalid frames.
+    pub fn read_frame_into<T: Pod>(&mut self, color_space: ColorSpace, pixels: &mut Vec<T>) -> io::Result<Option<(usize, usize)>> {
+        if self.next_frame()?.is_none() {
+            return Ok(None);
+        }
+        // after a failure the old decoder is gone, and a default one replaces it
+        let reusable = self.decompress.take().unwrap_or_else(|| Decompress::builder().reusable());
+        let mut started = reusable.from_mem(&self.frame)?.to_colorspace(color_space)?;
+        let (width, height) = (started.width(), started.height());
+        pixels.resize(width * height * pixel_items_per_pixel::<T>(color_space.num_components())?, T::zeroed());
+        started.read_scanlines_into(pixels)?;
+        self.decompress = Some(started.finish_for_reuse()?.0);
+        Ok(Some((width, height)))
+    }
+
+    /// Returns the underlying reader, positioned after the last frame that has been read
+    #[must_use]
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let byte = *self.reader.fill_buf()?.first().ok_or(io::ErrorKind::UnexpectedEof)?;
+        self.reader.consume(1);
+        Ok(byte)
+    }
+
+    /// Reads `0xFF`, any fill bytes, and the marker code
+    fn read_marker(&mut self) -> io::Result<u8> {
+        if self.read_byte()? != 0xFF {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "expected a JPEG marker"));
+        }
+        loop {
+            match self.read_byte()? {
+                0xFF => {},
+                marker => return Ok(marker),
+            }
+        }
+    }
+
+    /// Copies the marker and its length-prefixed payload to the frame
+    fn copy_segment(&mut self, marker: u8) -> io::Result<()> {
+        let len = [self.read_byte()?, self.read_byte()?];
+        let payload_len = usize::from(u16::from_be_bytes(len)).checked_sub(2)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid JPEG marker length"))?;
+        self.frame.extend_from_slice(&[0xFF, marker, len[0], len[1]]);
+        let start = self.frame.len();
+        self.frame.resize(start + payload_len, 0);
+        self.reader.read_exact(&mut self.frame[start..])
+    }
+
+    /// Copies entropy-coded data including stuffed zeros and restart markers,
+    /// and returns the marker that ends it (already consumed)
+    fn copy_entropy_data(&mut self) -> io::Result<u8> {
+        loop {
+            let buf = self.reader.fill_buf()?;
+            if buf.is_empty() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let Some(pos) = buf.iter().position(|&b| b == 0xFF) else {
+                let len = buf.len();
+                self.frame.extend_from_slice(buf);
+                self.reader.consume(len);
+                continue;
+            };
+            self.frame.extend_from_slice(&buf[..pos]);
+            self.reader.consume(pos + 1);
+            let mut code = self.read_byte()?;
+            while code == 0xFF {
+                code = self.read_byte()?;
+            }
+            match code {
+                0x00 | 0xD0..=0xD7 => self.frame.extend_from_slice(&[0xFF, code]),
+                marker => return Ok(marker),
+            }
+        }
+    }
+}
+
+#[test]
+fn splits_frames() {
+    let jpeg = std::fs::read("tests/test.jpg").unwrap();
+    let mut comp = crate::Compress::new(ColorSpace::JCS_RGB);
+    comp.set_fastest_defaults();
+    comp.set_size(16, 8);
+    let pixels: Vec<u8> = (0..16 * 8 * 3).map(|i| (i * 5) as u8).collect();
+    let mut started = comp.start_compress(Vec::new()).unwrap();
+    started.write_scanlines(&pixels).unwrap();
+    let with_dht = started.finish().unwrap();
+    // like a webcam frame
+    let without_dht = crate::parse::without_segments(&with_dht, DHT);
+    assert!(without_dht.len() < with_dht.len());
+
+    let mut stream = Vec::new();
+    stream.extend_from_slice(&jpeg);
+    stream.extend_from_slice(b"\r\n--boundary\r\nContent-Type: image/jpeg\r\n\r\n");
+    stream.extend_from_slice(&without_dht);
+    stream.extend_from_slice(&[0, 0xFF, 0xFF]);
+    stream.extend_from_slice(&jpeg);
+    stream.extend_from_slice(b"\r\n--boundary--\r\n");
+
+    // tiny buffer to test markers split between reads
+    let mut reader = MjpegReader::new(io::BufReader::with_capacity(3, &stream[..]));
+    assert_eq!(Some(&jpeg[..]), reader.next_frame().unwrap());
+    assert_eq!(Some(&with_dht[..]), reader.next_frame().unwrap());
+    let mut pixels = Vec::<[u8; 3]>::new();
+    assert_eq!(Some((45, 30)), reader.read_frame_into(ColorSpace::JCS_RGB, &mut pixels).unwrap());
+    assert_eq!(45 * 30, pixels.len());
+    assert_eq!(None, reader.next_frame().unwrap());
+
+    let mut reader = MjpegReader::new(&stream[..]);
+    let mut sizes = Vec::new();
+    while let Some(size) = reader.read_frame_into(ColorSpace::JCS_GRAYSCALE, &mut Vec::<u8>::new()).unwrap() {
+        sizes.push(size);
+    }
+    assert_eq!(vec![(45, 30), (16, 8), (45, 30)], sizes);
+
+    let truncated = &stream[..jpeg.len() / 2];
+    assert!(MjpegReader::new(truncated).next_frame().is_err());
+}
+
+#[test]
+fn standard_dht_once_per_frame() {
+    // baseline frame with a separate scan for each component
+    let scans: Vec<crate::ffi::jpeg_scan_info> = (0..3).map(|c| {
+        let mut scan = crate::ffi::jpeg_scan_info { comps_in_scan: 1, Se: 63, ..Default::default() };
+        scan.component_index[0] = c;
+        scan
+    }).collect();
+    let mut comp = crate::Compress::new(ColorSpace::JCS_RGB);
+    comp.set_fastest_defaults();
+    comp.set_size(16, 8);
+    comp.cinfo.scan_info = scans.as_ptr();
+    comp.cinfo.num_scans = 3;
+    let mut started = comp.start_compress(Vec::new()).unwrap();
+    started.write_scanlines(&[100; 16 * 8 * 3]).unwrap();
+    let jpeg = started.finish().unwrap();
+    let segments = |jpeg: &[u8], marker| crate::parse::Segments::new(jpeg).filter(|s| s.marker == marker).count();
+    assert_eq!(3, segments(&jpeg, SOS));
+
+    let without_dht = crate::parse::without_segments(&jpeg, DHT);
+
+    let mut reader = MjpegReader::new(&without_dht[..]);
+    let standard_dhts = segments(&reader.standard_dht, DHT);
+    let frame = reader.next_frame().unwrap().unwrap();
+    assert_eq!(standard_dhts, segments(frame, DHT));
+    assert_eq!(Some((16, 8)), MjpegReader::new(&without_dht[..]).read_frame_into(ColorSpace::JCS_RGB, &mut Vec::<u8>::new()).unwrap());
+}
@@ -0,0 +1,319 @@
+//! Multi-Picture Format (CIPA DC-007). Secondary images, like previews, depth maps or gain maps,
+//! are appended after the primary JPEG, and located by an index in its APP2 `MPF` marker.
+//!
+//! See `MpIndex` for reading, and `MpfWriter` for writing.
+use crate::compress::CompressStarted;
+use crate::parse::{end_of_leading_markers, Segments, SOS};
+use std::io;
+
+/// Start of the APP2 marker data
+pub(crate) const MPF_HEADER: &[u8] = b"MPF\0";
+
+const TAG_VERSION: u16 = 0xB000;
+const TAG_NUMBER_OF_IMAGES: u16 = 0xB001;
+const TAG_MP_ENTRY: u16 = 0xB002;
+const TYPE_LONG: u16 = 4;
+const TYPE_UNDEFINED: u16 = 7;
+const MP_ENTRY_SIZE: usize = 16;
+
+const ATTR_DEPENDENT_PARENT: u32 = 1 << 31;
+const ATTR_DEPENDENT_CHILD: u32 = 1 << 30;
+const ATTR_REPRESENTATIVE: u32 = 1 << 29;
+const ATTR_TYPE_MASK: u32 = 0xFF_FFFF;
+
+/// What the image is for, from the MP entry's type code
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MpImageType {
+    /// Baseline MP primary image
+    Primary,
+    /// Preview up to 640x480
+    LargeThumbnailVga,
+    /// Preview up to 1920x1080
+    LargeThumbnailFullHd,
+    Panorama,
+    /// Stereo pair, e.g. the other eye's view
+    Disparity,
+    MultiAngle,
+    /// Any other code. Gain maps and depth maps commonly use 0 (undefined).
+    Other(u32),
+}
+
+impl MpImageType {
+    #[must_use]
+    pub fn from_code(code: u32) -> Self {
+        match code & ATTR_TYPE_MASK {
+            0x03_0000 => Self::Primary,
+            0x01_0001 => Self::LargeThumbnailVga,
+            0x01_0002 => Self::LargeThumbnailFullHd,
+            0x02_0001 => Self::Panorama,
+            0x02_0002 => Self::Disparity,
+            0x02_0003 => Self::MultiAngle,
+            code => Self::Other(code),
+        }
+    }
+
+    /// The 24-bit type code used in MP entries
+    #[must_use]
+    pub fn code(self) -> u32 {
+        match self {
+            Self::Primary => 0x03_0000,
+            Self::LargeThumbnailVga => 0x01_0001,
+            Self::LargeThumbnailFullHd => 0x01_0002,
+            Self::Panorama => 0x02_0001,
+            Self::Disparity => 0x02_0002,
+            Self::MultiAngle => 0x02_0003,
+            Self::Other(code) => code & ATTR_TYPE_MASK,
+        }
+    }
+}
+
+/// One image listed in the MP index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MpEntry {
+    pub image_type: MpImageType,
+    /// The image that should be shown by default
+    pub representative: bool,
+    /// Has dependent images listed in `dependent_images`
+    pub dependent_parent: bool,
+    /// Is a dependent image of another entry
+    pub dependent_child: bool,
+    /// Offset of the image's SOI marker from the start of the file
+    pub offset: usize,
+    /// Length of the image in bytes
+    pub size: usize,
+    /// 1-based numbers of entries that depend on this image, 0 if none
+    pub dependent_images: (u16, u16),
+}
+
+impl MpEntry {
+    /// Slice of the file with this image's JPEG data. `None` if it's outside of the file.
+    #[must_use]
+    pub fn data<'a>(&self, file: &'a [u8]) -> Option<&'a [u8]> {
+        file.get(self.offset..self.offset.checked_add(self.size)?)
+    }
+}
+
+/// List of all images in a multi-picture file, from the primary image's APP2 `MPF` marker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MpIndex {
+    /// The primary image is first
+    pub entries: Vec<MpEntry>,
+}
+
+impl MpIndex {
+    /// Finds and parses the MP index in the whole file. Returns `None` if the file doesn't have one.
+    pub fn from_mem(file: &[u8]) -> io::Result<Option<Self>> {
+        let Some(segment) = Segments::new(file)
+            .take_while(|s| s.marker != SOS)
+            .find(|s| s.marker == 0xE2 && s.payload.starts_with(MPF_HEADER))
+        else {
+            return Ok(None);
+        };
+        // offsets are relative to the TIFF header after `MPF\0`
+        let header_offset = segment.start + 4 + MPF_HEADER.len();
+        Self::parse(&segment.payload[MPF_HEADER.len()..], header_offset).map(Some)
+    }
+
+    /// Parses TIFF-formatted MP index (APP2 data after `MPF\0`), which starts at `header_offset` in the file
+    fn parse(tiff: &[u8], header_offset: usize) -> io::Result<Self> {
+        let big_endian = match tiff.get(..4) {
+            Some(b"MM\0\x2A") => true,
+            Some(b"II\x2A\0") => false,
+            _ => return Err(invalid("invalid MPF header")),
+        };
+        let u16_at = |pos: usize| -> io::Result<u16> {
+            let b = tiff.get(pos..pos + 2).ok_or_else(|| invalid("truncated MPF index"))?;
+            let b = [b[0], b[1]];
+            Ok(if big_endian { u16::from_be_bytes(b) } else { u16::from_le_bytes(b) })
+        };
+        let u32_at = |pos: usize| -> io::Result<u32> {
+            let b = tiff.get(pos..pos + 4).ok_or_else(|| invalid("truncated MPF index"))?;
+            let b = [b[0], b[1], b[2], b[3]];
+            Ok(if big_endian { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) })
+        };
+
+        let ifd = u32_at(4)? as usize;
+        let mut num_images = None;
+        let mut entries_at = None;
+        for i in 0..usize::from(u16_at(ifd)?) {
+            let field = ifd + 2 + i * 12;
+            match u16_at(field)? {
+                TAG_NUMBER_OF_IMAGES => num_images = Some(u32_at(field + 8)? as usize),
+                TAG_MP_ENTRY => entries_at = Some((u32_at(field + 8)? as usize, u32_at(field + 4)? as usize)),
+                _ => {},
+            }
+        }
+        let (Some(num_images), Some((entries_at, entries_len))) = (num_images, entries_at) else {
+            return Err(invalid("MPF index without MP entries"));
+        };
+        if entries_len != num_images.saturating_mul(MP_ENTRY_SIZE) {
+            return Err(invalid("invalid MPF entries length"));
+        }
+
+        let entries = (0..num_images).map(|i| {
+            let pos = entries_at + i * MP_ENTRY_SIZE;
+            let attributes = u32_at(pos)?;
+            let offset = u32_at(pos + 8)? as usize;
+            Ok(MpEntry {
+                image_type: MpImageType::from_code(attributes),
+                representative: attributes & ATTR_REPRESENTATIVE != 0,
+                dependent_parent: attributes & ATTR_DEPENDENT_PARENT != 0,
+                dependent_child: attributes & ATTR_DEPENDENT_CHILD != 0,
+                // the primary image has offset 0, and starts the file
+                offset: if offset == 0 { 0 } else { header_offset + offset },
+                size: u32_at(pos + 4)? as usize,
+                dependent_images: (u16_at(pos + 12)?, u16_at(pos + 14)?),
+            })
+        }).collect::<io::Result<_>>()?;
+        Ok(Self { entries })
+    }
+}
+
+/// Makes a multi-picture file from a primary image and secondary JPEG files appended after it
+///
+/// The MP index is inserted into the primary image after its APP0/APP1 markers, and lists all images.
+#[derive(Debug, Clone, Default)]
+pub struct MpfWriter<'a> {
+    images: Vec<(MpImageType, &'a [u8])>,
+}
+
+impl<'a> MpfWriter<'a> {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a complete JPEG file as a secondary image
+    pub fn add_image(&mut self, image_type: MpImageType, jpeg: &'a [u8]) {
+        self.images.push((image_type, jpeg));
+    }
+
+    /// Finishes compression of the primary image, and returns the whole multi-picture file
+    pub fn finish(&self, primary: CompressStarted<Vec<u8>>) -> io::Result<Vec<u8>> {
+        self.attach_to(&primary.finish()?)
+    }
+
+    /// Returns a multi-picture file with the given JPEG file as the primary image
+    pub fn attach_to(&self, primary: &[u8]) -> io::Result<Vec<u8>> {
+        let insert_at = end_of_leading_markers(primary, &[0xE0, 0xE1])
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "primary image is not a JPEG file"))?;
+        if Segments::new(primary).take_while(|s| s.marker != SOS).any(|s| s.marker == 0xE2 && s.payload.starts_with(MPF_HEADER)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "primary image already has an MPF index"));
+        }
+
+        let num_images = 1 + self.images.len();
+        let ifd_len = 2 + 3 * 12 + 4;
+        let entries_at = 8 + ifd_len;
+        let tiff_len = entries_at + num_images * MP_ENTRY_SIZE;
+        let segment_len = 4 + MPF_HEADER.len() + tiff_len;
+        if segment_len - 2 > usize::from(u16::MAX) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many images for MPF"));
+        }
+        let header_offset = insert_at + 4 + MPF_HEADER.len();
+        let primary_size = primary.len() + segment_len;
+        let to_u32 = |n: usize| u32::try_from(n).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "MPF file too large"));
+
+        let mut app2 = Vec::with_capacity(segment_len);
+        app2.extend_from_slice(&[0xFF, 0xE2]);
+        app2.extend_from_slice(&((segment_len - 2) as u16).to_be_bytes());
+        app2.extend_from_slice(MPF_HEADER);
+        app2.extend_from_slice(b"MM\0\x2A");
+        app2.extend_from_slice(&8u32.to_be_bytes());
+        app2.extend_from_slice(&3u16.to_be_bytes());
+        let mut field = |tag: u16, typ: u16, count: u32, value: [u8; 4]| {
+            app2.extend_from_slice(&tag.to_be_bytes());
+            app2.extend_from_slice(&typ.to_be_bytes());
+            app2.extend_from_slice(&count.to_be_bytes());
+            app2.extend_from_slice(&value);
+        };
+        field(TAG_VERSION, TYPE_UNDEFINED, 4, *b"0100");
+        field(TAG_NUMBER_OF_IMAGES, TYPE_LONG, 1, (num_images as u32).to_be_bytes());
+        field(TAG_MP_ENTRY, TYPE_UNDEFINED, (num_images * MP_ENTRY_SIZE) as u32, (entries_at as u32).to_be_bytes());
+        app2.extend_from_slice(&0u32.to_be_bytes());
+
+        let mut entry = |attributes: u32, size: usize, offset: usize| -> io::Result<()> {
+            app2.extend_from_slice(&attributes.to_be_bytes());
+            app2.extend_from_slice(&to_u32(size)?.to_be_bytes());
+            app2.extend_from_slice(&to_u32(offset)?.to_be_bytes());
+            app2.extend_from_slice(&[0; 4]);
+            Ok(())
+        };
+        entry(ATTR_REPRESENTATIVE | MpImageType::Primary.code(), primary_size, 0)?;
+        let mut next_offset = primary_size;
+        for &(image_type, jpeg) in &self.images {
+            entry(image_type.code(), jpeg.len(), next_offset - header_offset)?;
+            next_offset += jpeg.len();
+        }
+        debug_assert_eq!(segment_len, app2.len());
+
+        let mut out = Vec::with_capacity(next_offset);
+        out.extend_from_slice(&primary[..insert_at]);
+        out.extend_from_slice(&app2);
+        out.extend_from_slice(&primary[insert_at..]);
+        for (_, jpeg) in &self.images {
+            out.extend_from_slice(jpeg);
+        }
+        Ok(out)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[test]
+fn writes_and_parses_index() {
+    let gray = |width, height, color| {
+        let mut comp = crate::Compress::new(crate::ColorSpace::JCS_GRAYSCALE);
+        comp.set_size(width, height);
+        let mut started = comp.start_compress(Vec::new()).unwrap();
+        started.write_scanlines(&vec![color; width * height]).unwrap();
+        started
+    };
+    let depth = gray(8, 4, 10).finish().unwrap();
+    let preview = std::fs::read("tests/test.jpg").unwrap();
+    assert_eq!(None, MpIndex::from_mem(&preview).unwrap());
+
+    let mut writer = MpfWriter::new();
+    writer.add_image(MpImageType::LargeThumbnailVga, &preview);
+    writer.add_image(MpImageType::Other(0), &depth);
+    let file = writer.finish(gray(16, 16, 200)).unwrap();
+    assert!(writer.attach_to(&file).is_err());
+
+    let index = MpIndex::from_mem(&file).unwrap().unwrap();
+    let types: Vec<_> = index.entries.iter().map(|e| e.image_type).collect();
+    assert_eq!(vec![MpImageType::Primary, MpImageType::LargeThumbnailVga, MpImageType::Other(0)], types);
+    assert!(index.entries[0].representative);
+    assert_eq!(0, index.entries[0].offset);
+    assert_eq!(file.len() - preview.len() - depth.len(), index.entries[0].size);
+    assert_eq!(Some(&preview[..]), index.entries[1].data(&file));
+    assert_eq!(Some(&depth[..]), index.entries[2].data(&file));
+    // the APP2 marker goes after APP0
+    assert_eq!([0xFF, 0xE0], file[2..4]);
+    let primary = index.entries[0].data(&file).unwrap();
+    assert_eq!(vec![200u8; 16 * 16], crate::Decompress::new_mem(primary).unwrap().grayscale().unwrap().read_scanlines::<u8>().unwrap());
+
+    let mut sizes = Vec::new();
+    let mut seq = crate::DecompressSequence::new(&file[..]);
+    while let Some(dec) = seq.next_image().unwrap() {
+        sizes.push(dec.size());
+        let mut started = dec.grayscale().unwrap();
+        let _: Vec<u8> = started.read_scanlines().unwrap();
+        started.finish().unwrap();
+    }
+    assert_eq!(vec![(16, 16), (45, 30), (8, 4)], sizes);
+
+    // little-endian, as written by some cameras
+    let mut le = b"II\x2A\0\x08\0\0\0\x02\0".to_vec();
+    le.extend_from_slice(&[0x01, 0xB0, 4, 0, 1, 0, 0, 0, 2, 0, 0, 0]);
+    le.extend_from_slice(&[0x02, 0xB0, 7, 0, 32, 0, 0, 0, 38, 0, 0, 0]);
+    le.extend_from_slice(&[0, 0, 0, 0]);
+    le.extend_from_slice(&[0, 0, 3, 0x20, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    le.extend_from_slice(&[2, 0, 2, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    let index = MpIndex::parse(&le, 100).unwrap();
+    assert_eq!(MpImageType::Primary, index.entries[0].image_type);
+    assert_eq!(0x100, index.entries[0].size);
+    assert_eq!(MpImageType::Disparity, index.entries[1].image_type);
+    assert_eq!((0x200, 100 + 0x100), (index.entries[1].size, index.entries[1].offset));
+    assert!(MpIndex::parse(&le[..40], 100).is_err());
+}
@@ -0,0 +1,146 @@
+//! EXIF orientation, which cameras use instead of rotating the pixels. See `Decompress::apply_exif_orientation()`.
+use crate::decompress::Decompress;
+use crate::exif::{Tiff, TAG_ORIENTATION};
+
+/// How the stored pixels need to be transformed to show the image upright. Values are from the EXIF tag.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Orientation {
+    #[default]
+    Normal = 1,
+    /// Mirror left-right
+    FlipHorizontal = 2,
+    Rotate180 = 3,
+    /// Mirror top-bottom
+    FlipVertical = 4,
+    /// Flip across the top-left to bottom-right diagonal
+    Transpose = 5,
+    /// Rotate 90° clockwise
+    Rotate90 = 6,
+    /// Flip across the top-right to bottom-left diagonal
+    Transverse = 7,
+    /// Rotate 90° counter-clockwise
+    Rotate270 = 8,
+}
+
+impl Orientation {
+    /// From the value of the EXIF tag (1-8)
+    #[must_use]
+    pub fn from_exif(value: u32) -> Option<Self> {
+        Some(match value {
+            1 => Self::Normal,
+            2 => Self::FlipHorizontal,
+            3 => Self::Rotate180,
+            4 => Self::FlipVertical,
+            5 => Self::Transpose,
+            6 => Self::Rotate90,
+            7 => Self::Transverse,
+            8 => Self::Rotate270,
+            _ => return None,
+        })
+    }
+
+    /// `true` if width and height are swapped by the transformation
+    #[must_use]
+    pub fn swaps_dimensions(self) -> bool {
+        matches!(self, Self::Transpose | Self::Rotate90 | Self::Transverse | Self::Rotate270)
+    }
+
+    /// Transforms `width`×`height` pixels of `bytes_per_pixel` each
+    pub(crate) fn apply(self, pixels: &[u8], width: usize, height: usize, bytes_per_pixel: usize) -> Vec<u8> {
+        let (out_width, out_height) = if self.swaps_dimensions() { (height, width) } else { (width, height) };
+        let mut out = Vec::with_capacity(pixels.len());
+        for y in 0..out_height {
+            for x in 0..out_width {
+                let (src_x, src_y) = match self {
+                    Self::Normal => (x, y),
+                    Self::FlipHorizontal => (width - 1 - x, y),
+                    Self::Rotate180 => (width - 1 - x, height - 1 - y),
+                    Self::FlipVertical => (x, height - 1 - y),
+                    Self::Transpose => (y, x),
+                    Self::Rotate90 => (y, height - 1 - x),
+                    Self::Transverse => (width - 1 - y, height - 1 - x),
+                    Self::Rotate270 => (width - 1 - y, x),
+                };
+                let pos = (src_y * width + src_x) * bytes_per_pixel;
+                out.extend_from_slice(&pixels[pos..pos + bytes_per_pixel]);
+            }
+        }
+        out
+    }
+}
+
+impl<R> Decompress<R> {
+    /// Orientation from the EXIF metadata. Requires the APP1 marker to be saved, e.g. with `ALL_MARKERS`.
+    #[must_use]
+    pub fn exif_orientation(&self) -> Option<Orientation> {
+        let tiff = Tiff::from_markers(self.markers())?;
+        Orientation::from_exif(tiff.find_u32(tiff.first_ifd()?, TAG_ORIENTATION)?)
+    }
+
+    /// If `true`, pixels are rotated and flipped according to the EXIF orientation, so they're output upright.
+    /// `size()` and `width()`/`height()` of the decoder and `DecompressStarted` report the oriented dimensions.
+    ///
+    /// Requires the APP1 marker to be saved, e.g. with `ALL_MARKERS`. The whole image is buffered on the first read.
+    /// It isn't applied to raw data and in buffered-image mode, and can't be used with `crop_scanline()`/`skip_scanlines()`.
+    /// Default is `false`.
+    pub fn apply_exif_orientation(&mut self, value: bool) {
+        self.orientation = if value { self.exif_orientation().unwrap_or_default() } else { Orientation::Normal };
+    }
+}
+
+#[test]
+fn applies_orientation() {
+    use crate::{ColorSpace, Compress, Marker, ALL_MARKERS};
+
+    // pixels 0 1 2
+    //        3 4 5
+    let pixels = [0, 1, 2, 3, 4, 5];
+    let expected: [(Orientation, &[u8]); 8] = [
+        (Orientation::Normal, &[0, 1, 2, 3, 4, 5]),
+        (Orientation::FlipHorizontal, &[2, 1, 0, 5, 4, 3]),
+        (Orientation::Rotate180, &[5, 4, 3, 2, 1, 0]),
+        (Orientation::FlipVertical, &[3, 4, 5, 0, 1, 2]),
+        (Orientation::Transpose, &[0, 3, 1, 4, 2, 5]),
+        (Orientation::Rotate90, &[3, 0, 4, 1, 5, 2]),
+        (Orientation::Transverse, &[5, 2, 4, 1, 3, 0]),
+        (Orientation::Rotate270, &[2, 5, 1, 4, 0, 3]),
+    ];
+    for (orientation, out) in expected {
+        assert_eq!(Some(orientation), Orientation::from_exif(orientation as u32));
+        assert_eq!(out, orientation.apply(&pixels, 3, 2, 1));
+    }
+    assert_eq!(vec![4, 5, 0, 1, 6, 7, 2, 3], Orientation::Rotate90.apply(&[0, 1, 2, 3, 4, 5, 6, 7], 2, 2, 2));
+
+    // 16x8 image with a bright left half, and little-endian EXIF with orientation 6
+    let (width, height) = (16, 8);
+    let image: Vec<u8> = (0..width * height).map(|i| if i % width < 8 { 250 } else { 5 }).collect();
+    let mut comp = Compress::new(ColorSpace::JCS_GRAYSCALE);
+    comp.set_size(width, height);
+    let mut started = comp.start_compress(Vec::new()).unwrap();
+    started.write_marker(Marker::APP(1), b"Exif\0\0II\x2A\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0\x06\0\0\0\0\0\0\0");
+    started.write_scanlines(&image).unwrap();
+    let jpeg = started.finish().unwrap();
+
+    let mut dec = Decompress::with_markers(ALL_MARKERS).from_mem(&jpeg).unwrap();
+    assert_eq!(Some(Orientation::Rotate90), dec.exif_orientation());
+    assert_eq!((16, 8), dec.size());
+    dec.apply_exif_orientation(true);
+    assert_eq!((8, 16), dec.size());
+    let mut started = dec.grayscale().unwrap();
+    assert_eq!((8, 16), (started.width(), started.height()));
+    assert!(started.skip_scanlines(1).is_err());
+    let mut top = vec![0u8; 8 * 4];
+    started.read_scanlines_into(&mut top).unwrap();
+    let rest: Vec<u8> = started.read_scanlines().unwrap();
+    assert_eq!(8 * 12, rest.len());
+    started.finish().unwrap();
+    // after rotating clockwise, the bright half is at the top
+    assert!(top.iter().all(|&p| p > 200));
+    assert!(rest[8 * 4..].iter().all(|&p| p < 50));
+
+    // no EXIF: no change
+    let data = std::fs::read("tests/test.jpg").unwrap();
+    let mut dec = Decompress::with_markers(ALL_MARKERS).from_mem(&data).unwrap();
+    dec.apply_exif_orientation(true);
+    assert_eq!((45, 30), dec.size());
+}
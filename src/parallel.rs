@@ -0,0 +1,450 @@
+//! Multi-threaded compression and decompression of large images. See `ParallelCompress` and `ParallelDecompress`.
+use bytemuck::Pod;
+use crate::colorspace::{ColorSpace, ColorSpaceExt};
+use crate::compress::Compress;
+use crate::decompress::{pixel_items_per_pixel, Decompress};
+use crate::ffi::DCTSIZE;
+use crate::marker::Marker;
+use crate::parse::{append_renumbered, entropy_data_end, is_sof, push_restart_marker, restart_marker_offsets, Segments, DRI, EOI, SOS};
+use std::io;
+use std::panic;
+use std::thread;
+
+/// Compresses horizontal strips of an image on multiple threads, and joins them into one baseline JPEG
+///
+/// Every strip is a whole number of MCU rows, and ends with a restart marker,
+/// so the strips' entropy-coded data can be concatenated. For this all strips
+/// must use the same tables, so the output is never progressive, uses the standard Huffman tables,
+/// and can't use trellis quantization (which optimizes Huffman tables). Restart markers are added after every MCU row.
+pub struct ParallelCompress<'a> {
+    color_space: ColorSpace,
+    width: usize,
+    height: usize,
+    threads: usize,
+    settings: Option<&'a (dyn Fn(&mut Compress) + Sync)>,
+    markers: Vec<(Marker, &'a [u8])>,
+    icc_profile: Option<&'a [u8]>,
+}
+
+impl<'a> ParallelCompress<'a> {
+    /// Compress image of this size, using input in this colorspace.
+    ///
+    /// Uses as many threads as there are CPUs available.
+    #[must_use]
+    pub fn new(color_space: ColorSpace, width: usize, height: usize) -> Self {
+        Self {
+            color_space,
+            width,
+            height,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            settings: None,
+            markers: Vec::new(),
+            icc_profile: None,
+        }
+    }
+
+    /// Max number of strips to compress at the same time
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    /// The callback is called to configure `Compress` of every strip, e.g. to set quality or chroma subsampling.
+    ///
+    /// Progressive mode, scan optimization, trellis quantization and Huffman table optimization are turned off afterwards.
+    pub fn set_compress_settings(&mut self, settings: &'a (dyn Fn(&mut Compress) + Sync)) {
+        self.settings = Some(settings);
+    }
+
+    /// Add a marker to the compressed file. See `CompressStarted::write_marker`.
+    pub fn add_marker(&mut self, marker: Marker, data: &'a [u8]) {
+        self.markers.push((marker, data));
+    }
+
+    /// Add ICC profile to the compressed file. See `CompressStarted::write_icc_profile`.
+    pub fn add_icc_profile(&mut self, data: &'a [u8]) {
+        self.icc_profile = Some(data);
+    }
+
+    /// `pixels` are rows of width × number of components bytes, top to bottom.
+    ///
+    /// ## Panics
+    ///
+    /// Like all functions of this library, libjpeg errors panic. Panics of strip threads are propagated.
+    pub fn compress(&self, pixels: &[u8]) -> io::Result<Vec<u8>> {
+        let row_bytes = self.width * self.color_space.num_components();
+        if row_bytes == 0 || self.height == 0 || pixels.len() != row_bytes * self.height {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("expected {}x{} pixels, got {}B", self.width, self.height, pixels.len())));
+        }
+        let height = u16::try_from(self.height).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "image too tall"))?;
+
+        let mcu_height = self.new_compress(self.height).components().iter()
+            .map(|c| c.v_samp_factor as usize).max().unwrap_or(1) * DCTSIZE;
+        let mcu_rows = (self.height + mcu_height - 1) / mcu_height;
+        let strips = self.threads.min(mcu_rows);
+        let strip_height = (mcu_rows + strips - 1) / strips * mcu_height;
+
+        let strips = thread::scope(|s| {
+            let handles: Vec<_> = pixels.chunks(strip_height * row_bytes).enumerate().map(|(i, strip_pixels)| {
+                s.spawn(move || self.compress_strip(strip_pixels, strip_pixels.len() / row_bytes, i == 0))
+            }).collect();
+            handles.into_iter()
+                .map(|h| h.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect::<io::Result<Vec<_>>>()
+        })?;
+        stitch(&strips, height)
+    }
+
+    fn new_compress(&self, height: usize) -> Compress {
+        let mut comp = Compress::new(self.color_space);
+        if let Some(settings) = self.settings {
+            settings(&mut comp);
+        }
+        comp.set_size(self.width, height);
+        comp.set_optimize_scans(false);
+        comp.set_optimize_coding(false);
+        comp.set_trellis_quantization(false);
+        comp.set_restart_in_rows(1);
+        comp
+    }
+
+    fn compress_strip(&self, pixels: &[u8], height: usize, first: bool) -> io::Result<Vec<u8>> {
+        let mut started = self.new_compress(height).start_compress(Vec::with_capacity(pixels.len() / 8))?;
+        if first {
+            if let Some(icc) = self.icc_profile {
+                started.write_icc_profile(icc);
+            }
+            for &(marker, data) in &self.markers {
+                started.write_marker(marker, data);
+            }
+        }
+        started.write_scanlines(pixels)?;
+        started.finish()
+    }
+}
+
+fn invalid_strip() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "unexpected layout of compressed strip")
+}
+
+/// Headers of the first strip with the full height, and entropy-coded data of all strips
+/// with restart markers renumbered to continue from the previous strip
+fn stitch(strips: &[Vec<u8>], height: u16) -> io::Result<Vec<u8>> {
+    let first = strips.first().ok_or_else(invalid_strip)?;
+    let headers_end = Segments::new(first).find(|s| s.marker == SOS).ok_or_else(invalid_strip)?.end();
+
+    let mut out = Vec::with_capacity(strips.iter().map(|s| s.len()).sum());
+    out.extend_from_slice(&first[..headers_end]);
+    // SOF is: marker, length, precision, height
+    let height_pos = Segments::new(&out).find(|s| is_sof(s.marker)).ok_or_else(invalid_strip)?.start + 5;
+    out[height_pos..height_pos + 2].copy_from_slice(&height.to_be_bytes());
+
+    let mut restart_num = 0;
+    for (i, strip) in strips.iter().enumerate() {
+        let data_start = if i == 0 { headers_end } else {
+            Segments::new(strip).find(|s| s.marker == SOS).ok_or_else(invalid_strip)?.end()
+        };
+        if i > 0 {
+            push_restart_marker(&mut out, &mut restart_num);
+        }
+        append_renumbered(&mut out, &strip[data_start..entropy_data_end(strip, data_start)], &mut restart_num);
+    }
+    out.extend_from_slice(&[0xFF, EOI]);
+    Ok(out)
+}
+
+/// Decodes JPEGs with restart markers on multiple threads
+///
+/// Restart markers split entropy-coded data into segments that can be decoded independently.
+/// When every segment is a whole number of MCU rows, the image is split into bands of rows decoded in parallel.
+/// Each band is decoded with one extra segment above and below it, so that chroma upsampling
+/// at the band's edges gives exactly the same pixels as sequential decoding.
+///
+/// Files without restart markers, progressive or multi-scan files, and files with restart intervals
+/// that don't end at the end of a row are decoded sequentially, like with `Decompress`.
+pub struct ParallelDecompress<'a> {
+    data: &'a [u8],
+    width: usize,
+    height: usize,
+    threads: usize,
+    layout: Option<RestartLayout<'a>>,
+}
+
+impl<'a> ParallelDecompress<'a> {
+    /// Reads the header and finds restart markers in the file
+    ///
+    /// Uses as many threads as there are CPUs available.
+    ///
+    /// ## Panics
+    ///
+    /// Like `Decompress::new_mem`, it may panic on invalid files.
+    pub fn new(data: &'a [u8]) -> io::Result<Self> {
+        let (width, height) = Decompress::new_mem(data)?.size();
+        Ok(Self {
+            data,
+            width,
+            height,
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            layout: RestartLayout::new(data, width, height),
+        })
+    }
+
+    /// Max number of bands of rows to decode at the same time
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// `false` if the file will be decoded sequentially, because its restart markers (if any) can't be used
+    #[inline]
+    #[must_use]
+    pub fn is_parallel(&self) -> bool {
+        self.layout.as_ref().is_some_and(|l| l.segments.len() > 1)
+    }
+
+    /// Decodes the whole image in the given color space. Pixels can be `u8` or pixel types, like in `DecompressStarted::read_scanlines`.
+    ///
+    /// ## Panics
+    ///
+    /// Like all functions of this library, libjpeg errors panic. Panics of band threads are propagated.
+    pub fn read_scanlines<T: Pod + Send>(&self, color_space: ColorSpace) -> io::Result<Vec<T>> {
+        let row_len = self.width * pixel_items_per_pixel::<T>(color_space.num_components())?;
+        let layout = match &self.layout {
+            Some(layout) if self.threads > 1 && self.is_parallel() => layout,
+            _ => {
+                let mut dec = Decompress::new_mem(self.data)?.to_colorspace(color_space)?;
+                let pixels = dec.read_scanlines()?;
+                dec.finish()?;
+                return Ok(pixels);
+            },
+        };
+
+        let mut pixels: Vec<T> = Vec::new();
+        pixels.try_reserve_exact(row_len * self.height).map_err(|_| io::ErrorKind::OutOfMemory)?;
+        pixels.resize(row_len * self.height, T::zeroed());
+
+        let bands = self.threads.min(layout.segments.len());
+        let segments_per_band = (layout.segments.len() + bands - 1) / bands;
+        let band_len = segments_per_band * layout.segment_height * row_len;
+        thread::scope(|s| {
+            let handles: Vec<_> = pixels.chunks_mut(band_len).enumerate().map(|(i, band)| {
+                s.spawn(move || layout.decode_band(i * segments_per_band, band, row_len, color_space, self.height))
+            }).collect();
+            handles.into_iter()
+                .try_for_each(|h| h.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+        })?;
+        Ok(pixels)
+    }
+}
+
+/// Where to split a single-scan file
+struct RestartLayout<'a> {
+    /// Markers needed for decoding, up to and including SOS
+    headers: Vec<u8>,
+    /// Offset of image height in the SOF marker in `headers`
+    height_pos: usize,
+    /// Entropy-coded data of every restart interval, without the restart markers
+    segments: Vec<&'a [u8]>,
+    /// Pixel rows decoded from each segment
+    segment_height: usize,
+}
+
+impl<'a> RestartLayout<'a> {
+    fn new(data: &'a [u8], width: usize, height: usize) -> Option<Self> {
+        let mut headers = Vec::new();
+        let mut height_pos = None;
+        let mut sampling = Vec::new();
+        let mut restart_interval = 0;
+        let mut scan = None;
+        for segment in Segments::new(data) {
+            if scan.is_some() {
+                // multiple scans, or DNL
+                if segment.marker != EOI {
+                    return None;
+                }
+                break;
+            }
+            match segment.marker {
+                // baseline and extended sequential Huffman only
+                0xC0 | 0xC1 => {
+                    let num_components = usize::from(*segment.payload.get(5)?);
+                    sampling = segment.payload.get(6..6 + 3 * num_components)?.chunks_exact(3)
+                        .map(|c| (usize::from(c[1] >> 4), usize::from(c[1] & 15)))
+                        .collect();
+                    height_pos = Some(headers.len() + 5);
+                },
+                m if is_sof(m) => return None,
+                DRI => restart_interval = usize::from(u16::from_be_bytes(segment.payload.get(..2)?.try_into().ok()?)),
+                SOS => {
+                    // non-interleaved scans of multi-component images
+                    if usize::from(*segment.payload.first()?) != sampling.len() {
+                        return None;
+                    }
+                    scan = Some(segment);
+                },
+                // metadata isn't needed for decoding, except JFIF and Adobe markers
+                0xE1..=0xED | 0xEF | 0xFE => continue,
+                _ => {},
+            }
+            headers.extend_from_slice(&data[segment.start..segment.end()]);
+        }
+        let scan = scan?;
+        let height_pos = height_pos?;
+
+        let max_h = sampling.iter().map(|s| s.0).max()?;
+        let max_v = sampling.iter().map(|s| s.1).max()?;
+        let (mcu_width, mcu_height) = if sampling.len() == 1 { (DCTSIZE, DCTSIZE) } else { (max_h * DCTSIZE, max_v * DCTSIZE) };
+        if mcu_width == 0 || mcu_height == 0 {
+            return None;
+        }
+        let mcus_per_row = (width + mcu_width - 1) / mcu_width;
+        let mcu_rows = (height + mcu_height - 1) / mcu_height;
+        if restart_interval == 0 || restart_interval % mcus_per_row != 0 {
+            return None;
+        }
+        let rows_per_segment = restart_interval / mcus_per_row;
+
+        let entropy_data = &data[scan.end()..entropy_data_end(data, scan.end())];
+        let mut segments = Vec::with_capacity((mcu_rows + rows_per_segment - 1) / rows_per_segment);
+        let mut start = 0;
+        for rst in restart_marker_offsets(entropy_data) {
+            segments.push(&entropy_data[start..rst]);
+            start = rst + 2;
+        }
+        segments.push(&entropy_data[start..]);
+        // corrupted or truncated data needs libjpeg's error recovery
+        if segments.len() != (mcu_rows + rows_per_segment - 1) / rows_per_segment {
+            return None;
+        }
+
+        Some(Self {
+            headers,
+            height_pos,
+            segments,
+            segment_height: rows_per_segment * mcu_height,
+        })
+    }
+
+    /// Decodes rows starting at `first_segment` into `band`, from a JPEG made of the band's segments and their neighbors
+    fn decode_band<T: Pod>(&self, first_segment: usize, band: &mut [T], row_len: usize, color_space: ColorSpace, image_height: usize) -> io::Result<()> {
+        let band_rows = band.len() / row_len;
+        let start = first_segment.saturating_sub(1);
+        let end = (first_segment + (band_rows + self.segment_height - 1) / self.segment_height + 1).min(self.segments.len());
+        let top = start * self.segment_height;
+        let bottom = (end * self.segment_height).min(image_height);
+
+        let mut jpeg = Vec::with_capacity(self.headers.len() + self.segments[start..end].iter().map(|s| s.len() + 2).sum::<usize>());
+        jpeg.extend_from_slice(&self.headers);
+        jpeg[self.height_pos..self.height_pos + 2].copy_from_slice(&((bottom - top) as u16).to_be_bytes());
+        let mut restart_num = 0;
+        for (i, segment) in self.segments[start..end].iter().enumerate() {
+            if i > 0 {
+                push_restart_marker(&mut jpeg, &mut restart_num);
+            }
+            jpeg.extend_from_slice(segment);
+        }
+        jpeg.extend_from_slice(&[0xFF, EOI]);
+
+        let mut dec = Decompress::new_mem(&jpeg)?.to_colorspace(color_space)?;
+        let mut skipped_row = vec![T::zeroed(); row_len];
+        for _ in top..first_segment * self.segment_height {
+            dec.read_scanlines_into(&mut skipped_row)?;
+        }
+        dec.read_scanlines_into(band)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn test_image(width: usize, height: usize) -> Vec<u8> {
+    (0..height).flat_map(|y| (0..width).flat_map(move |x| [x as u8, y as u8, ((x ^ y) & 0x1F) as u8])).collect()
+}
+
+#[test]
+fn same_as_sequential() {
+    let (width, height) = (301, 203);
+    let pixels = test_image(width, height);
+    let settings = |comp: &mut Compress| {
+        comp.set_fastest_defaults();
+        comp.set_quality(80.);
+    };
+
+    let mut comp = Compress::new(ColorSpace::JCS_RGB);
+    settings(&mut comp);
+    comp.set_size(width, height);
+    comp.set_restart_in_rows(1);
+    let mut started = comp.start_compress(Vec::new()).unwrap();
+    started.write_scanlines(&pixels).unwrap();
+    let expected = started.finish().unwrap();
+
+    for threads in [1, 3, 8, 100] {
+        let mut par = ParallelCompress::new(ColorSpace::JCS_RGB, width, height);
+        par.set_threads(threads);
+        par.set_compress_settings(&settings);
+        assert_eq!(expected, par.compress(&pixels).unwrap(), "{threads}");
+    }
+}
+
+#[test]
+fn decodes() {
+    let (width, height) = (200, 150);
+    let pixels = test_image(width, height);
+    let mut par = ParallelCompress::new(ColorSpace::JCS_RGB, width, height);
+    par.set_threads(4);
+    par.add_marker(Marker::COM, b"hello");
+    let jpeg = par.compress(&pixels).unwrap();
+
+    let dec = crate::Decompress::builder().with_markers(&[Marker::COM]).from_mem(&jpeg).unwrap();
+    assert_eq!(b"hello", dec.markers().next().unwrap().data);
+    assert_eq!((width, height), dec.size());
+    let decoded: Vec<u8> = dec.rgb().unwrap().read_scanlines().unwrap();
+    let diff = pixels.iter().zip(&decoded).map(|(&a, &b)| u64::from(a.abs_diff(b))).sum::<u64>();
+    assert!(diff / (pixels.len() as u64) < 8, "{diff}");
+    assert!(par.compress(&pixels[1..]).is_err());
+}
+
+#[cfg(test)]
+fn compress_sequential(width: usize, height: usize, settings: impl Fn(&mut Compress)) -> Vec<u8> {
+    let mut comp = Compress::new(ColorSpace::JCS_RGB);
+    comp.set_size(width, height);
+    // baseline, but with optimized Huffman tables
+    comp.set_optimize_scans(false);
+    settings(&mut comp);
+    let mut started = comp.start_compress(Vec::new()).unwrap();
+    started.write_scanlines(&test_image(width, height)).unwrap();
+    started.finish().unwrap()
+}
+
+#[test]
+fn parallel_decode() {
+    let (width, height) = (301, 203);
+    let jpegs = [
+        (true, compress_sequential(width, height, |c| c.set_restart_in_rows(1))),
+        (true, compress_sequential(width, height, |c| { c.set_restart_in_rows(3); c.set_chroma_sampling_pixel_sizes((2, 1), (2, 1)); })),
+        (true, compress_sequential(width, height, |c| { c.set_restart_in_rows(2); c.set_color_space(ColorSpace::JCS_GRAYSCALE); })),
+        (true, ParallelCompress::new(ColorSpace::JCS_RGB, width, height).compress(&test_image(width, height)).unwrap()),
+        (false, compress_sequential(width, height, |c| c.set_restart_interval(7))),
+        (false, compress_sequential(width, height, |c| { c.set_restart_in_rows(1); c.set_progressive_mode(); })),
+        (false, compress_sequential(width, height, |_| {})),
+    ];
+    for (i, (is_parallel, jpeg)) in jpegs.iter().enumerate() {
+        let expected: Vec<[u8; 3]> = Decompress::new_mem(jpeg).unwrap().rgb().unwrap().read_scanlines().unwrap();
+        for threads in [1, 2, 3, 8, 100] {
+            let mut dec = ParallelDecompress::new(jpeg).unwrap();
+            assert_eq!(*is_parallel, dec.is_parallel(), "{i}");
+            assert_eq!((width, height), (dec.width(), dec.height()));
+            dec.set_threads(threads);
+            assert_eq!(expected, dec.read_scanlines::<[u8; 3]>(ColorSpace::JCS_RGB).unwrap(), "{i} {threads}");
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! Minimal walker over JPEG marker segments, for splicing files without decoding them.
+use std::io::{self, BufRead};
+
+/// Start of scan
+pub(crate) const SOS: u8 = 0xDA;
+/// Define restart interval
+pub(crate) const DRI: u8 = 0xDD;
+/// Define Huffman tables
+pub(crate) const DHT: u8 = 0xC4;
+pub(crate) const SOI: u8 = 0xD8;
+pub(crate) const EOI: u8 = 0xD9;
+pub(crate) const RST0: u8 = 0xD0;
+
+/// Markers that aren't followed by a length
+#[inline]
+pub(crate) fn is_standalone(marker: u8) -> bool {
+    matches!(marker, 0x01 | 0xD0..=0xD9)
+}
+
+/// Start of frame markers, except DHT, JPG and DAC that share the range
+#[inline]
+pub(crate) fn is_sof(marker: u8) -> bool {
+    matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC)
+}
+
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Segment<'a> {
+    pub(crate) marker: u8,
+    /// Offset of the `0xFF` byte of the marker
+    pub(crate) start: usize,
+    /// Contents after the length field
+    pub(crate) payload: &'a [u8],
+}
+
+impl Segment<'_> {
+    /// Offset of the first byte after the segment. For SOS it's where entropy-coded data starts.
+    #[inline]
+    pub(crate) fn end(&self) -> usize {
+        if is_standalone(self.marker) {
+            self.start + 2
+        } else {
+            self.start + 4 + self.payload.len()
+        }
+    }
+}
+
+/// Iterates markers of a file in order. Entropy-coded data after SOS is skipped,
+/// so all scans of progressive files are found. Stops after EOI or at malformed data.
+pub(crate) struct Segments<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Segments<'a> {
+    #[inline]
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = Segment<'a>;
+
+    fn next(&mut self) -> Option<Segment<'a>> {
+        let data = self.data;
+        if *data.get(self.pos)? != 0xFF {
+            return None;
+        }
+        // any number of 0xFF can pad markers
+        let mut pos = self.pos;
+        while data.get(pos + 1) == Some(&0xFF) {
+            pos += 1;
+        }
+        let marker = *data.get(pos + 1)?;
+        let payload = if is_standalone(marker) {
+            &[][..]
+        } else {
+            let len = usize::from(u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]));
+            data.get(pos + 4..(pos + 2 + len).max(pos + 4))?
+        };
+        let segment = Segment { marker, start: pos, payload };
+        self.pos = match marker {
+            EOI => data.len() + 1,
+            SOS => entropy_data_end(data, segment.end()),
+            _ => segment.end(),
+        };
+        Some(segment)
+    }
+}
+
+/// Offset of the first marker after entropy-coded data starting at `start`,
+/// skipping stuffed zeros and restart markers. Returns length of `data` if there's no marker.
+pub(crate) fn entropy_data_end(data: &[u8], start: usize) -> usize {
+    let mut pos = start;
+    while let Some(found) = data.get(pos..).and_then(|d| d.iter().position(|&b| b == 0xFF)) {
+        pos += found;
+        match data.get(pos + 1) {
+            Some(0x00 | 0xD0..=0xD7 | 0xFF) => pos += 1,
+            Some(_) => return pos,
+            None => break,
+        }
+    }
+    data.len()
+}
+
+/// Consumes bytes up to and including the next SOI marker, skipping padding or other data between images.
+/// Returns `false` if the stream ends first.
+pub(crate) fn skip_to_soi<R: BufRead>(reader: &mut R) -> io::Result<bool> {
+    let mut after_ff = false;
+    loop {
+        let buf = reader.fill_buf()?;
+        let Some(&first) = buf.first() else { return Ok(false) };
+        if after_ff {
+            reader.consume(1);
+            match first {
+                SOI => return Ok(true),
+                0xFF => {},
+                _ => after_ff = false,
+            }
+            continue;
+        }
+        let skip = buf.iter().position(|&b| b == 0xFF);
+        after_ff = skip.is_some();
+        let len = skip.map_or(buf.len(), |pos| pos + 1);
+        reader.consume(len);
+    }
+}
+
+/// Copy of the data with all segments of the given marker removed
+pub(crate) fn without_segments(data: &[u8], marker: u8) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut copied = 0;
+    for s in Segments::new(data).filter(|s| s.marker == marker) {
+        out.extend_from_slice(&data[copied..s.start]);
+        copied = s.end();
+    }
+    out.extend_from_slice(&data[copied..]);
+    out
+}
+
+/// Offset after SOI and the run of markers from `markers` right after it, where a new marker can be inserted.
+/// `None` if the data doesn't start with SOI.
+pub(crate) fn end_of_leading_markers(data: &[u8], markers: &[u8]) -> Option<usize> {
+    let mut segments = Segments::new(data);
+    if segments.next()?.marker != SOI {
+        return None;
+    }
+    let mut end = 2;
+    for s in segments {
+        if s.start != end || !markers.contains(&s.marker) {
+            break;
+        }
+        end = s.end();
+    }
+    Some(end)
+}
+
+/// Offsets of the `0xFF` bytes of restart markers in entropy-coded data
+pub(crate) fn restart_marker_offsets(entropy_data: &[u8]) -> Vec<usize> {
+    let mut offsets = Vec::new();
+    let mut pos = 0;
+    while let Some(found) = entropy_data.get(pos..).and_then(|d| d.iter().position(|&b| b == 0xFF)) {
+        pos += found;
+        if matches!(entropy_data.get(pos + 1), Some(0xD0..=0xD7)) {
+            offsets.push(pos);
+        }
+        pos += 1;
+    }
+    offsets
+}
+
+/// Appends a restart marker with the next number in sequence
+#[inline]
+pub(crate) fn push_restart_marker(out: &mut Vec<u8>, restart_num: &mut u8) {
+    out.extend_from_slice(&[0xFF, RST0 + *restart_num]);
+    *restart_num = (*restart_num + 1) & 7;
+}
+
+/// Appends entropy-coded data, renumbering its restart markers to continue the sequence from `restart_num`
+pub(crate) fn append_renumbered(out: &mut Vec<u8>, entropy_data: &[u8], restart_num: &mut u8) {
+    let mut copied = 0;
+    for rst in restart_marker_offsets(entropy_data) {
+        out.extend_from_slice(&entropy_data[copied..rst]);
+        push_restart_marker(out, restart_num);
+        copied = rst + 2;
+    }
+    out.extend_from_slice(&entropy_data[copied..]);
+}
+
+#[test]
+fn walks_segments() {
+    let data = [
+        0xFF, 0xD8,
+        0xFF, 0xE0, 0, 4, 1, 2,
+        0xFF, 0xFF, 0xDD, 0, 4, 0, 8,
+        0xFF, SOS, 0, 3, 9,
+        1, 0xFF, 0, 2, 0xFF, 0xD3, 3,
+        0xFF, EOI,
+        7,
+    ];
+    let segments: Vec<_> = Segments::new(&data).collect();
+    let markers: Vec<_> = segments.iter().map(|s| s.marker).collect();
+    assert_eq!(markers, [0xD8, 0xE0, 0xDD, SOS, EOI]);
+    assert_eq!(segments[1].payload, [1, 2]);
+    assert_eq!(segments[2].start, 9);
+    assert_eq!(segments[2].payload, [0, 8]);
+    assert_eq!(segments[3].end(), 20);
+    assert_eq!(segments[4].start, 27);
+    assert_eq!(entropy_data_end(&data, 20), 27);
+    assert_eq!(restart_marker_offsets(&data[20..27]), [4]);
+    assert_eq!(without_segments(&data, DRI), [&data[..9], &data[15..]].concat());
+}
+
+#[test]
+fn renumbers() {
+    let mut out = vec![];
+    let mut num = 6;
+    append_renumbered(&mut out, &[1, 0xFF, 0xD0, 2, 0xFF, 0, 0xFF, 0xD1, 3], &mut num);
+    assert_eq!(out, [1, 0xFF, 0xD6, 2, 0xFF, 0, 0xFF, 0xD7, 3]);
+    push_restart_marker(&mut out, &mut num);
+    assert_eq!(out[9..], [0xFF, 0xD0]);
+    assert_eq!(num, 1);
+}
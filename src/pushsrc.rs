@@ -0,0 +1,201 @@
+use crate::{fail, warn};
+use mozjpeg_sys::boolean;
+use mozjpeg_sys::jpeg_decompress_struct;
+use mozjpeg_sys::JERR_VIRTUAL_BUG;
+use mozjpeg_sys::JWRN_JPEG_EOF;
+use mozjpeg_sys::{jpeg_resync_to_restart, jpeg_source_mgr};
+use std::cell::UnsafeCell;
+use std::marker::PhantomPinned;
+use std::os::raw::c_long;
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::ptr;
+
+/// Source manager that suspends libjpeg when it runs out of data,
+/// instead of blocking on a reader.
+///
+/// Data is appended with `push()` between libjpeg calls. libjpeg backs up to the last
+/// point it can restart from when it suspends, so unconsumed bytes are kept in the buffer.
+pub(crate) struct PushSourceMgr {
+    /// Same aliasing rules as in `SourceMgr`: the `iface` is mutated by C via `cinfo.src`.
+    inner_shared: *mut UnsafeCell<PushSourceMgrInner>,
+}
+
+impl UnwindSafe for PushSourceMgr {}
+impl RefUnwindSafe for PushSourceMgr {}
+
+#[repr(C)]
+struct PushSourceMgrInner {
+    iface: jpeg_source_mgr,
+    buf: Vec<u8>,
+    /// `skip_input_data` can't suspend, so skips past the end of the buffer are deferred
+    skip_pending: usize,
+    end_of_input: bool,
+    // jpeg_source_mgr callbacks get a pointer to the struct
+    _pinned: PhantomPinned,
+}
+
+impl PushSourceMgr {
+    #[inline]
+    pub(crate) fn new() -> Self {
+        let src = PushSourceMgrInner {
+            iface: jpeg_source_mgr {
+                next_input_byte: ptr::null(),
+                bytes_in_buffer: 0,
+                init_source: Some(PushSourceMgrInner::init_source),
+                fill_input_buffer: Some(PushSourceMgrInner::fill_input_buffer),
+                skip_input_data: Some(PushSourceMgrInner::skip_input_data),
+                resync_to_restart: Some(jpeg_resync_to_restart),
+                term_source: Some(PushSourceMgrInner::term_source),
+            },
+            buf: Vec::new(),
+            skip_pending: 0,
+            end_of_input: false,
+            _pinned: PhantomPinned,
+        };
+        Self {
+            inner_shared: Box::into_raw(Box::new(UnsafeCell::new(src))),
+        }
+    }
+
+    #[inline]
+    fn inner(&mut self) -> &mut PushSourceMgrInner {
+        // Safety: libjpeg isn't running while `&mut self` is held by Rust code
+        unsafe { &mut *UnsafeCell::raw_get(self.inner_shared) }
+    }
+
+    /// Appends more data. Must not be called while libjpeg is using the buffer.
+    pub fn push(&mut self, mut data: &[u8]) {
+        let inner = self.inner();
+        if inner.end_of_input {
+            return;
+        }
+        let skip = inner.skip_pending.min(data.len());
+        inner.skip_pending -= skip;
+        data = &data[skip..];
+
+        inner.discard_consumed();
+        inner.buf.extend_from_slice(data);
+        inner.iface.next_input_byte = inner.buf.as_ptr();
+        inner.iface.bytes_in_buffer = inner.buf.len();
+    }
+
+    /// After this, running out of data is treated like a truncated file
+    pub fn set_end_of_input(&mut self) {
+        self.inner().end_of_input = true;
+    }
+
+    pub fn is_end_of_input(&mut self) -> bool {
+        self.inner().end_of_input
+    }
+
+    /// Number of bytes pushed, but not consumed by libjpeg yet
+    pub fn bytes_in_buffer(&mut self) -> usize {
+        self.inner().iface.bytes_in_buffer
+    }
+
+    /// Safety: `PushSourceMgr` can only be dropped after `cinfo.src` is set to NULL,
+    /// or otherwise guaranteed not to be used any more via libjpeg.
+    pub unsafe fn iface_c_ptr(&mut self) -> *mut jpeg_source_mgr {
+        debug_assert!(!self.inner_shared.is_null());
+        unsafe {
+            ptr::addr_of_mut!((*UnsafeCell::raw_get(self.inner_shared)).iface)
+        }
+    }
+}
+
+impl Drop for PushSourceMgr {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = Box::from_raw(self.inner_shared);
+        }
+    }
+}
+
+impl PushSourceMgrInner {
+    #[inline]
+    unsafe fn cast(cinfo: &mut jpeg_decompress_struct) -> &mut Self {
+        if let Some(maybe_aliased_src) = cinfo.src.cast::<UnsafeCell<Self>>().as_ref() {
+            // UnsafeCell is intentionally accessed via shared reference. The libjpeg library is single-threaded,
+            // so while there are other pointers to the cell, they're not used concurrently.
+            let this = maybe_aliased_src.get();
+            // Type alias to unify higher-ranked lifetimes
+            type FnPtr<'a> = unsafe extern "C-unwind" fn(cinfo: &'a mut jpeg_decompress_struct);
+            // This is a redundant safety check to ensure the struct is ours
+            #[allow(unknown_lints)]
+            #[allow(unpredictable_function_pointer_comparisons)] // it's the same pointer from the same unit
+            if Some::<FnPtr>(Self::init_source) == (*this).iface.init_source {
+                return &mut *this;
+            }
+        }
+        fail(&mut cinfo.common, JERR_VIRTUAL_BUG);
+    }
+
+    /// Removes bytes before `next_input_byte`, which libjpeg won't go back to
+    fn discard_consumed(&mut self) {
+        if self.buf.is_empty() {
+            return;
+        }
+        let consumed = self.buf.len().saturating_sub(self.iface.bytes_in_buffer);
+        self.buf.drain(..consumed);
+    }
+
+    #[inline(never)]
+    unsafe extern "C-unwind" fn init_source(cinfo: &mut jpeg_decompress_struct) {
+        let _ = Self::cast(cinfo);
+    }
+
+    #[cold]
+    fn set_buffer_to_eoi(&mut self) {
+        // Same as the blocking reader, a truncated file gets a fake EOI
+        self.buf.clear();
+        self.iface.next_input_byte = [0xFF, 0xD9, 0xFF, 0xD9].as_ptr();
+        self.iface.bytes_in_buffer = 4;
+    }
+
+    /// Returning 0 makes libjpeg suspend and return to the caller,
+    /// which will retry after more data is pushed.
+    #[inline(never)]
+    unsafe extern "C-unwind" fn fill_input_buffer(cinfo: &mut jpeg_decompress_struct) -> boolean {
+        let this = Self::cast(cinfo);
+        if !this.end_of_input {
+            return 0;
+        }
+        this.set_buffer_to_eoi();
+        warn(&mut cinfo.common, JWRN_JPEG_EOF);
+        1
+    }
+
+    #[inline(never)]
+    unsafe extern "C-unwind" fn skip_input_data(cinfo: &mut jpeg_decompress_struct, num_bytes: c_long) {
+        if num_bytes <= 0 {
+            return;
+        }
+        let this = Self::cast(cinfo);
+        let num_bytes = usize::try_from(num_bytes).unwrap();
+        let skip_from_buffer = this.iface.bytes_in_buffer.min(num_bytes);
+        this.iface.bytes_in_buffer -= skip_from_buffer;
+        this.iface.next_input_byte = this.iface.next_input_byte.add(skip_from_buffer);
+        this.skip_pending += num_bytes - skip_from_buffer;
+    }
+
+    #[inline(never)]
+    unsafe extern "C-unwind" fn term_source(cinfo: &mut jpeg_decompress_struct) {
+        let _ = Self::cast(cinfo);
+    }
+}
+
+#[test]
+fn push_keeps_unconsumed() {
+    let mut src = PushSourceMgr::new();
+    src.push(b"abcd");
+    assert_eq!(4, src.bytes_in_buffer());
+    unsafe {
+        let iface = &mut *src.iface_c_ptr();
+        iface.next_input_byte = iface.next_input_byte.add(3);
+        iface.bytes_in_buffer -= 3;
+    }
+    src.push(b"ef");
+    assert_eq!(3, src.bytes_in_buffer());
+    let iface = unsafe { &*src.iface_c_ptr() };
+    assert_eq!(b"def", unsafe { std::slice::from_raw_parts(iface.next_input_byte, iface.bytes_in_buffer) });
+}
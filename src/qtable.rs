@@ -2,9 +2,11 @@
 
 use std::cmp::{max, min};
 use std::fmt;
+use std::io;
 use std::os::raw::c_uint;
 type Coef = c_uint;
 
+#[derive(Clone)]
 pub struct QTable {
     pub(crate) coeffs: [Coef; 64],
 }
@@ -28,7 +30,150 @@ const low_weights : [f32; 19] = [
     0.55, 0.10, 0.05,
 ];
 
+/// Natural (row-major) index of each coefficient in zigzag order, as stored in DQT segments
+pub const ZIGZAG_ORDER: [u8; 64] = [
+     0,  1,  8, 16,  9,  2,  3, 10,
+    17, 24, 32, 25, 18, 11,  4,  5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13,  6,  7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Reorders coefficients from natural (row-major) order to zigzag order
+#[must_use]
+pub fn natural_to_zigzag<T: Copy>(natural: &[T; 64]) -> [T; 64] {
+    ZIGZAG_ORDER.map(|i| natural[usize::from(i)])
+}
+
+/// Reorders coefficients from zigzag order to natural (row-major) order
+#[must_use]
+pub fn zigzag_to_natural<T: Copy>(zigzag: &[T; 64]) -> [T; 64] {
+    let mut natural = *zigzag;
+    for (&i, &v) in ZIGZAG_ORDER.iter().zip(zigzag) {
+        natural[usize::from(i)] = v;
+    }
+    natural
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
 impl QTable {
+    /// Table from coefficients in natural (row-major) order. They must be in range 1-65535.
+    pub fn from_natural(coeffs: [u32; 64]) -> io::Result<Self> {
+        if let Some(pos) = coeffs.iter().position(|&c| c == 0 || c > 0xFFFF) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("quantization table value {} at {pos} is out of range 1-65535", coeffs[pos])));
+        }
+        Ok(Self { coeffs })
+    }
+
+    /// Table from coefficients in zigzag order, as they're stored in JPEG files
+    pub fn from_zigzag(coeffs: [u32; 64]) -> io::Result<Self> {
+        Self::from_natural(zigzag_to_natural(&coeffs))
+    }
+
+    /// Coefficients in natural (row-major) order
+    #[must_use]
+    pub fn to_natural(&self) -> [u32; 64] {
+        self.coeffs
+    }
+
+    /// Coefficients in zigzag order
+    #[must_use]
+    pub fn to_zigzag(&self) -> [u32; 64] {
+        natural_to_zigzag(&self.coeffs)
+    }
+
+    /// Coefficient for the given horizontal and vertical frequency (0-7)
+    #[must_use]
+    pub fn get(&self, x: usize, y: usize) -> u32 {
+        assert!(x < 8 && y < 8);
+        self.coeffs[y * 8 + x]
+    }
+
+    /// `true` if all coefficients fit in 8 bits, as required in baseline JPEG
+    #[must_use]
+    pub fn is_baseline(&self) -> bool {
+        self.coeffs.iter().all(|&c| c <= 255)
+    }
+
+    /// DQT segment defining this table in `slot` (0-3), including the marker and length.
+    ///
+    /// Uses 8-bit precision if possible, 16-bit otherwise.
+    #[must_use]
+    pub fn to_dqt(&self, slot: u8) -> Vec<u8> {
+        assert!(slot < 4);
+        let sixteen_bit = !self.is_baseline();
+        let len = 2 + 1 + 64 * if sixteen_bit { 2 } else { 1 };
+        let mut out = Vec::with_capacity(2 + len);
+        out.extend_from_slice(&[0xFF, 0xDB]);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+        out.push(u8::from(sixteen_bit) << 4 | slot);
+        for c in self.to_zigzag() {
+            if sixteen_bit {
+                out.extend_from_slice(&(c as u16).to_be_bytes());
+            } else {
+                out.push(c as u8);
+            }
+        }
+        out
+    }
+
+    /// Parses a DQT segment, which can define multiple tables. Returns slot numbers and tables.
+    ///
+    /// Accepts data with or without the marker and length.
+    pub fn parse_dqt(data: &[u8]) -> io::Result<Vec<(u8, Self)>> {
+        let mut data = match data {
+            [0xFF, 0xDB, len @ ..] if len.len() >= 2 => {
+                let declared = usize::from(u16::from_be_bytes([len[0], len[1]]));
+                data.get(4..2 + declared).ok_or_else(|| invalid_data("truncated DQT"))?
+            },
+            _ => data,
+        };
+        let mut tables = Vec::new();
+        while let Some((&precision_slot, rest)) = data.split_first() {
+            let (precision, slot) = (precision_slot >> 4, precision_slot & 15);
+            if precision > 1 || slot > 3 {
+                return Err(invalid_data(format!("invalid DQT table {precision_slot:#04x}")));
+            }
+            let size = 64 << precision;
+            let values = rest.get(..size).ok_or_else(|| invalid_data("truncated DQT"))?;
+            let mut coeffs = [0; 64];
+            for (c, v) in coeffs.iter_mut().zip(values.chunks_exact(1 << precision)) {
+                *c = v.iter().fold(0, |acc, &b| acc << 8 | u32::from(b));
+            }
+            tables.push((slot, Self::from_zigzag(coeffs).map_err(|e| invalid_data(e.to_string()))?));
+            data = &rest[size..];
+        }
+        Ok(tables)
+    }
+
+    /// Parses tables in the format of cjpeg's `-qtables` file: 64 whitespace-separated numbers per table,
+    /// in natural (row-major) order. Text after `#` is a comment.
+    pub fn parse_text(text: &str) -> io::Result<Vec<Self>> {
+        let mut numbers = Vec::new();
+        for line in text.lines() {
+            let line = line.split_once('#').map_or(line, |(line, _)| line);
+            for word in line.split_whitespace() {
+                numbers.push(word.parse::<u32>().map_err(|_| invalid_data(format!("'{word}' is not a valid quantization table value")))?);
+            }
+        }
+        if numbers.len() % 64 != 0 {
+            return Err(invalid_data(format!("quantization tables must have 64 values each, got {}", numbers.len())));
+        }
+        if numbers.len() > 4 * 64 {
+            return Err(invalid_data("too many quantization tables, at most 4 are allowed"));
+        }
+        numbers.chunks_exact(64)
+            .map(|t| Self::from_natural(t.try_into().unwrap()).map_err(|e| invalid_data(e.to_string())))
+            .collect()
+    }
+
     #[must_use]
     pub fn compare(&self, other: &Self) -> (f32, f32) {
         let mut scales = [0.; 64];
@@ -69,6 +214,12 @@ impl QTable {
         self.coeffs.as_ptr()
     }
 
+    /// Coefficients in natural (row-major) order
+    #[must_use]
+    pub fn as_slice(&self) -> &[c_uint] {
+        &self.coeffs
+    }
+
     // Similar to libjpeg, but result is 100x smaller
     fn quality_scaling(quality: f32) -> f32 {
         assert!(quality > 0. && quality <= 100.);
@@ -202,6 +353,76 @@ pub static ALL_TABLES: [(&str, &QTable); 12] = [
     ("Peterson, Ahumada, Watson", &PetersonAhumadaWatson),
 ];
 
+/// Guess of the quality setting that a file has been encoded with, from its quantization tables.
+///
+/// Useful for avoiding recompressing files at a higher quality than they already have.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityEstimate {
+    /// libjpeg quality (1-100) that gives tables closest to the luma table, scaling `AnnexK_Luma`
+    pub luma_quality: u8,
+    /// libjpeg quality that gives tables closest to the chroma table, scaling `AnnexK_Chroma`
+    pub chroma_quality: Option<u8>,
+    /// How closely libjpeg's tables at these qualities match, from 0 (not at all) to 1 (identical).
+    /// Files from encoders using other tables, such as MozJPEG's defaults, get low values.
+    pub confidence: f32,
+    /// Name of the table from `ALL_TABLES` that has the most similar shape to the luma table
+    pub preset: &'static str,
+    /// Average ratio of luma table's coefficients to the preset's (see `QTable::compare`)
+    pub preset_scale: f32,
+}
+
+impl QualityEstimate {
+    /// Estimates quality from the luma table and the chroma table, if the image has color
+    #[must_use]
+    pub fn from_tables(luma: &QTable, chroma: Option<&QTable>) -> Self {
+        let (luma_quality, luma_error) = closest_libjpeg_quality(luma, &AnnexK_Luma);
+        let chroma = chroma.map(|chroma| closest_libjpeg_quality(chroma, &AnnexK_Chroma));
+        let error = chroma.map_or(luma_error, |(_, chroma_error)| (luma_error + chroma_error) / 2.);
+
+        // differences in scale are fine, but differences in shape mean it's a different table
+        let (preset, preset_scale, _) = ALL_TABLES.iter().map(|&(name, table)| {
+            let (avg, var) = luma.compare(table);
+            (name, avg, if avg > 0. { var / (avg * avg) } else { f32::INFINITY })
+        }).fold(("", 0., f32::INFINITY), |best, t| if t.2 < best.2 { t } else { best });
+
+        Self {
+            luma_quality,
+            chroma_quality: chroma.map(|(q, _)| q),
+            confidence: (1. - error).max(0.),
+            preset,
+            preset_scale,
+        }
+    }
+
+    /// Estimates quality from tables of the first two components.
+    ///
+    /// Components have their tables only after decompression has started. Use `Decompress::estimate_quality()` before that.
+    #[must_use]
+    pub fn from_components(components: &[crate::CompInfo]) -> Option<Self> {
+        use crate::CompInfoExt;
+        let luma = components.first()?.qtable()?;
+        let chroma = components.get(1).and_then(|c| c.qtable());
+        Some(Self::from_tables(&luma, chroma.as_ref()))
+    }
+}
+
+/// `base` scaled the way `jpeg_set_quality()` does it
+fn libjpeg_scaled(base: &QTable, quality: u32, max_value: Coef) -> QTable {
+    let scale = if quality < 50 { 5000 / quality } else { 200 - quality * 2 };
+    QTable { coeffs: base.coeffs.map(|c| ((c * scale + 50) / 100).clamp(1, max_value)) }
+}
+
+/// Quality and relative error (sum of differences divided by sum of coefficients)
+fn closest_libjpeg_quality(table: &QTable, base: &QTable) -> (u8, f32) {
+    // baseline files have 8-bit tables, so libjpeg must have clamped them
+    let max_value = if table.coeffs.iter().all(|&c| c <= 255) { 255 } else { 32767 };
+    (1..=100u8).map(|quality| {
+        let expected = libjpeg_scaled(base, quality.into(), max_value);
+        let diff: Coef = table.coeffs.iter().zip(&expected.coeffs).map(|(&a, &b)| a.abs_diff(b)).sum();
+        (quality, diff as f32 / expected.coeffs.iter().sum::<Coef>() as f32)
+    }).fold((0, f32::INFINITY), |best, q| if q.1 < best.1 { q } else { best })
+}
+
 #[test]
 fn scaling() {
     assert_eq!(QTable { coeffs: [100; 64] }, QTable { coeffs: [100; 64] });
@@ -225,3 +446,46 @@ fn scaling() {
     assert_eq!(QTable { coeffs: [1; 64] }, NRobidoux.scaled(99.9, 99.9));
     assert_eq!(QTable { coeffs: [1; 64] }, MSSSIM_Chroma.scaled(99.8, 99.8));
 }
+
+#[test]
+fn estimates_quality() {
+    for quality in [10, 50, 75, 95, 100] {
+        let luma = libjpeg_scaled(&AnnexK_Luma, quality, 255);
+        let chroma = libjpeg_scaled(&AnnexK_Chroma, quality, 255);
+        let est = QualityEstimate::from_tables(&luma, Some(&chroma));
+        assert_eq!(quality as u8, est.luma_quality);
+        assert_eq!(Some(quality as u8), est.chroma_quality);
+        assert_eq!(1., est.confidence);
+    }
+
+    let est = QualityEstimate::from_tables(&NRobidoux.scaled(80., 80.), None);
+    assert_eq!("N. Robidoux", est.preset);
+    assert!(est.preset_scale < 1.);
+    assert!(est.confidence < 1.);
+    assert_eq!(None, est.chroma_quality);
+}
+
+#[test]
+fn serializes() {
+    assert_eq!(AnnexK_Luma.to_zigzag()[..6], [16, 11, 12, 14, 12, 10]);
+    assert_eq!(AnnexK_Luma, QTable::from_zigzag(AnnexK_Luma.to_zigzag()).unwrap());
+    assert_eq!(11, AnnexK_Luma.get(1, 0));
+    assert!(QTable::from_natural([0; 64]).is_err());
+    assert!(QTable::from_natural([65536; 64]).is_err());
+
+    let dqt = AnnexK_Luma.to_dqt(1);
+    assert_eq!(dqt.len(), 4 + 65);
+    assert_eq!(vec![(1, AnnexK_Luma.clone())], QTable::parse_dqt(&dqt).unwrap());
+
+    let big = QTable::from_natural([300; 64]).unwrap();
+    let mut both = big.to_dqt(0)[4..].to_vec();
+    both.extend_from_slice(&dqt[4..]);
+    assert_eq!(vec![(0, big), (1, AnnexK_Luma.clone())], QTable::parse_dqt(&both).unwrap());
+    assert!(QTable::parse_dqt(&dqt[..40]).is_err());
+
+    let text: String = AnnexK_Luma.coeffs.chunks(8)
+        .map(|row| row.iter().map(|c| format!("{c:3} ")).collect::<String>() + " # row\n").collect();
+    let tables = QTable::parse_text(&format!("# luma\n{text}\n{text}")).unwrap();
+    assert_eq!(vec![AnnexK_Luma.clone(), AnnexK_Luma.clone()], tables);
+    assert!(QTable::parse_text("1 2 3").is_err());
+}
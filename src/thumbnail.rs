@@ -0,0 +1,195 @@
+//! Small preview images embedded in the metadata of JPEG files, see `Decompress::embedded_thumbnail()`.
+use crate::compress::Compress;
+use crate::decompress::Decompress;
+use crate::exif::{Tiff, TAG_JPEG_INTERCHANGE_FORMAT, TAG_JPEG_INTERCHANGE_FORMAT_LENGTH};
+use crate::marker::Marker;
+use std::io;
+
+const JFIF_HEADER: &[u8] = b"JFIF\0";
+const JFXX_HEADER: &[u8] = b"JFXX\0";
+const JFXX_JPEG: u8 = 0x10;
+const JFXX_PALETTE: u8 = 0x11;
+const JFXX_RGB: u8 = 0x13;
+/// Max size of marker data
+const MAX_MARKER_LEN: usize = 65533;
+
+/// Encoding of `Thumbnail::data`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ThumbnailFormat {
+    /// A complete JPEG file
+    Jpeg,
+    /// Uncompressed RGB pixels, 3 bytes per pixel
+    Rgb { width: u8, height: u8 },
+    /// 256-color RGB palette (768 bytes), followed by one palette index per pixel
+    Palette { width: u8, height: u8 },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Thumbnail<'a> {
+    pub format: ThumbnailFormat,
+    pub data: &'a [u8],
+}
+
+impl<R> Decompress<R> {
+    /// Thumbnail from the EXIF (IFD1), JFXX or JFIF metadata, without decoding the image.
+    ///
+    /// Requires the APP0 and APP1 markers to be saved, e.g. with `ALL_MARKERS`.
+    /// Returns `None` if there's no thumbnail, or it's invalid.
+    #[must_use]
+    pub fn embedded_thumbnail(&self) -> Option<Thumbnail<'_>> {
+        self.exif_thumbnail().or_else(|| self.markers()
+            .filter(|m| m.marker == Marker::APP(0))
+            .find_map(|m| jfif_thumbnail(m.data)))
+    }
+
+    fn exif_thumbnail(&self) -> Option<Thumbnail<'_>> {
+        let tiff = Tiff::from_markers(self.markers())?;
+        let ifd1 = tiff.next_ifd(tiff.first_ifd()?)?;
+        let offset = tiff.find_u32(ifd1, TAG_JPEG_INTERCHANGE_FORMAT)? as usize;
+        let len = tiff.find_u32(ifd1, TAG_JPEG_INTERCHANGE_FORMAT_LENGTH)? as usize;
+        let data = tiff.data.get(offset..offset.checked_add(len)?)?;
+        Some(Thumbnail { format: ThumbnailFormat::Jpeg, data })
+    }
+}
+
+/// Thumbnail from APP0 marker data
+fn jfif_thumbnail(data: &[u8]) -> Option<Thumbnail<'_>> {
+    if let Some(p) = data.strip_prefix(JFXX_HEADER) {
+        let (&code, p) = p.split_first()?;
+        return match code {
+            JFXX_JPEG => Some(Thumbnail { format: ThumbnailFormat::Jpeg, data: p }),
+            JFXX_PALETTE => {
+                let (width, height, data) = uncompressed(p, 768, 1)?;
+                Some(Thumbnail { format: ThumbnailFormat::Palette { width, height }, data })
+            },
+            JFXX_RGB => {
+                let (width, height, data) = uncompressed(p, 0, 3)?;
+                Some(Thumbnail { format: ThumbnailFormat::Rgb { width, height }, data })
+            },
+            _ => None,
+        };
+    }
+    // version, units and density come before the thumbnail size
+    let (width, height, data) = uncompressed(data.strip_prefix(JFIF_HEADER)?.get(7..)?, 0, 3)?;
+    Some(Thumbnail { format: ThumbnailFormat::Rgb { width, height }, data })
+}
+
+/// Width, height, and data of a non-empty uncompressed thumbnail
+fn uncompressed(p: &[u8], palette_len: usize, bytes_per_pixel: usize) -> Option<(u8, u8, &[u8])> {
+    let (&[width, height], data) = (p.get(..2)?, &p[2..]) else { return None };
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let len = palette_len + usize::from(width) * usize::from(height) * bytes_per_pixel;
+    Some((width, height, data.get(..len)?))
+}
+
+impl Compress {
+    /// Generates a JPEG thumbnail that fits in `max_size`×`max_size` pixels, which `start_compress()` embeds in a JFXX marker.
+    ///
+    /// Call it after `set_size()`. `pixels` must be the whole image, in the same format as for `write_scanlines()`.
+    /// The file will declare JFIF version 1.02, which added JFXX, and `start_compress()` will fail if the JFIF header is disabled.
+    ///
+    /// Fails if the thumbnail doesn't fit in a marker.
+    ///
+    /// ## Panics
+    ///
+    /// It may panic, like all functions of this library.
+    pub fn set_thumbnail(&mut self, pixels: &[u8], max_size: usize) -> io::Result<()> {
+        let cinfo = &self.cinfo;
+        let (width, height) = (cinfo.image_width as usize, cinfo.image_height as usize);
+        let components = cinfo.input_components.max(0) as usize;
+        if max_size == 0 || components == 0 || pixels.len() != width * height * components {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+
+        let scale = ((width.max(height) + max_size - 1) / max_size).max(1);
+        let (thumb_width, thumb_height) = ((width + scale - 1) / scale, (height + scale - 1) / scale);
+        let mut thumb_pixels = Vec::with_capacity(thumb_width * thumb_height * components);
+        let mut sums = vec![0u64; components];
+        for ty in 0..thumb_height {
+            let rows = ty * scale..((ty + 1) * scale).min(height);
+            for tx in 0..thumb_width {
+                let cols = tx * scale..((tx + 1) * scale).min(width);
+                sums.fill(0);
+                for y in rows.clone() {
+                    let row = &pixels[(y * width + cols.start) * components..(y * width + cols.end) * components];
+                    for px in row.chunks_exact(components) {
+                        sums.iter_mut().zip(px).for_each(|(s, &v)| *s += u64::from(v));
+                    }
+                }
+                let count = (rows.len() * cols.len()) as u64;
+                thumb_pixels.extend(sums.iter().map(|&s| ((s + count / 2) / count) as u8));
+            }
+        }
+
+        let mut thumb = Compress::new(cinfo.in_color_space);
+        thumb.set_size(thumb_width, thumb_height);
+        // JFIF doesn't allow JFIF markers in the thumbnail
+        thumb.cinfo.write_JFIF_header = 0;
+        let mut started = thumb.start_compress([JFXX_HEADER, &[JFXX_JPEG]].concat())?;
+        started.write_scanlines(&thumb_pixels)?;
+        let data = started.finish()?;
+        if data.len() > MAX_MARKER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "thumbnail doesn't fit in a JFXX marker"));
+        }
+        self.thumbnail = Some(data);
+        Ok(())
+    }
+}
+
+#[test]
+fn embeds_and_extracts_thumbnails() {
+    use crate::{ColorSpace, ALL_MARKERS};
+
+    let (width, height) = (64, 48);
+    let pixels: Vec<u8> = (0..width * height).flat_map(|i| [(i % width * 4) as u8, (i / width * 5) as u8, 99]).collect();
+    let mut comp = Compress::new(ColorSpace::JCS_RGB);
+    comp.set_size(width, height);
+    comp.set_thumbnail(&pixels, 16).unwrap();
+    let mut started = comp.start_compress(Vec::new()).unwrap();
+    started.write_scanlines(&pixels).unwrap();
+    let jpeg = started.finish().unwrap();
+    // APP0 JFIF version 1.02, followed by APP0 JFXX
+    assert_eq!(b"JFIF\0\x01\x02", &jpeg[6..13]);
+    assert_eq!(b"JFXX\0", &jpeg[24..29]);
+
+    assert!(Decompress::new_mem(&jpeg).unwrap().embedded_thumbnail().is_none());
+    let dec = Decompress::with_markers(ALL_MARKERS).from_mem(&jpeg).unwrap();
+    let thumb = dec.embedded_thumbnail().unwrap();
+    assert_eq!(ThumbnailFormat::Jpeg, thumb.format);
+    let mut thumb_dec = Decompress::new_mem(thumb.data).unwrap().rgb().unwrap();
+    assert_eq!((16, 12), (thumb_dec.width(), thumb_dec.height()));
+    let thumb_pixels: Vec<[u8; 3]> = thumb_dec.read_scanlines().unwrap();
+    // averages of 4x4 blocks, with chroma subsampled
+    assert!(thumb_pixels[0][2].abs_diff(99) < 24);
+    assert!(thumb_pixels[16 * 12 - 1][0].abs_diff(246) < 12);
+    assert!(thumb_pixels[16 * 12 - 1][1].abs_diff(228) < 12);
+
+    // EXIF IFD1 with JPEGInterchangeFormat, big-endian TIFF with empty IFD0
+    let mut exif = b"Exif\0\0MM\0\x2A\0\0\0\x08\0\0\0\0\0\x0E\0\x02".to_vec();
+    exif.extend_from_slice(&[0x02, 0x01, 0, 4, 0, 0, 0, 1, 0, 0, 0, 44]);
+    exif.extend_from_slice(&[0x02, 0x02, 0, 4, 0, 0, 0, 1]);
+    exif.extend_from_slice(&(thumb.data.len() as u32).to_be_bytes());
+    exif.extend_from_slice(&[0; 4]);
+    exif.extend_from_slice(thumb.data);
+    let mut comp = Compress::new(ColorSpace::JCS_GRAYSCALE);
+    comp.set_size(8, 8);
+    assert!(comp.set_thumbnail(&[0; 7], 4).is_err());
+    let mut cmyk = Compress::new(ColorSpace::JCS_CMYK);
+    cmyk.set_size(8, 8);
+    cmyk.set_thumbnail(&[0; 8 * 8 * 4], 4).unwrap();
+    assert!(cmyk.start_compress(Vec::new()).is_err());
+    let mut started = comp.start_compress(Vec::new()).unwrap();
+    started.write_marker(Marker::APP(1), &exif);
+    started.write_scanlines(&[0; 64]).unwrap();
+    let with_exif = started.finish().unwrap();
+    let dec = Decompress::with_markers(ALL_MARKERS).from_mem(&with_exif).unwrap();
+    assert_eq!(Some(Thumbnail { format: ThumbnailFormat::Jpeg, data: thumb.data }), dec.embedded_thumbnail());
+
+    assert_eq!(None, jfif_thumbnail(b"JFIF\0\x01\x02\0\0\x01\0\x01\0\0"));
+    assert_eq!(Some(Thumbnail { format: ThumbnailFormat::Rgb { width: 1, height: 2 }, data: &[1, 2, 3, 4, 5, 6] }),
+        jfif_thumbnail(b"JFIF\0\x01\x02\0\0\x01\0\x01\x01\x02\x01\x02\x03\x04\x05\x06"));
+    assert_eq!(Some(ThumbnailFormat::Rgb { width: 1, height: 1 }), jfif_thumbnail(b"JFXX\0\x13\x01\x01abc").map(|t| t.format));
+    assert_eq!(None, jfif_thumbnail(b"JFXX\0\x11\x01\x01abc"));
+}
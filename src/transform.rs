@@ -0,0 +1,376 @@
+//! Lossless operations on DCT coefficients, like `jpegtran`. Requires the `jpegtran` feature.
+//!
+//! Files can be re-optimized (Huffman tables and progressive scans), rotated, flipped, cropped
+//! and converted to grayscale without decoding to pixels, so there's no generation loss.
+use crate::compress::Compress;
+use crate::decompress::Decompress;
+use crate::ffi;
+use crate::ffi::{boolean, jpeg_compress_struct, jpeg_decompress_struct, jvirt_barray_ptr, JDIMENSION};
+use crate::marker::Marker;
+use crate::ALL_MARKERS;
+use std::io::{self, BufRead, Write};
+use std::os::raw::c_int;
+
+/// Geometric transformation, see `Transform::set_operation`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Operation {
+    #[default]
+    None,
+    /// Mirror left-right
+    FlipHorizontal,
+    /// Mirror top-bottom
+    FlipVertical,
+    /// Flip across the top-left to bottom-right diagonal
+    Transpose,
+    /// Flip across the top-right to bottom-left diagonal
+    Transverse,
+    /// Clockwise
+    Rotate90,
+    Rotate180,
+    /// Clockwise, i.e. 90° counter-clockwise
+    Rotate270,
+}
+
+impl From<Operation> for ffi::JXFORM_CODE {
+    fn from(op: Operation) -> Self {
+        match op {
+            Operation::None => ffi::JXFORM_CODE_JXFORM_NONE,
+            Operation::FlipHorizontal => ffi::JXFORM_CODE_JXFORM_FLIP_H,
+            Operation::FlipVertical => ffi::JXFORM_CODE_JXFORM_FLIP_V,
+            Operation::Transpose => ffi::JXFORM_CODE_JXFORM_TRANSPOSE,
+            Operation::Transverse => ffi::JXFORM_CODE_JXFORM_TRANSVERSE,
+            Operation::Rotate90 => ffi::JXFORM_CODE_JXFORM_ROT_90,
+            Operation::Rotate180 => ffi::JXFORM_CODE_JXFORM_ROT_180,
+            Operation::Rotate270 => ffi::JXFORM_CODE_JXFORM_ROT_270,
+        }
+    }
+}
+
+/// Which markers (metadata) are copied from the source file, see `Transform::set_copy_markers`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum CopyMarkers {
+    /// Strip all metadata
+    None,
+    /// Only COM markers
+    Comments,
+    /// All APPn and COM markers
+    #[default]
+    All,
+    /// All, except the ICC profile
+    AllExceptIcc,
+    /// Only the ICC profile
+    Icc,
+}
+
+impl CopyMarkers {
+    fn markers(self) -> &'static [Marker] {
+        match self {
+            Self::None => &[],
+            Self::Comments => &[Marker::COM],
+            Self::All | Self::AllExceptIcc => ALL_MARKERS,
+            Self::Icc => &[Marker::APP(2)],
+        }
+    }
+}
+
+impl From<CopyMarkers> for ffi::JCOPY_OPTION {
+    fn from(copy: CopyMarkers) -> Self {
+        match copy {
+            CopyMarkers::None => ffi::JCOPY_OPTION_JCOPYOPT_NONE,
+            CopyMarkers::Comments => ffi::JCOPY_OPTION_JCOPYOPT_COMMENTS,
+            CopyMarkers::All => ffi::JCOPY_OPTION_JCOPYOPT_ALL,
+            CopyMarkers::AllExceptIcc => ffi::JCOPY_OPTION_JCOPYOPT_ALL_EXCEPT_ICC,
+            CopyMarkers::Icc => ffi::JCOPY_OPTION_JCOPYOPT_ICC,
+        }
+    }
+}
+
+/// Rewrites JPEG files losslessly
+///
+/// By default it only optimizes the file: makes it progressive with MozJPEG's scan optimization,
+/// with optimized Huffman tables, and keeps all metadata.
+///
+/// ```rust
+/// # fn t(jpeg: &[u8]) -> std::io::Result<()> {
+/// use mozjpeg::transform::{Operation, Transform};
+/// let mut transform = Transform::new();
+/// transform.set_operation(Operation::Rotate90);
+/// transform.set_trim(true);
+/// let rotated = transform.transform_mem(jpeg)?;
+/// # Ok(()) }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Transform {
+    operation: Operation,
+    perfect: bool,
+    trim: bool,
+    grayscale: bool,
+    crop: Option<[usize; 4]>,
+    progressive: bool,
+    copy_markers: CopyMarkers,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transform {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            operation: Operation::None,
+            perfect: false,
+            trim: false,
+            grayscale: false,
+            crop: None,
+            progressive: true,
+            copy_markers: CopyMarkers::All,
+        }
+    }
+
+    /// Rotation or flip to apply
+    ///
+    /// Partial MCU blocks at the right and bottom edges can't be moved losslessly, and by default stay in place.
+    /// See `set_trim` and `set_perfect`.
+    pub fn set_operation(&mut self, operation: Operation) {
+        self.operation = operation;
+    }
+
+    /// If `true`, fail instead of leaving untransformable edge blocks in place
+    pub fn set_perfect(&mut self, perfect: bool) {
+        self.perfect = perfect;
+    }
+
+    /// If `true`, drop the untransformable edge blocks, making the image slightly smaller
+    pub fn set_trim(&mut self, trim: bool) {
+        self.trim = trim;
+    }
+
+    /// Drop color components, keeping only luma
+    pub fn set_grayscale(&mut self, grayscale: bool) {
+        self.grayscale = grayscale;
+    }
+
+    /// Crop to the `width`x`height` rectangle at `x`,`y` (of the transformed image).
+    /// The top-left corner is moved to the nearest MCU boundary above and to the left.
+    pub fn set_crop(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        self.crop = Some([x, y, width, height]);
+    }
+
+    /// Progressive with MozJPEG's scan optimization (default), or baseline if `false`.
+    /// Huffman tables are optimized in both cases.
+    pub fn set_progressive(&mut self, progressive: bool) {
+        self.progressive = progressive;
+    }
+
+    /// Metadata to keep. All markers are copied by default.
+    pub fn set_copy_markers(&mut self, copy_markers: CopyMarkers) {
+        self.copy_markers = copy_markers;
+    }
+
+    /// `true` if it changes the image, rather than only optimizing how it's stored
+    #[must_use]
+    pub fn changes_image(&self) -> bool {
+        self.operation != Operation::None || self.grayscale || self.crop.is_some()
+    }
+
+    /// Transforms a whole JPEG file in memory
+    ///
+    /// ## Panics
+    ///
+    /// It may panic, like all functions of this library.
+    pub fn transform_mem(&self, jpeg: &[u8]) -> io::Result<Vec<u8>> {
+        self.transform(jpeg, Vec::with_capacity(jpeg.len()))
+    }
+
+    /// Reads a JPEG file from `reader` and writes the transformed file to `writer`
+    ///
+    /// ## Panics
+    ///
+    /// It may panic, like all functions of this library.
+    pub fn transform<R: BufRead, W: Write>(&self, reader: R, writer: W) -> io::Result<W> {
+        let mut dec = Decompress::builder().with_markers(self.copy_markers.markers()).from_reader(reader)?;
+        let mut info = self.transform_info();
+        unsafe {
+            if jtransform_request_workspace(&mut dec.cinfo, &mut info) == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "transformation is not perfect"));
+            }
+            let src_coefs = ffi::jpeg_read_coefficients(&mut dec.cinfo);
+            if src_coefs.is_null() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "no image in the JPEG file"));
+            }
+
+            // Settings are replaced by jpeg_copy_critical_parameters
+            let mut comp = Compress::new(dec.color_space());
+            ffi::jpeg_copy_critical_parameters(&dec.cinfo, &mut comp.cinfo);
+            let dst_coefs = jtransform_adjust_parameters(&mut dec.cinfo, &mut comp.cinfo, src_coefs, &mut info);
+            // The scan script has to be made for the adjusted number of components
+            if self.progressive {
+                comp.set_progressive_mode();
+            } else {
+                comp.set_optimize_scans(false);
+            }
+            comp.set_optimize_coding(true);
+
+            // Coefficient arrays belong to the decompressor, which is dropped later
+            let mut started = comp.start_compress_coefficients(writer, dst_coefs);
+            jcopy_markers_execute(&mut dec.cinfo, &mut started.compress.cinfo, self.copy_markers.into());
+            jtransform_execute_transform(&mut dec.cinfo, &mut started.compress.cinfo, src_coefs, &mut info);
+            started.finish()
+        }
+    }
+
+    fn transform_info(&self) -> TransformInfo {
+        let crop = self.crop.unwrap_or_default().map(|v| v as JDIMENSION);
+        let crop_set = if self.crop.is_some() { ffi::JCROP_CODE_JCROP_POS } else { ffi::JCROP_CODE_JCROP_UNSET };
+        TransformInfo {
+            transform: self.operation.into(),
+            perfect: boolean::from(self.perfect),
+            trim: boolean::from(self.trim),
+            force_grayscale: boolean::from(self.grayscale),
+            crop: boolean::from(self.crop.is_some()),
+            slow_hflip: boolean::from(false),
+            crop_xoffset: crop[0],
+            crop_xoffset_set: crop_set,
+            crop_yoffset: crop[1],
+            crop_yoffset_set: crop_set,
+            crop_width: crop[2],
+            crop_width_set: crop_set,
+            crop_height: crop[3],
+            crop_height_set: crop_set,
+            drop_ptr: std::ptr::null_mut(),
+            drop_coef_arrays: std::ptr::null_mut(),
+            num_components: 0,
+            workspace_coef_arrays: std::ptr::null_mut(),
+            output_width: 0,
+            output_height: 0,
+            x_crop_offset: 0,
+            y_crop_offset: 0,
+            drop_width: 0,
+            drop_height: 0,
+            iMCU_sample_width: 0,
+            iMCU_sample_height: 0,
+        }
+    }
+}
+
+/// `jpeg_transform_info` from `transupp.h`.
+///
+/// The definition in `mozjpeg-sys` lacks the `drop_*` fields, so it can't be passed to `transupp.c`.
+#[repr(C)]
+#[allow(non_snake_case)]
+struct TransformInfo {
+    transform: ffi::JXFORM_CODE,
+    perfect: boolean,
+    trim: boolean,
+    force_grayscale: boolean,
+    crop: boolean,
+    slow_hflip: boolean,
+    crop_width: JDIMENSION,
+    crop_width_set: ffi::JCROP_CODE,
+    crop_height: JDIMENSION,
+    crop_height_set: ffi::JCROP_CODE,
+    crop_xoffset: JDIMENSION,
+    crop_xoffset_set: ffi::JCROP_CODE,
+    crop_yoffset: JDIMENSION,
+    crop_yoffset_set: ffi::JCROP_CODE,
+    drop_ptr: *mut jpeg_decompress_struct,
+    drop_coef_arrays: *mut jvirt_barray_ptr,
+    num_components: c_int,
+    workspace_coef_arrays: *mut jvirt_barray_ptr,
+    output_width: JDIMENSION,
+    output_height: JDIMENSION,
+    x_crop_offset: JDIMENSION,
+    y_crop_offset: JDIMENSION,
+    drop_width: JDIMENSION,
+    drop_height: JDIMENSION,
+    iMCU_sample_width: c_int,
+    iMCU_sample_height: c_int,
+}
+
+extern "C-unwind" {
+    fn jtransform_request_workspace(srcinfo: &mut jpeg_decompress_struct, info: &mut TransformInfo) -> boolean;
+    fn jtransform_adjust_parameters(srcinfo: &mut jpeg_decompress_struct, dstinfo: &mut jpeg_compress_struct, src_coef_arrays: *mut jvirt_barray_ptr, info: &mut TransformInfo) -> *mut jvirt_barray_ptr;
+    fn jtransform_execute_transform(srcinfo: &mut jpeg_decompress_struct, dstinfo: &mut jpeg_compress_struct, src_coef_arrays: *mut jvirt_barray_ptr, info: &mut TransformInfo);
+    fn jcopy_markers_execute(srcinfo: &mut jpeg_decompress_struct, dstinfo: &mut jpeg_compress_struct, option: ffi::JCOPY_OPTION);
+}
+
+#[cfg(test)]
+fn test_image(width: usize, height: usize, progressive: bool) -> Vec<u8> {
+    let pixels: Vec<u8> = (0..width * height).flat_map(|i| [(i % width) as u8, (i / width) as u8, 200]).collect();
+    let mut comp = Compress::new(crate::ColorSpace::JCS_RGB);
+    comp.set_size(width, height);
+    comp.set_quality(90.);
+    if !progressive {
+        comp.set_optimize_scans(false);
+        comp.set_optimize_coding(false);
+        comp.set_trellis_quantization(false);
+    }
+    let mut started = comp.start_compress(Vec::new()).unwrap();
+    started.write_marker(Marker::COM, b"comment");
+    started.write_icc_profile(&[1; 100]);
+    started.write_scanlines(&pixels).unwrap();
+    started.finish().unwrap()
+}
+
+#[test]
+fn optimizes_losslessly() {
+    let original = test_image(64, 48, false);
+    let optimized = Transform::new().transform_mem(&original).unwrap();
+    assert!(optimized.len() < original.len(), "{} {}", optimized.len(), original.len());
+
+    let decode = |jpeg: &[u8]| {
+        let dec = Decompress::builder().with_markers(ALL_MARKERS).from_mem(jpeg).unwrap();
+        let markers = dec.markers().count();
+        let pixels: Vec<[u8; 3]> = dec.rgb().unwrap().read_scanlines().unwrap();
+        (pixels, markers)
+    };
+    let (pixels, markers) = decode(&original);
+    assert_eq!((pixels.clone(), markers), decode(&optimized));
+
+    let mut stripped = Transform::new();
+    stripped.set_copy_markers(CopyMarkers::Comments);
+    stripped.set_progressive(false);
+    let stripped = stripped.transform_mem(&original).unwrap();
+    let dec = Decompress::builder().with_markers(ALL_MARKERS).from_mem(&stripped).unwrap();
+    assert_eq!(b"comment", dec.markers().find(|m| m.marker == Marker::COM).unwrap().data);
+    assert!(dec.icc_profile().is_none());
+    assert_eq!(pixels, dec.rgb().unwrap().read_scanlines::<[u8; 3]>().unwrap());
+}
+
+#[test]
+fn rotates() {
+    let (width, height) = (64, 48);
+    let original = test_image(width, height, true);
+    let original_pixels: Vec<[u8; 3]> = Decompress::new_mem(&original).unwrap().rgb().unwrap().read_scanlines().unwrap();
+
+    let mut transform = Transform::new();
+    transform.set_operation(Operation::Rotate90);
+    assert!(transform.changes_image());
+    let rotated = transform.transform_mem(&original).unwrap();
+    let dec = Decompress::new_mem(&rotated).unwrap();
+    assert_eq!((height, width), dec.size());
+    let rotated_pixels: Vec<[u8; 3]> = dec.rgb().unwrap().read_scanlines().unwrap();
+    // pixel at x,y moves to height-1-y,x
+    let (x, y) = (50, 10);
+    let before = original_pixels[y * width + x];
+    let after = rotated_pixels[x * height + (height - 1 - y)];
+    assert!(before.iter().zip(after).all(|(&a, b)| a.abs_diff(b) < 4), "{before:?} {after:?}");
+
+    transform.set_operation(Operation::FlipHorizontal);
+    transform.set_perfect(true);
+    assert!(transform.transform_mem(&test_image(60, 48, true)).is_err());
+    transform.set_perfect(false);
+    transform.set_trim(true);
+    assert_eq!((48, 48), Decompress::new_mem(&transform.transform_mem(&test_image(60, 48, true)).unwrap()).unwrap().size());
+
+    let mut crop = Transform::new();
+    crop.set_crop(16, 16, 20, 10);
+    crop.set_grayscale(true);
+    let cropped = crop.transform_mem(&original).unwrap();
+    let cropped = Decompress::new_mem(&cropped).unwrap();
+    assert_eq!((20, 10), cropped.size());
+    assert_eq!(1, cropped.components().len());
+}
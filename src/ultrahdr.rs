@@ -0,0 +1,424 @@
+//! Ultra HDR JPEG files, which have an SDR primary image and a secondary gain map image
+//! that lets HDR displays reconstruct the HDR rendition.
+//!
+//! The gain map is appended after the primary image and listed in its MPF index (see `mpf`).
+//! Parameters of the gain map are in the XMP of the gain map image (Adobe's `hdrgm` namespace),
+//! and the primary image's XMP lists both images in a Google Container directory.
+use crate::compress::CompressStarted;
+use crate::decompress::Decompress;
+use crate::mpf::{MpImageType, MpIndex, MpfWriter};
+use crate::parse::{end_of_leading_markers, Segments, SOS};
+use std::fmt::Write as _;
+use std::io;
+
+/// Start of the APP1 marker data with XMP
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+/// How to apply the gain map, from the `hdrgm` XMP namespace.
+///
+/// Gains and HDR capacities are in log2 space. Per-channel values are in RGB order,
+/// and are all the same for single-channel gain maps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GainMapMetadata {
+    pub version: String,
+    /// The primary image is HDR, and the gain map maps it to SDR
+    pub base_rendition_is_hdr: bool,
+    /// Gain encoded by 0 in the gain map
+    pub gain_map_min: [f32; 3],
+    /// Gain encoded by 255 in the gain map
+    pub gain_map_max: [f32; 3],
+    /// Gamma applied to the gain map values
+    pub gamma: [f32; 3],
+    /// Added to the SDR pixels before applying the gain
+    pub offset_sdr: [f32; 3],
+    /// Subtracted from the HDR pixels after applying the gain
+    pub offset_hdr: [f32; 3],
+    /// Display HDR headroom at which the gain map starts to be applied
+    pub hdr_capacity_min: f32,
+    /// Display HDR headroom at which the gain map is fully applied
+    pub hdr_capacity_max: f32,
+}
+
+impl Default for GainMapMetadata {
+    fn default() -> Self {
+        Self {
+            version: "1.0".into(),
+            base_rendition_is_hdr: false,
+            gain_map_min: [0.; 3],
+            gain_map_max: [1.; 3],
+            gamma: [1.; 3],
+            offset_sdr: [1. / 64.; 3],
+            offset_hdr: [1. / 64.; 3],
+            hdr_capacity_min: 0.,
+            hdr_capacity_max: 1.,
+        }
+    }
+}
+
+impl GainMapMetadata {
+    /// Parses the XMP packet of the gain map image (without the APP1 XMP namespace header).
+    ///
+    /// Properties can be attributes or elements, but must use the conventional `hdrgm` prefix.
+    /// Missing optional properties get their default values.
+    pub fn from_xmp(xmp: &[u8]) -> io::Result<Self> {
+        let xmp = std::str::from_utf8(xmp).map_err(|_| invalid("XMP is not UTF-8"))?;
+        let default = Self::default();
+        if xmp_values(xmp, "hdrgm:GainMapMax").is_none() {
+            return Err(invalid("XMP has no hdrgm:GainMapMax"));
+        }
+        let rgb = |name: &str, default: [f32; 3]| -> io::Result<[f32; 3]> {
+            let Some(values) = xmp_values(xmp, name) else { return Ok(default) };
+            let values = values.iter().map(|v| v.parse::<f32>()).collect::<Result<Vec<_>, _>>()
+                .map_err(|_| invalid("invalid number in gain map XMP"))?;
+            match values[..] {
+                [v] => Ok([v; 3]),
+                [r, g, b] => Ok([r, g, b]),
+                _ => Err(invalid("gain map XMP needs 1 or 3 values")),
+            }
+        };
+        let gain_map_max = rgb("hdrgm:GainMapMax", default.gain_map_max)?;
+        Ok(Self {
+            version: xmp_values(xmp, "hdrgm:Version").and_then(|v| v.first().map(|&v| v.into())).unwrap_or(default.version),
+            base_rendition_is_hdr: xmp_values(xmp, "hdrgm:BaseRenditionIsHDR").is_some_and(|v| v.first().is_some_and(|v| v.eq_ignore_ascii_case("true"))),
+            gain_map_min: rgb("hdrgm:GainMapMin", default.gain_map_min)?,
+            gamma: rgb("hdrgm:Gamma", default.gamma)?,
+            offset_sdr: rgb("hdrgm:OffsetSDR", default.offset_sdr)?,
+            offset_hdr: rgb("hdrgm:OffsetHDR", default.offset_hdr)?,
+            hdr_capacity_min: rgb("hdrgm:HDRCapacityMin", [default.hdr_capacity_min; 3])?[0],
+            // the spec requires it, but it's commonly the same as the max gain
+            hdr_capacity_max: rgb("hdrgm:HDRCapacityMax", gain_map_max)?[0],
+            gain_map_max,
+        })
+    }
+
+    /// XMP packet for the gain map image
+    #[must_use]
+    pub fn to_xmp(&self) -> Vec<u8> {
+        let mut attributes = String::new();
+        let mut elements = String::new();
+        let _ = write!(attributes, "\n    hdrgm:Version=\"{}\"", xml_escape(&self.version));
+        let _ = write!(attributes, "\n    hdrgm:BaseRenditionIsHDR=\"{}\"", if self.base_rendition_is_hdr { "True" } else { "False" });
+        for (name, values) in [
+            ("GainMapMin", self.gain_map_min),
+            ("GainMapMax", self.gain_map_max),
+            ("Gamma", self.gamma),
+            ("OffsetSDR", self.offset_sdr),
+            ("OffsetHDR", self.offset_hdr),
+        ] {
+            if values[1..].iter().all(|&v| v == values[0]) {
+                let _ = write!(attributes, "\n    hdrgm:{name}=\"{}\"", values[0]);
+            } else {
+                let _ = write!(elements, "\n   <hdrgm:{name}><rdf:Seq><rdf:li>{}</rdf:li><rdf:li>{}</rdf:li><rdf:li>{}</rdf:li></rdf:Seq></hdrgm:{name}>",
+                    values[0], values[1], values[2]);
+            }
+        }
+        let _ = write!(attributes, "\n    hdrgm:HDRCapacityMin=\"{}\"", self.hdr_capacity_min);
+        let _ = write!(attributes, "\n    hdrgm:HDRCapacityMax=\"{}\"", self.hdr_capacity_max);
+        xmp_packet(&format!(
+            "  <rdf:Description rdf:about=\"\"\n    xmlns:hdrgm=\"http://ns.adobe.com/hdr-gain-map/1.0/\"{attributes}>{elements}\n  </rdf:Description>\n"))
+    }
+}
+
+/// Primary image and gain map found in an Ultra HDR file
+#[derive(Debug, Clone)]
+pub struct UltraHdr<'a> {
+    /// The whole primary JPEG file, usually the SDR rendition
+    pub primary: &'a [u8],
+    /// JPEG file of the gain map, grayscale or RGB
+    pub gain_map: &'a [u8],
+    pub metadata: GainMapMetadata,
+}
+
+impl<'a> UltraHdr<'a> {
+    /// Finds the gain map in the file. Returns `None` if the file doesn't have one.
+    ///
+    /// The gain map is located via the MPF index, or the XMP container directory if there's no MPF.
+    pub fn from_mem(file: &'a [u8]) -> io::Result<Option<Self>> {
+        let with_metadata = |gain_map: &'a [u8]| -> io::Result<Option<Self>> {
+            let Some(xmp) = find_xmp(gain_map) else { return Ok(None) };
+            if !contains(xmp, b"hdrgm:GainMapMax") {
+                return Ok(None);
+            }
+            Ok(Some(Self { primary: file, gain_map, metadata: GainMapMetadata::from_xmp(xmp)? }))
+        };
+
+        if let Some(index) = MpIndex::from_mem(file)? {
+            let primary = index.entries.first().and_then(|e| e.data(file)).unwrap_or(file);
+            for entry in index.entries.iter().skip(1) {
+                if let Some(mut found) = entry.data(file).map(with_metadata).transpose()?.flatten() {
+                    found.primary = primary;
+                    return Ok(Some(found));
+                }
+            }
+            return Ok(None);
+        }
+
+        // without MPF, items of the container directory follow the primary image, each followed by its padding
+        let Some(xmp) = find_xmp(file).and_then(|x| std::str::from_utf8(x).ok()) else { return Ok(None) };
+        let items: Vec<_> = container_items(xmp).collect();
+        let is_gain_map = |item: &&str| xmp_values(item, "Item:Semantic").is_some_and(|v| v == ["GainMap"]);
+        let Some(gain_map_pos) = items.iter().position(is_gain_map) else { return Ok(None) };
+        let size = |item: &str, name| xmp_values(item, name).and_then(|v| v.first()?.parse::<usize>().ok());
+        let mut end = file.len();
+        for item in items[gain_map_pos + 1..].iter().rev() {
+            let length = size(item, "Item:Length").ok_or_else(|| invalid("container item without Item:Length"))?;
+            end = length.checked_add(size(item, "Item:Padding").unwrap_or(0))
+                .and_then(|item_size| end.checked_sub(item_size))
+                .ok_or_else(|| invalid("container items are outside of the file"))?;
+        }
+        let gain_map = items[gain_map_pos];
+        let length = size(gain_map, "Item:Length").ok_or_else(|| invalid("gain map item without Item:Length"))?;
+        let start = end.checked_sub(size(gain_map, "Item:Padding").unwrap_or(0))
+            .and_then(|end| end.checked_sub(length))
+            .ok_or_else(|| invalid("gain map is outside of the file"))?;
+        let Some(mut found) = with_metadata(&file[start..start + length])? else { return Ok(None) };
+        found.primary = &file[..start];
+        Ok(Some(found))
+    }
+
+    /// Decoder for the primary image
+    pub fn decompress_primary(&self) -> io::Result<Decompress<&'a [u8]>> {
+        Decompress::new_mem(self.primary)
+    }
+
+    /// Decoder for the gain map image
+    pub fn decompress_gain_map(&self) -> io::Result<Decompress<&'a [u8]>> {
+        Decompress::new_mem(self.gain_map)
+    }
+}
+
+/// Makes Ultra HDR files from a primary SDR image and an already-computed gain map image
+#[derive(Debug, Clone)]
+pub struct UltraHdrWriter {
+    metadata: GainMapMetadata,
+}
+
+impl UltraHdrWriter {
+    #[must_use]
+    pub fn new(metadata: GainMapMetadata) -> Self {
+        Self { metadata }
+    }
+
+    /// Finishes compression of both images, and returns the whole Ultra HDR file
+    pub fn finish(&self, primary: CompressStarted<Vec<u8>>, gain_map: CompressStarted<Vec<u8>>) -> io::Result<Vec<u8>> {
+        self.attach_to(&primary.finish()?, &gain_map.finish()?)
+    }
+
+    /// Adds XMP metadata to both JPEG files, and appends the gain map to the primary image with an MPF index.
+    ///
+    /// Neither file can already have XMP.
+    pub fn attach_to(&self, primary: &[u8], gain_map: &[u8]) -> io::Result<Vec<u8>> {
+        let gain_map = insert_xmp(gain_map, &self.metadata.to_xmp())?;
+        let container = xmp_packet(&format!(concat!(
+            "  <rdf:Description rdf:about=\"\"\n",
+            "    xmlns:Container=\"http://ns.google.com/photos/1.0/container/\"\n",
+            "    xmlns:Item=\"http://ns.google.com/photos/1.0/container/item/\"\n",
+            "    xmlns:hdrgm=\"http://ns.adobe.com/hdr-gain-map/1.0/\"\n",
+            "    hdrgm:Version=\"{}\">\n",
+            "   <Container:Directory>\n",
+            "    <rdf:Seq>\n",
+            "     <rdf:li rdf:parseType=\"Resource\">\n",
+            "      <Container:Item Item:Semantic=\"Primary\" Item:Mime=\"image/jpeg\"/>\n",
+            "     </rdf:li>\n",
+            "     <rdf:li rdf:parseType=\"Resource\">\n",
+            "      <Container:Item Item:Semantic=\"GainMap\" Item:Mime=\"image/jpeg\" Item:Length=\"{}\"/>\n",
+            "     </rdf:li>\n",
+            "    </rdf:Seq>\n",
+            "   </Container:Directory>\n",
+            "  </rdf:Description>\n"), xml_escape(&self.metadata.version), gain_map.len()));
+        let primary = insert_xmp(primary, &container)?;
+
+        let mut mpf = MpfWriter::new();
+        mpf.add_image(MpImageType::Other(0), &gain_map);
+        mpf.attach_to(&primary)
+    }
+}
+
+fn xmp_packet(description: &str) -> Vec<u8> {
+    format!(concat!(
+        "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\" x:xmptk=\"mozjpeg-rust\">\n",
+        " <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n",
+        "{}",
+        " </rdf:RDF>\n",
+        "</x:xmpmeta>\n"), description).into_bytes()
+}
+
+/// Adds an APP1 XMP marker after SOI and APP0
+fn insert_xmp(jpeg: &[u8], xmp: &[u8]) -> io::Result<Vec<u8>> {
+    let insert_at = end_of_leading_markers(jpeg, &[0xE0])
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a JPEG file"))?;
+    if find_xmp(jpeg).is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "image already has XMP"));
+    }
+    let len = u16::try_from(2 + XMP_HEADER.len() + xmp.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "XMP too large"))?;
+    let mut out = Vec::with_capacity(jpeg.len() + 2 + usize::from(len));
+    out.extend_from_slice(&jpeg[..insert_at]);
+    out.extend_from_slice(&[0xFF, 0xE1]);
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(XMP_HEADER);
+    out.extend_from_slice(xmp);
+    out.extend_from_slice(&jpeg[insert_at..]);
+    Ok(out)
+}
+
+/// XMP packet from the header of a JPEG file
+fn find_xmp(jpeg: &[u8]) -> Option<&[u8]> {
+    Segments::new(jpeg)
+        .take_while(|s| s.marker != SOS)
+        .find(|s| s.marker == 0xE1 && s.payload.starts_with(XMP_HEADER))
+        .map(|s| &s.payload[XMP_HEADER.len()..])
+}
+
+/// Values of an XMP property written as an attribute, an element, or an element with `rdf:Seq`
+fn xmp_values<'x>(xmp: &'x str, name: &str) -> Option<Vec<&'x str>> {
+    for quote in ['"', '\''] {
+        let attribute = format!("{name}={quote}");
+        if let Some(pos) = xmp.find(&attribute) {
+            let value = &xmp[pos + attribute.len()..];
+            return Some(vec![&value[..value.find(quote)?]]);
+        }
+    }
+    let open = format!("<{name}>");
+    let start = xmp.find(&open)? + open.len();
+    let content = &xmp[start..start + xmp[start..].find(&format!("</{name}>"))?];
+    if !content.contains("<rdf:li") {
+        return Some(vec![content.trim()]);
+    }
+    content.split("<rdf:li").skip(1).map(|item| {
+        let item = &item[item.find('>')? + 1..];
+        Some(item[..item.find("</rdf:li>")?].trim())
+    }).collect()
+}
+
+/// Attributes of each `<Container:Item …/>` element
+fn container_items(xmp: &str) -> impl Iterator<Item = &str> {
+    xmp.split("<Container:Item").skip(1)
+        .filter(|rest| rest.starts_with(|c: char| c.is_ascii_whitespace() || c == '/' || c == '>'))
+        .map(|rest| &rest[..rest.find('>').unwrap_or(rest.len())])
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;")
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[test]
+fn roundtrips_gain_map() {
+    let start = |color_space, width: usize, height: usize, pixels: &[u8]| {
+        let mut comp = crate::Compress::new(color_space);
+        comp.set_size(width, height);
+        let mut started = comp.start_compress(Vec::new()).unwrap();
+        started.write_scanlines(pixels).unwrap();
+        started
+    };
+    let sdr: Vec<u8> = (0..32 * 16 * 3).map(|i| (i % 251) as u8).collect();
+    let gain: Vec<u8> = (0..8 * 4).map(|i| (i * 8) as u8).collect();
+
+    let metadata = GainMapMetadata {
+        gain_map_max: [2.5, 2., 1.5],
+        hdr_capacity_max: 2.5,
+        ..GainMapMetadata::default()
+    };
+    let file = UltraHdrWriter::new(metadata.clone()).finish(
+        start(crate::ColorSpace::JCS_RGB, 32, 16, &sdr),
+        start(crate::ColorSpace::JCS_GRAYSCALE, 8, 4, &gain),
+    ).unwrap();
+
+    let hdr = UltraHdr::from_mem(&file).unwrap().unwrap();
+    assert_eq!(metadata, hdr.metadata);
+    assert_eq!(file.len(), hdr.primary.len() + hdr.gain_map.len());
+    assert_eq!((32, 16), hdr.decompress_primary().unwrap().size());
+    let mut gain_map = hdr.decompress_gain_map().unwrap().grayscale().unwrap();
+    assert_eq!((8, 4), (gain_map.width(), gain_map.height()));
+    let decoded: Vec<u8> = gain_map.read_scanlines().unwrap();
+    assert!(decoded.iter().zip(&gain).all(|(&a, &b)| a.abs_diff(b) < 16));
+
+    let index = MpIndex::from_mem(&file).unwrap().unwrap();
+    assert_eq!(MpImageType::Other(0), index.entries[1].image_type);
+    assert_eq!(Some(hdr.gain_map), index.entries[1].data(&file));
+
+    // without MPF, the container directory is used
+    let primary_xmp_only = {
+        let mpf = Segments::new(&file).find(|s| s.marker == 0xE2).unwrap();
+        [&file[..mpf.start], &file[mpf.end()..]].concat()
+    };
+    let hdr = UltraHdr::from_mem(&primary_xmp_only).unwrap().unwrap();
+    assert_eq!(metadata, hdr.metadata);
+    assert!(hdr.primary.ends_with(&[0xFF, 0xD9]));
+
+    assert!(UltraHdr::from_mem(&std::fs::read("tests/test.jpg").unwrap()).unwrap().is_none());
+}
+
+#[test]
+fn parses_xmp() {
+    let xmp = br#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+        <rdf:Description xmlns:hdrgm="http://ns.adobe.com/hdr-gain-map/1.0/" hdrgm:Version='1.0' hdrgm:BaseRenditionIsHDR="True">
+        <hdrgm:GainMapMax>3.5</hdrgm:GainMapMax>
+        <hdrgm:Gamma><rdf:Seq><rdf:li>1</rdf:li><rdf:li>0.5</rdf:li><rdf:li>2</rdf:li></rdf:Seq></hdrgm:Gamma>
+        </rdf:Description></rdf:RDF></x:xmpmeta>"#;
+    let metadata = GainMapMetadata::from_xmp(xmp).unwrap();
+    assert!(metadata.base_rendition_is_hdr);
+    assert_eq!([3.5; 3], metadata.gain_map_max);
+    assert_eq!(3.5, metadata.hdr_capacity_max);
+    assert_eq!([1., 0.5, 2.], metadata.gamma);
+    assert_eq!([1. / 64.; 3], metadata.offset_sdr);
+    assert_eq!(metadata, GainMapMetadata::from_xmp(&metadata.to_xmp()).unwrap());
+    assert!(GainMapMetadata::from_xmp(b"<x:xmpmeta/>").is_err());
+}
+
+#[test]
+fn finds_gain_map_in_container() {
+    let encode = |value: u8| {
+        let mut comp = crate::Compress::new(crate::ColorSpace::JCS_GRAYSCALE);
+        comp.set_size(8, 8);
+        let mut started = comp.start_compress(Vec::new()).unwrap();
+        started.write_scanlines(&[value; 64]).unwrap();
+        started.finish().unwrap()
+    };
+    let gain_map = insert_xmp(&encode(50), &GainMapMetadata::default().to_xmp()).unwrap();
+    let with_directory = |items: &str| {
+        let xmp = xmp_packet(&format!(concat!(
+            "<rdf:Description xmlns:Container=\"http://ns.google.com/photos/1.0/container/\" ",
+            "xmlns:Item=\"http://ns.google.com/photos/1.0/container/item/\">",
+            "<Container:Directory><rdf:Seq>{}</rdf:Seq></Container:Directory></rdf:Description>"), items));
+        insert_xmp(&encode(200), &xmp).unwrap()
+    };
+
+    // Item:Length before Item:Semantic
+    let primary = with_directory(&format!(concat!(
+        "<rdf:li rdf:parseType='Resource'><Container:Item Item:Mime='image/jpeg' Item:Semantic='Primary'/></rdf:li>",
+        "<rdf:li rdf:parseType='Resource'><Container:Item Item:Length='{}' Item:Mime='image/jpeg' Item:Semantic='GainMap'/></rdf:li>"),
+        gain_map.len()));
+    let file = [&primary[..], &gain_map].concat();
+    let hdr = UltraHdr::from_mem(&file).unwrap().unwrap();
+    assert_eq!(&primary[..], hdr.primary);
+    assert_eq!(&gain_map[..], hdr.gain_map);
+
+    // padding after the primary image and after the gain map
+    let primary = with_directory(&format!(concat!(
+        "<rdf:li rdf:parseType=\"Resource\"><Container:Item Item:Semantic=\"Primary\" Item:Mime=\"image/jpeg\" Item:Padding=\"3\"/></rdf:li>",
+        "<rdf:li rdf:parseType=\"Resource\"><Container:Item Item:Semantic=\"GainMap\" Item:Padding=\"5\" Item:Length=\"{}\"/></rdf:li>"),
+        gain_map.len()));
+    let file = [&primary[..], &[0; 3], &gain_map, &[0; 5]].concat();
+    let hdr = UltraHdr::from_mem(&file).unwrap().unwrap();
+    assert!(hdr.primary.starts_with(&primary));
+    assert_eq!(&gain_map[..], hdr.gain_map);
+    assert_eq!((8, 8), hdr.decompress_gain_map().unwrap().size());
+
+    let too_long = with_directory(&format!("<Container:Item Item:Semantic='GainMap' Item:Length='{}'/>", 1 << 20));
+    assert!(UltraHdr::from_mem(&too_long).is_err());
+
+    let overflowing = with_directory(&format!(concat!(
+        "<Container:Item Item:Semantic='GainMap' Item:Length='1'/>",
+        "<Container:Item Item:Semantic='Depth' Item:Length='{}' Item:Padding='1'/>"),
+        usize::MAX));
+    assert!(UltraHdr::from_mem(&overflowing).is_err());
+}
@@ -37,6 +37,8 @@ struct DestinationMgrInner<W> {
     iface: jpeg_destination_mgr,
     buf: Vec<u8>,
     writer: W,
+    /// `empty_output_buffer` returns FALSE instead of writing, when libjpeg can resume later
+    suspend: bool,
     // jpeg_destination_mgr callbacks get a pointer to the struct
     _pinned: PhantomPinned,
 }
@@ -57,6 +59,7 @@ impl<W: Write> DestinationMgr<W> {
                     // Can't use BufWriter, because it doesn't expose the unwritten buffer
                     buf: Vec::with_capacity(if capacity > 0 { capacity.min(i32::MAX as usize) } else { 4096 }),
                     writer,
+                    suspend: false,
                     _pinned: PhantomPinned,
                 },
             ))),
@@ -70,6 +73,36 @@ impl<W: Write> DestinationMgr<W> {
         }
     }
 
+    /// Writer that has been given all the data flushed by libjpeg so far.
+    /// Must not be used while libjpeg is running.
+    #[cfg(any(feature = "futures", feature = "tokio"))]
+    pub fn writer_mut(&mut self) -> &mut W {
+        unsafe {
+            &mut (*UnsafeCell::raw_get(self.inner_shared)).writer
+        }
+    }
+
+    /// Allow libjpeg to suspend instead of writing the buffer when it's full.
+    ///
+    /// Only `jpeg_write_scanlines` in single-pass Huffman mode can resume after suspension
+    /// (it returns fewer rows), and libjpeg fails with `JERR_CANT_SUSPEND` elsewhere.
+    #[cfg(any(feature = "futures", feature = "tokio"))]
+    pub fn set_suspend(&mut self, suspend: bool) {
+        unsafe {
+            (*UnsafeCell::raw_get(self.inner_shared)).suspend = suspend;
+        }
+    }
+
+    /// Writes data that libjpeg has put in the buffer so far, and makes the whole buffer available again.
+    /// Must not be used while libjpeg is running.
+    #[cfg(any(feature = "futures", feature = "tokio"))]
+    pub fn write_buffered(&mut self) -> io::Result<()> {
+        unsafe {
+            (*UnsafeCell::raw_get(self.inner_shared)).write_buffer(false)
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "can't write the buffer"))
+        }
+    }
+
     /// Must be called after `term_destination`
     pub fn into_inner(mut self) -> W {
         unsafe {
@@ -165,9 +198,16 @@ impl<W: Write> DestinationMgrInner<W> {
 
     /// This is called by `jcphuff`'s `dump_buffer()`, which does NOT keep
     /// the position up to date, and expects full buffer write every time.
+    ///
+    /// When suspending, the position is where `jchuff` can resume from, and only data before it is kept.
+    /// Marker and arithmetic coder writes update the position, and can't suspend, but they always fill the whole buffer.
     #[inline(never)]
     unsafe extern "C-unwind" fn empty_output_buffer(cinfo: &mut jpeg_compress_struct) -> boolean {
         let this = Self::cast(cinfo);
+        // suspending with nothing in the buffer wouldn't make any progress
+        if this.suspend && this.iface.free_in_buffer > 0 && this.iface.free_in_buffer < this.buf.spare_capacity_mut().len() {
+            return 0;
+        }
         if let Err(code) = this.write_buffer(true) {
             fail(&mut cinfo.common, code);
         }